@@ -0,0 +1,14 @@
+ALTER TABLE orders
+    ALTER COLUMN status DROP NOT NULL,
+    ALTER COLUMN payment_status DROP NOT NULL;
+
+ALTER TABLE orders
+    ALTER COLUMN order_date TYPE TIMESTAMP;
+
+ALTER TABLE products
+    ALTER COLUMN created_at TYPE TIMESTAMP,
+    ALTER COLUMN updated_at TYPE TIMESTAMP;
+
+ALTER TABLE users
+    ALTER COLUMN created_at TYPE TIMESTAMP,
+    ALTER COLUMN updated_at TYPE TIMESTAMP;
@@ -0,0 +1,18 @@
+-- chrono::DateTime<Utc> maps to TIMESTAMPTZ, plain TIMESTAMP columns can't be decoded
+ALTER TABLE users
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ,
+    ALTER COLUMN updated_at TYPE TIMESTAMPTZ;
+
+ALTER TABLE products
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ,
+    ALTER COLUMN updated_at TYPE TIMESTAMPTZ;
+
+ALTER TABLE orders
+    ALTER COLUMN order_date TYPE TIMESTAMPTZ;
+
+-- Статусы заказа всегда заданы (у колонок есть DEFAULT, но не было NOT NULL)
+UPDATE orders SET status = 'pending' WHERE status IS NULL;
+UPDATE orders SET payment_status = 'unpaid' WHERE payment_status IS NULL;
+ALTER TABLE orders
+    ALTER COLUMN status SET NOT NULL,
+    ALTER COLUMN payment_status SET NOT NULL;
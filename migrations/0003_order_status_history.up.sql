@@ -0,0 +1,18 @@
+-- История смены статусов заказа
+CREATE TABLE order_status_history (
+    history_id SERIAL PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    from_status VARCHAR(20),
+    to_status VARCHAR(20) NOT NULL,
+    changed_by INTEGER,
+    comment TEXT,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE CASCADE,
+    FOREIGN KEY (changed_by) REFERENCES users(user_id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_order_status_history_order_id ON order_status_history(order_id);
+
+-- Existing orders get their current status as the initial history entry
+INSERT INTO order_status_history (order_id, from_status, to_status, changed_at)
+SELECT order_id, NULL, status, COALESCE(order_date, CURRENT_TIMESTAMP) FROM orders;
@@ -0,0 +1,7 @@
+DROP TABLE IF EXISTS refunds;
+DROP TABLE IF EXISTS payments;
+
+UPDATE orders SET payment_status = 'paid' WHERE payment_status = 'partially_refunded';
+ALTER TABLE orders DROP CONSTRAINT IF EXISTS orders_payment_status_check;
+ALTER TABLE orders ADD CONSTRAINT orders_payment_status_check
+    CHECK (payment_status IN ('unpaid', 'paid', 'refunded', 'failed'));
@@ -0,0 +1,35 @@
+-- Платежи по заказам и возвраты
+ALTER TABLE orders DROP CONSTRAINT IF EXISTS orders_payment_status_check;
+ALTER TABLE orders ADD CONSTRAINT orders_payment_status_check
+    CHECK (payment_status IN ('unpaid', 'paid', 'partially_refunded', 'refunded', 'failed'));
+
+CREATE TABLE payments (
+    payment_id SERIAL PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    provider VARCHAR(30) NOT NULL,
+    provider_reference VARCHAR(100) UNIQUE,
+    amount DECIMAL(10, 2) NOT NULL CHECK (amount >= 0),
+    status VARCHAR(20) NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'succeeded', 'failed')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_payments_order_id ON payments(order_id);
+
+CREATE TRIGGER update_payments_updated_at
+    BEFORE UPDATE ON payments
+    FOR EACH ROW EXECUTE FUNCTION update_updated_at_column();
+
+CREATE TABLE refunds (
+    refund_id SERIAL PRIMARY KEY,
+    payment_id INTEGER NOT NULL,
+    amount DECIMAL(10, 2) NOT NULL CHECK (amount > 0),
+    provider_reference VARCHAR(100),
+    reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (payment_id) REFERENCES payments(payment_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_refunds_payment_id ON refunds(payment_id);
@@ -0,0 +1,25 @@
+-- OAuth2 клиенты и refresh-токены
+CREATE TABLE oauth_clients (
+    client_id VARCHAR(100) PRIMARY KEY,
+    client_secret_hash VARCHAR(255) NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    allowed_grants TEXT[] NOT NULL DEFAULT '{password,refresh_token}',
+    allowed_scopes TEXT[] NOT NULL DEFAULT '{}',
+    is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Only a SHA-256 of the refresh token is stored
+CREATE TABLE oauth_refresh_tokens (
+    token_hash CHAR(64) PRIMARY KEY,
+    client_id VARCHAR(100) NOT NULL,
+    user_id INTEGER,
+    scopes TEXT[] NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (client_id) REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_oauth_refresh_tokens_user_id ON oauth_refresh_tokens(user_id);
@@ -0,0 +1,3 @@
+-- Роли пользователей: customer (по умолчанию), staff, admin
+ALTER TABLE users ADD COLUMN role VARCHAR(20) NOT NULL DEFAULT 'customer'
+    CONSTRAINT users_role_check CHECK (role IN ('customer', 'staff', 'admin'));
@@ -0,0 +1,6 @@
+-- Удалённые товары с повторяющимися артикулами не дадут вернуть UNIQUE
+DROP INDEX IF EXISTS idx_products_not_deleted;
+DROP INDEX IF EXISTS products_sku_key;
+ALTER TABLE products ADD CONSTRAINT products_sku_key UNIQUE (sku);
+
+ALTER TABLE products DROP COLUMN IF EXISTS deleted_at;
@@ -0,0 +1,8 @@
+-- Мягкое удаление товаров: строка остаётся (на неё ссылаются order_items)
+ALTER TABLE products ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- Артикул удалённого товара можно использовать снова
+ALTER TABLE products DROP CONSTRAINT IF EXISTS products_sku_key;
+CREATE UNIQUE INDEX products_sku_key ON products(sku) WHERE deleted_at IS NULL;
+
+CREATE INDEX idx_products_not_deleted ON products(created_at) WHERE deleted_at IS NULL;
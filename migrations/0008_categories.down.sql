@@ -0,0 +1,12 @@
+ALTER TABLE products ADD COLUMN category VARCHAR(50);
+
+UPDATE products p
+SET category = left(c.name, 50)
+FROM categories c
+WHERE c.category_id = p.category_id;
+
+CREATE INDEX IF NOT EXISTS idx_products_category ON products(category);
+DROP INDEX IF EXISTS idx_products_category_id;
+ALTER TABLE products DROP COLUMN category_id;
+
+DROP TABLE IF EXISTS categories;
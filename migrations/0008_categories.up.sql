@@ -0,0 +1,43 @@
+-- Категории товаров: дерево через parent_id вместо свободного текста products.category
+CREATE TABLE categories (
+    category_id SERIAL PRIMARY KEY,
+    parent_id INTEGER,
+    name VARCHAR(100) NOT NULL,
+    slug VARCHAR(100) NOT NULL UNIQUE,
+    description TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (parent_id) REFERENCES categories(category_id) ON DELETE RESTRICT,
+    CHECK (parent_id <> category_id)
+);
+
+CREATE INDEX idx_categories_parent_id ON categories(parent_id);
+
+CREATE TRIGGER update_categories_updated_at
+    BEFORE UPDATE ON categories
+    FOR EACH ROW EXECUTE FUNCTION update_updated_at_column();
+
+-- Existing free-text values become root categories
+INSERT INTO categories (name, slug)
+SELECT DISTINCT ON (slug) name, slug
+FROM (
+    SELECT category AS name,
+           trim(BOTH '-' FROM regexp_replace(lower(category), '[[:space:][:punct:]]+', '-', 'g')) AS slug
+    FROM products
+    WHERE category IS NOT NULL AND trim(category) <> ''
+) AS source
+WHERE slug <> ''
+ORDER BY slug, name;
+
+ALTER TABLE products ADD COLUMN category_id INTEGER;
+ALTER TABLE products ADD CONSTRAINT products_category_id_fkey
+    FOREIGN KEY (category_id) REFERENCES categories(category_id) ON DELETE RESTRICT;
+
+UPDATE products p
+SET category_id = c.category_id
+FROM categories c
+WHERE c.slug = trim(BOTH '-' FROM regexp_replace(lower(p.category), '[[:space:][:punct:]]+', '-', 'g'));
+
+DROP INDEX IF EXISTS idx_products_category;
+ALTER TABLE products DROP COLUMN category;
+CREATE INDEX idx_products_category_id ON products(category_id);
@@ -0,0 +1,18 @@
+-- Полнотекстовый поиск по товарам.
+-- Данные смешанные (русский/английский), поэтому вектор строится сразу в трёх
+-- конфигурациях: запрос в любой из них (russian, english, simple) попадает в индекс.
+ALTER TABLE products ADD COLUMN search_vector TSVECTOR GENERATED ALWAYS AS (
+    setweight(
+        to_tsvector('russian', coalesce(name, ''))
+            || to_tsvector('english', coalesce(name, ''))
+            || to_tsvector('simple', coalesce(name, '')),
+        'A')
+    || setweight(to_tsvector('simple', coalesce(sku, '')), 'B')
+    || setweight(
+        to_tsvector('russian', coalesce(description, ''))
+            || to_tsvector('english', coalesce(description, ''))
+            || to_tsvector('simple', coalesce(description, '')),
+        'C')
+) STORED;
+
+CREATE INDEX idx_products_search_vector ON products USING GIN (search_vector);
@@ -0,0 +1,18 @@
+-- Изображения товаров: файлы лежат в хранилище (см. storage.rs), здесь только ключи
+CREATE TABLE product_images (
+    image_id SERIAL PRIMARY KEY,
+    product_id INTEGER NOT NULL,
+    storage_key VARCHAR(255) NOT NULL,
+    -- size name -> storage key, e.g. {"small": "images/products/1/<uuid>_small.jpg"}
+    thumbnails JSONB NOT NULL DEFAULT '{}',
+    content_type VARCHAR(50) NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    position INTEGER NOT NULL CHECK (position >= 0),
+    alt_text VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE CASCADE,
+    -- deferred so that reordering can swap positions in one statement
+    CONSTRAINT product_images_position_key UNIQUE (product_id, position) DEFERRABLE INITIALLY DEFERRED
+);
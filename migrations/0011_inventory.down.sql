@@ -0,0 +1,3 @@
+DROP VIEW IF EXISTS product_stock;
+DROP TABLE IF EXISTS stock_reservations;
+DROP TABLE IF EXISTS stock_movements;
@@ -0,0 +1,60 @@
+-- Складской учёт: журнал движений остатков и резервы под неоплаченные заказы
+CREATE TABLE stock_movements (
+    movement_id SERIAL PRIMARY KEY,
+    product_id INTEGER NOT NULL,
+    movement_type VARCHAR(20) NOT NULL
+        CHECK (movement_type IN ('receipt', 'sale', 'cancellation', 'adjustment')),
+    -- signed change of products.stock_quantity
+    quantity INTEGER NOT NULL CHECK (quantity <> 0),
+    balance_after INTEGER NOT NULL CHECK (balance_after >= 0),
+    order_id INTEGER,
+    created_by INTEGER,
+    comment TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE CASCADE,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE SET NULL,
+    FOREIGN KEY (created_by) REFERENCES users(user_id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_stock_movements_product_id ON stock_movements(product_id);
+CREATE INDEX idx_stock_movements_order_id ON stock_movements(order_id);
+
+CREATE TABLE stock_reservations (
+    reservation_id SERIAL PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    product_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    status VARCHAR(20) NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'converted', 'released')),
+    -- an active reservation stops holding stock after this moment
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE CASCADE,
+    FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_stock_reservations_order_id ON stock_reservations(order_id);
+CREATE INDEX idx_stock_reservations_active ON stock_reservations(product_id, expires_at) WHERE status = 'active';
+
+-- on hand = products.stock_quantity, reserved = unexpired active reservations
+CREATE VIEW product_stock AS
+SELECT p.product_id,
+       p.name,
+       p.sku,
+       p.stock_quantity AS on_hand,
+       COALESCE(r.reserved, 0) AS reserved,
+       p.stock_quantity - COALESCE(r.reserved, 0) AS available
+FROM products p
+LEFT JOIN (
+    SELECT product_id, SUM(quantity)::INTEGER AS reserved
+    FROM stock_reservations
+    WHERE status = 'active' AND expires_at > CURRENT_TIMESTAMP
+    GROUP BY product_id
+) r ON r.product_id = p.product_id
+WHERE p.deleted_at IS NULL;
+
+-- Current stock becomes the opening balance of the ledger
+INSERT INTO stock_movements (product_id, movement_type, quantity, balance_after, comment)
+SELECT product_id, 'receipt', stock_quantity, stock_quantity, 'Opening balance'
+FROM products
+WHERE stock_quantity > 0;
@@ -0,0 +1,33 @@
+-- Корзины: у пользователя или у гостя (по токену), позиции без цены - цена всегда текущая
+CREATE TABLE carts (
+    cart_id SERIAL PRIMARY KEY,
+    user_id INTEGER,
+    -- SHA-256 of the guest token, the token itself is only known to the client
+    guest_token_hash VARCHAR(64) UNIQUE,
+    status VARCHAR(20) NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'merged', 'converted')),
+    order_id INTEGER,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE SET NULL,
+    CHECK (user_id IS NOT NULL OR guest_token_hash IS NOT NULL)
+);
+
+-- One active cart per user
+CREATE UNIQUE INDEX carts_active_user_key ON carts(user_id) WHERE status = 'active';
+
+CREATE TRIGGER update_carts_updated_at
+    BEFORE UPDATE ON carts
+    FOR EACH ROW EXECUTE FUNCTION update_updated_at_column();
+
+CREATE TABLE cart_items (
+    cart_item_id SERIAL PRIMARY KEY,
+    cart_id INTEGER NOT NULL,
+    product_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    added_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (cart_id) REFERENCES carts(cart_id) ON DELETE CASCADE,
+    FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE CASCADE,
+    UNIQUE (cart_id, product_id)
+);
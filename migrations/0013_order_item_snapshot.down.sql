@@ -0,0 +1,4 @@
+ALTER TABLE order_items
+    DROP COLUMN IF EXISTS product_image_url,
+    DROP COLUMN IF EXISTS product_sku,
+    DROP COLUMN IF EXISTS product_name;
@@ -0,0 +1,14 @@
+-- Позиции заказа хранят товар на момент покупки: название, артикул и картинку
+ALTER TABLE order_items
+    ADD COLUMN product_name VARCHAR(100),
+    ADD COLUMN product_sku VARCHAR(50),
+    ADD COLUMN product_image_url VARCHAR(255);
+
+UPDATE order_items oi
+SET product_name = p.name,
+    product_sku = p.sku,
+    product_image_url = p.image_url
+FROM products p
+WHERE p.product_id = oi.product_id;
+
+ALTER TABLE order_items ALTER COLUMN product_name SET NOT NULL;
@@ -0,0 +1,6 @@
+-- Счётчики номеров заказов: отдельная последовательность на каждый период (день по умолчанию)
+CREATE TABLE order_number_counters (
+    -- date component of the number ("20261018"), empty when numbers have no date
+    period VARCHAR(20) PRIMARY KEY,
+    last_value BIGINT NOT NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE orders
+    DROP COLUMN cancelled_by,
+    DROP COLUMN cancelled_at,
+    DROP COLUMN cancellation_reason;
@@ -0,0 +1,18 @@
+-- Отмена заказа: причина, время и кто отменил
+ALTER TABLE orders
+    ADD COLUMN cancellation_reason TEXT,
+    ADD COLUMN cancelled_at TIMESTAMPTZ,
+    ADD COLUMN cancelled_by INTEGER REFERENCES users(user_id) ON DELETE SET NULL;
+
+-- Already cancelled orders take the time and comment of their last move to cancelled
+UPDATE orders o
+SET cancelled_at = h.changed_at,
+    cancelled_by = h.changed_by,
+    cancellation_reason = h.comment
+FROM (
+    SELECT DISTINCT ON (order_id) order_id, changed_at, changed_by, comment
+    FROM order_status_history
+    WHERE to_status = 'cancelled'
+    ORDER BY order_id, changed_at DESC, history_id DESC
+) h
+WHERE o.order_id = h.order_id AND o.status = 'cancelled';
@@ -0,0 +1,17 @@
+-- Ключи идемпотентности: первый ответ на запрос сохраняется и отдаётся при повторе
+CREATE TABLE idempotency_keys (
+    -- token subject, or the guest cart token hash
+    owner VARCHAR(120) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    -- sha256 of method, path and body
+    request_hash CHAR(64) NOT NULL,
+    -- NULL while the first request is still running
+    status_code SMALLINT,
+    response_headers JSONB,
+    response_body BYTEA,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (owner, idempotency_key)
+);
+
+CREATE INDEX idx_idempotency_keys_expires_at ON idempotency_keys(expires_at);
@@ -0,0 +1,57 @@
+-- Акции и купоны: скидка в процентах или фиксированная, на весь заказ, товар или категорию
+CREATE TABLE promotions (
+    promotion_id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL,
+    -- coupon code the customer enters; NULL = applied automatically
+    code VARCHAR(50),
+    discount_type VARCHAR(20) NOT NULL CHECK (discount_type IN ('percentage', 'fixed')),
+    value NUMERIC(10, 2) NOT NULL CHECK (value > 0),
+    -- what the discount applies to, the whole order when both are NULL
+    product_id INTEGER,
+    category_id INTEGER,
+    min_order_amount NUMERIC(10, 2) CHECK (min_order_amount >= 0),
+    -- orders that may use it, cancelled orders give their use back
+    max_uses INTEGER CHECK (max_uses > 0),
+    max_uses_per_user INTEGER CHECK (max_uses_per_user > 0),
+    starts_at TIMESTAMPTZ,
+    ends_at TIMESTAMPTZ,
+    -- stackable promotions combine with each other, the others apply alone
+    stackable BOOLEAN NOT NULL DEFAULT FALSE,
+    -- higher goes first when several apply
+    priority INTEGER NOT NULL DEFAULT 0,
+    is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE CASCADE,
+    FOREIGN KEY (category_id) REFERENCES categories(category_id) ON DELETE CASCADE,
+    CONSTRAINT promotions_percentage_check CHECK (discount_type <> 'percentage' OR value <= 100),
+    CONSTRAINT promotions_scope_check CHECK (product_id IS NULL OR category_id IS NULL),
+    CONSTRAINT promotions_window_check CHECK (ends_at > starts_at)
+);
+
+-- Codes are matched case-insensitively
+CREATE UNIQUE INDEX promotions_code_key ON promotions (LOWER(code));
+
+CREATE TRIGGER update_promotions_updated_at
+    BEFORE UPDATE ON promotions
+    FOR EACH ROW EXECUTE FUNCTION update_updated_at_column();
+
+-- Скидки, применённые к заказу: отдельными строками, чтобы итог можно было проверить
+CREATE TABLE order_discounts (
+    order_discount_id SERIAL PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    promotion_id INTEGER,
+    -- promotion as it was when applied
+    code VARCHAR(50),
+    description VARCHAR(100) NOT NULL,
+    amount NUMERIC(10, 2) NOT NULL CHECK (amount > 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE CASCADE,
+    FOREIGN KEY (promotion_id) REFERENCES promotions(promotion_id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_order_discounts_order_id ON order_discounts(order_id);
+CREATE INDEX idx_order_discounts_promotion_id ON order_discounts(promotion_id);
+
+-- total_amount = sum of the item subtotals - discount_amount
+ALTER TABLE orders ADD COLUMN discount_amount NUMERIC(10, 2) NOT NULL DEFAULT 0 CHECK (discount_amount >= 0);
@@ -0,0 +1,13 @@
+DROP TRIGGER IF EXISTS record_product_price_update ON products;
+DROP TRIGGER IF EXISTS record_product_price_insert ON products;
+DROP FUNCTION IF EXISTS record_product_price();
+DROP TABLE IF EXISTS product_price_history;
+DROP TRIGGER IF EXISTS set_product_price ON products;
+DROP FUNCTION IF EXISTS set_product_price();
+DROP FUNCTION IF EXISTS product_price_at(NUMERIC, NUMERIC, TIMESTAMPTZ, TIMESTAMPTZ, TIMESTAMPTZ);
+
+ALTER TABLE products
+    DROP COLUMN IF EXISTS sale_ends_at,
+    DROP COLUMN IF EXISTS sale_starts_at,
+    DROP COLUMN IF EXISTS sale_price,
+    DROP COLUMN IF EXISTS regular_price;
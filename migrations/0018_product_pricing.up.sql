@@ -0,0 +1,85 @@
+-- Цены товара: обычная цена, распродажа по расписанию и история изменений
+ALTER TABLE products
+    ADD COLUMN regular_price DECIMAL(10, 2),
+    ADD COLUMN sale_price DECIMAL(10, 2),
+    ADD COLUMN sale_starts_at TIMESTAMPTZ,
+    ADD COLUMN sale_ends_at TIMESTAMPTZ;
+
+UPDATE products SET regular_price = price;
+
+ALTER TABLE products
+    ALTER COLUMN regular_price SET NOT NULL,
+    ADD CONSTRAINT products_regular_price_check CHECK (regular_price >= 0),
+    ADD CONSTRAINT products_sale_price_check CHECK (sale_price >= 0 AND sale_price < regular_price),
+    ADD CONSTRAINT products_sale_window_check CHECK (sale_ends_at > sale_starts_at);
+
+-- Price charged at `at`: the sale price inside the sale window (open ends allowed), the regular price otherwise
+CREATE FUNCTION product_price_at(
+    regular_price NUMERIC,
+    sale_price NUMERIC,
+    sale_starts_at TIMESTAMPTZ,
+    sale_ends_at TIMESTAMPTZ,
+    at TIMESTAMPTZ
+) RETURNS NUMERIC AS $$
+    SELECT CASE
+        WHEN sale_price IS NOT NULL
+             AND (sale_starts_at IS NULL OR sale_starts_at <= at)
+             AND (sale_ends_at IS NULL OR sale_ends_at > at)
+        THEN sale_price
+        ELSE regular_price
+    END
+$$ LANGUAGE SQL IMMUTABLE;
+
+-- products.price is derived, the application writes regular_price and the sale
+CREATE FUNCTION set_product_price() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.price = product_price_at(NEW.regular_price, NEW.sale_price, NEW.sale_starts_at, NEW.sale_ends_at, CURRENT_TIMESTAMP);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER set_product_price
+    BEFORE INSERT OR UPDATE OF price, regular_price, sale_price, sale_starts_at, sale_ends_at ON products
+    FOR EACH ROW EXECUTE FUNCTION set_product_price();
+
+CREATE TABLE product_price_history (
+    price_history_id SERIAL PRIMARY KEY,
+    product_id INTEGER NOT NULL,
+    -- price charged right after the change
+    price DECIMAL(10, 2) NOT NULL,
+    regular_price DECIMAL(10, 2) NOT NULL,
+    sale_price DECIMAL(10, 2),
+    sale_starts_at TIMESTAMPTZ,
+    sale_ends_at TIMESTAMPTZ,
+    -- app.user_id of the transaction, NULL for the scheduler and migrations
+    changed_by INTEGER,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE CASCADE,
+    FOREIGN KEY (changed_by) REFERENCES users(user_id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_product_price_history_product_id ON product_price_history(product_id, changed_at);
+
+CREATE FUNCTION record_product_price() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO product_price_history (product_id, price, regular_price, sale_price, sale_starts_at, sale_ends_at, changed_by)
+    VALUES (NEW.product_id, NEW.price, NEW.regular_price, NEW.sale_price, NEW.sale_starts_at, NEW.sale_ends_at,
+            NULLIF(current_setting('app.user_id', TRUE), '')::INTEGER);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_product_price_insert
+    AFTER INSERT ON products
+    FOR EACH ROW EXECUTE FUNCTION record_product_price();
+
+CREATE TRIGGER record_product_price_update
+    AFTER UPDATE ON products
+    FOR EACH ROW
+    WHEN ((OLD.price, OLD.regular_price, OLD.sale_price, OLD.sale_starts_at, OLD.sale_ends_at)
+          IS DISTINCT FROM (NEW.price, NEW.regular_price, NEW.sale_price, NEW.sale_starts_at, NEW.sale_ends_at))
+    EXECUTE FUNCTION record_product_price();
+
+-- What we know so far: the current price, since the product was created
+INSERT INTO product_price_history (product_id, price, regular_price, changed_at)
+SELECT product_id, price, regular_price, COALESCE(created_at, CURRENT_TIMESTAMP) FROM products;
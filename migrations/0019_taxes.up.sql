@@ -0,0 +1,42 @@
+-- Налоги: ставки по стране/региону и налоговому классу товара, налог по строкам заказа
+CREATE TABLE tax_rates (
+    tax_rate_id SERIAL PRIMARY KEY,
+    -- ISO 3166-1 alpha-2, upper case
+    country CHAR(2) NOT NULL CHECK (country ~ '^[A-Z]{2}$'),
+    -- NULL = the whole country; a regional rate replaces the country one
+    region VARCHAR(100),
+    tax_class VARCHAR(50) NOT NULL,
+    -- percent, 20.000 = 20 %
+    rate NUMERIC(6, 3) NOT NULL CHECK (rate >= 0 AND rate <= 100),
+    name VARCHAR(100) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Regions are matched case-insensitively
+CREATE UNIQUE INDEX tax_rates_scope_key ON tax_rates (country, LOWER(COALESCE(region, '')), tax_class);
+
+CREATE TRIGGER update_tax_rates_updated_at
+    BEFORE UPDATE ON tax_rates
+    FOR EACH ROW EXECUTE FUNCTION update_updated_at_column();
+
+ALTER TABLE products ADD COLUMN tax_class VARCHAR(50) NOT NULL DEFAULT 'standard';
+
+-- Where the order is taxed and how its prices are meant; existing orders had no tax
+ALTER TABLE orders
+    ADD COLUMN tax_country CHAR(2),
+    ADD COLUMN tax_region VARCHAR(100),
+    ADD COLUMN prices_include_tax BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN tax_amount NUMERIC(10, 2) NOT NULL DEFAULT 0 CHECK (tax_amount >= 0);
+
+-- Tax of every line, with the class and rate it was worked out with.
+-- taxable_amount is the line net of discounts and of tax
+ALTER TABLE order_items
+    ADD COLUMN tax_class VARCHAR(50) NOT NULL DEFAULT 'standard',
+    ADD COLUMN tax_rate NUMERIC(6, 3) NOT NULL DEFAULT 0,
+    ADD COLUMN taxable_amount NUMERIC(10, 2),
+    ADD COLUMN tax_amount NUMERIC(10, 2) NOT NULL DEFAULT 0 CHECK (tax_amount >= 0);
+
+UPDATE order_items SET taxable_amount = subtotal;
+
+ALTER TABLE order_items ALTER COLUMN taxable_amount SET NOT NULL;
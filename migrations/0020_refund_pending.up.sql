@@ -0,0 +1,4 @@
+-- Возврат денег по отменённому заказу, который ещё не прошёл через платёжный шлюз
+ALTER TABLE orders ADD COLUMN refund_pending BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX idx_orders_refund_pending ON orders(order_id) WHERE refund_pending;
@@ -0,0 +1,6 @@
+ALTER TABLE order_discounts
+    DROP COLUMN IF EXISTS min_order_amount,
+    DROP COLUMN IF EXISTS category_id,
+    DROP COLUMN IF EXISTS product_id,
+    DROP COLUMN IF EXISTS value,
+    DROP COLUMN IF EXISTS discount_type;
@@ -0,0 +1,21 @@
+-- Условия скидки на момент оформления заказа: по ним скидка пересчитывается при изменении состава заказа
+ALTER TABLE order_discounts
+    ADD COLUMN discount_type VARCHAR(20) CHECK (discount_type IN ('percentage', 'fixed')),
+    ADD COLUMN value NUMERIC(10, 2) CHECK (value > 0),
+    ADD COLUMN product_id INTEGER,
+    ADD COLUMN category_id INTEGER,
+    ADD COLUMN min_order_amount NUMERIC(10, 2) CHECK (min_order_amount >= 0);
+
+-- Existing lines take the terms their promotion has now; lines whose promotion
+-- is gone keep their amount, off the whole order
+UPDATE order_discounts d
+SET discount_type = p.discount_type, value = p.value, product_id = p.product_id,
+    category_id = p.category_id, min_order_amount = p.min_order_amount
+FROM promotions p
+WHERE p.promotion_id = d.promotion_id;
+
+UPDATE order_discounts SET discount_type = 'fixed', value = amount WHERE discount_type IS NULL;
+
+ALTER TABLE order_discounts
+    ALTER COLUMN discount_type SET NOT NULL,
+    ALTER COLUMN value SET NOT NULL;
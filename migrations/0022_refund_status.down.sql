@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS idx_refunds_pending;
+DROP TRIGGER IF EXISTS update_refunds_updated_at ON refunds;
+DELETE FROM refunds WHERE status <> 'succeeded';
+ALTER TABLE refunds DROP COLUMN IF EXISTS updated_at;
+ALTER TABLE refunds DROP COLUMN IF EXISTS status;
@@ -0,0 +1,11 @@
+-- Возвраты записываются до обращения к шлюзу (pending) и подтверждаются его ответом
+ALTER TABLE refunds ADD COLUMN status VARCHAR(20) NOT NULL DEFAULT 'succeeded'
+    CHECK (status IN ('pending', 'succeeded', 'failed'));
+ALTER TABLE refunds ALTER COLUMN status SET DEFAULT 'pending';
+ALTER TABLE refunds ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+CREATE TRIGGER update_refunds_updated_at
+    BEFORE UPDATE ON refunds
+    FOR EACH ROW EXECUTE FUNCTION update_updated_at_column();
+
+CREATE INDEX idx_refunds_pending ON refunds(refund_id) WHERE status = 'pending';
@@ -0,0 +1,374 @@
+// JWT authentication.
+//
+// POST /api/login_check exchanges email + password for a signed access token.
+// The `authenticate` middleware validates `Authorization: Bearer <token>` on every
+// request and stores the `AuthenticatedUser` in the request extensions, handlers
+// that need a user take `AuthenticatedUser` as an argument (401 when missing).
+//
+// Tokens issued by /oauth/token carry a `scope` claim; routes declare the scope
+// they need with `require_scope` in main(). First-party login_check tokens have
+// no scope claim and are not restricted.
+//
+// User tokens also carry the user's `role`: staff-only routes are guarded with
+// `require_role` in main(), per-record ownership is checked in the handlers with
+// `AuthenticatedUser::ensure_owner_or_staff`.
+use std::future::{ready, Ready};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::error::ApiError;
+use crate::password;
+use crate::user::{Role, User};
+
+// Signing configuration:
+// JWT_ALGORITHM=HS256 (default) with JWT_SECRET, or
+// JWT_ALGORITHM=RS256 with JWT_PRIVATE_KEY_PATH / JWT_PUBLIC_KEY_PATH (PEM),
+// JWT_TTL_SECONDS - access token lifetime (default 3600), JWT_ISSUER
+#[derive(Clone)]
+pub struct JwtConfig {
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    pub ttl_seconds: i64,
+    issuer: String,
+}
+
+impl JwtConfig {
+    pub fn from_env() -> Self {
+        let algorithm = std::env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+        let ttl_seconds = std::env::var("JWT_TTL_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(3600);
+        let issuer = std::env::var("JWT_ISSUER").unwrap_or_else(|_| "rest-api-orders".to_string());
+
+        let (algorithm, encoding_key, decoding_key) = match algorithm.as_str() {
+            "HS256" => {
+                let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set for HS256");
+                (
+                    Algorithm::HS256,
+                    EncodingKey::from_secret(secret.as_bytes()),
+                    DecodingKey::from_secret(secret.as_bytes()),
+                )
+            }
+            "RS256" => {
+                let read = |name: &str| {
+                    let path = std::env::var(name).unwrap_or_else(|_| panic!("{} must be set for RS256", name));
+                    std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+                };
+                (
+                    Algorithm::RS256,
+                    EncodingKey::from_rsa_pem(&read("JWT_PRIVATE_KEY_PATH")).expect("Invalid RSA private key"),
+                    DecodingKey::from_rsa_pem(&read("JWT_PUBLIC_KEY_PATH")).expect("Invalid RSA public key"),
+                )
+            }
+            other => panic!("Unsupported JWT_ALGORITHM: {}", other),
+        };
+
+        JwtConfig {
+            algorithm,
+            encoding_key,
+            decoding_key,
+            ttl_seconds,
+            issuer,
+        }
+    }
+
+    // First-party token for a user (login_check)
+    pub fn issue(&self, user_id: i32, email: &str, role: Role) -> Result<String, ApiError> {
+        self.sign(user_id.to_string(), Some(email.to_string()), Some(role), None, None)
+    }
+
+    // `sub` is the user id, or "client:<client_id>" for client_credentials tokens
+    pub fn sign(
+        &self,
+        sub: String,
+        email: Option<String>,
+        role: Option<Role>,
+        scope: Option<String>,
+        client_id: Option<String>,
+    ) -> Result<String, ApiError> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub,
+            email,
+            role,
+            scope,
+            client_id,
+            iss: self.issuer.clone(),
+            iat: now,
+            exp: now + self.ttl_seconds,
+        };
+
+        jsonwebtoken::encode(&Header::new(self.algorithm), &claims, &self.encoding_key)
+            .map_err(|e| ApiError::Internal(format!("failed to sign token: {}", e)))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, ApiError> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+
+        jsonwebtoken::decode::<Claims>(token, &self.decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| ApiError::Unauthorized(format!("Invalid token: {}", e)))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    // user_id or "client:<client_id>"
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    // role at the time the token was issued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    // space separated OAuth2 scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    pub iss: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+// The user behind the bearer token of the current request
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: i32,
+    pub role: Role,
+}
+
+impl AuthenticatedUser {
+    pub fn is_staff(&self) -> bool {
+        self.role >= Role::Staff
+    }
+
+    // Customers may only touch records that belong to them
+    pub fn ensure_owner_or_staff(&self, owner_id: i64) -> Result<(), ApiError> {
+        if self.is_staff() || i64::from(self.user_id) == owner_id {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden("You can only access your own records".to_string()))
+        }
+    }
+}
+
+// Scopes granted to the bearer token; None for unrestricted first-party tokens
+#[derive(Debug, Clone)]
+pub struct GrantedScopes(Option<Vec<String>>);
+
+impl GrantedScopes {
+    pub fn allows(&self, scope: &str) -> bool {
+        match &self.0 {
+            Some(scopes) => scopes.iter().any(|s| s == scope),
+            None => true,
+        }
+    }
+}
+
+// `sub` claim of the bearer token: a user id, or "client:<client_id>"
+#[derive(Debug, Clone)]
+pub struct TokenSubject(pub String);
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| ApiError::Unauthorized("Authentication required".to_string())),
+        )
+    }
+}
+
+// Middleware: requests without a token pass through anonymously,
+// requests with an invalid or expired token are rejected with 401
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    if let Some(token) = token {
+        let data = req
+            .app_data::<web::Data<AppState>>()
+            .expect("AppState is registered");
+        let claims = data.jwt.verify(&token)?;
+
+        // client_credentials tokens act on behalf of a client, not a user
+        if !claims.sub.starts_with("client:") {
+            let user_id = claims
+                .sub
+                .parse::<i32>()
+                .map_err(|_| ApiError::Unauthorized("Invalid token subject".to_string()))?;
+            // Tokens issued before roles existed belong to customers
+            let role = claims.role.unwrap_or(Role::Customer);
+            req.extensions_mut().insert(AuthenticatedUser { user_id, role });
+        }
+
+        let scopes = claims
+            .scope
+            .map(|scope| scope.split_whitespace().map(str::to_string).collect());
+        req.extensions_mut().insert(GrantedScopes(scopes));
+        req.extensions_mut().insert(TokenSubject(claims.sub));
+    }
+
+    next.call(req).await
+}
+
+// Route middleware (see `require_scope!` in main): the request must be authenticated
+// and, for OAuth2 tokens, carry `scope`
+pub async fn require_scope(
+    scope: &'static str,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let granted = req.extensions().get::<GrantedScopes>().cloned();
+
+    match granted {
+        None => return Err(ApiError::Unauthorized("Authentication required".to_string()).into()),
+        Some(granted) if !granted.allows(scope) => {
+            return Err(ApiError::Forbidden(format!("Token lacks the {} scope", scope)).into());
+        }
+        Some(_) => {}
+    }
+
+    next.call(req).await
+}
+
+// Scope a client must be registered with for its client_credentials tokens to
+// pass staff routes, e.g. `oauth create-client erp --scopes "staff orders:read"`
+pub const CLIENT_STAFF_SCOPE: &str = "staff";
+
+// Route middleware (see `require_role!` in main): the user must have at least `role`.
+// client_credentials tokens have no user: they reach staff routes only with the
+// CLIENT_STAFF_SCOPE scope, and never admin ones
+pub async fn require_role(
+    role: Role,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let user = req.extensions().get::<AuthenticatedUser>().cloned();
+    let granted = req.extensions().get::<GrantedScopes>().cloned();
+
+    match (user, granted) {
+        (Some(user), _) if user.role < role => {
+            return Err(ApiError::Forbidden(format!("Requires the {} role", role)).into());
+        }
+        (Some(_), _) => {}
+        (None, Some(granted)) if role < Role::Admin && granted.allows(CLIENT_STAFF_SCOPE) => {}
+        (None, Some(_)) => {
+            return Err(ApiError::Forbidden(format!("Requires the {} role, client tokens don't have it", role)).into());
+        }
+        (None, None) => return Err(ApiError::Unauthorized("Authentication required".to_string()).into()),
+    }
+
+    next.call(req).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    // LexikJWT-compatible clients send the email as "username"
+    #[serde(alias = "username")]
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginResponse {
+    pub token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct Credentials {
+    pub user_id: i32,
+    pub email: String,
+    pub role: Role,
+    password_hash: String,
+    is_active: Option<bool>,
+}
+
+// Endpoint Callbacks
+// Login (React frontend: REACT_APP_SHOP_JWT_TOKEN_URL)
+// curl -X POST http://localhost:8080/api/login_check \
+//   -H "Content-Type: application/json" \
+//   -d '{"username": "beam@example.com", "password": "secret123"}'
+pub async fn login_check(
+    data: web::Data<AppState>,
+    login_req: web::Json<LoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let credentials = check_credentials(&data, &login_req.email, &login_req.password).await?;
+    let token = data.jwt.issue(credentials.user_id, &credentials.email, credentials.role)?;
+
+    Ok(HttpResponse::Ok().json(LoginResponse {
+        token,
+        token_type: "Bearer",
+        expires_in: data.jwt.ttl_seconds,
+    }))
+}
+
+// Same error for unknown email, wrong password and disabled account
+pub(crate) async fn check_credentials(
+    data: &AppState,
+    email: &str,
+    password: &str,
+) -> Result<Credentials, ApiError> {
+    let invalid = || ApiError::Unauthorized("Invalid credentials".to_string());
+
+    let credentials = sqlx::query_as::<_, Credentials>(
+        "SELECT user_id, email, role, password_hash, is_active FROM users WHERE email = $1"
+    )
+        .bind(email.trim().to_lowercase())
+        .fetch_optional(&data.db)
+        .await?;
+
+    // Unknown emails still pay for a verification, the response time doesn't tell them apart
+    let password = password.to_string();
+    let password_hash = credentials
+        .as_ref()
+        .map_or(password::DUMMY_HASH, |c| c.password_hash.as_str())
+        .to_string();
+    let valid = web::block(move || password::verify_password(&password, &password_hash))
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    match credentials {
+        Some(credentials) if valid && credentials.is_active != Some(false) => Ok(credentials),
+        _ => Err(invalid()),
+    }
+}
+
+// Current user
+// curl http://localhost:8080/api/users/me -H "Authorization: Bearer $TOKEN"
+pub async fn get_me(
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE user_id = $1")
+        .bind(auth.user_id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("User"))?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
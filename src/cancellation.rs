@@ -0,0 +1,233 @@
+// Order cancellation.
+//
+// One transaction moves the order to cancelled (only from pending or processing,
+// see order_status.rs), gives its reserved or sold quantities back to stock,
+// stores who cancelled it and why and, if anything was paid, marks it
+// refund_pending. Only after that commit a pending refund row is recorded and
+// sent to the gateway (see payment.rs): a gateway refund can't be rolled back, so
+// it must never be issued for a cancellation that may still fail. The mark is
+// cleared when the refund succeeds. spawn_refund_retry sends a refund that was
+// never answered again under its own row, so the gateway doesn't pay it twice,
+// and records a new one only after the last one failed.
+// OrderEvent::Cancelled goes out once the refund has been attempted.
+use std::sync::Arc;
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres};
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::events::OrderEvent;
+use crate::order::Order;
+use crate::order_status::{self, OrderStatus};
+use crate::payment::{self, Refund};
+use crate::payment_provider::PaymentProvider;
+
+const MAX_REASON_LENGTH: usize = 500;
+const REFUND_RETRY_SECONDS: u64 = 600;
+
+#[derive(Debug, Serialize)]
+pub struct Cancellation {
+    #[serde(flatten)]
+    pub order: Order,
+    // None when nothing had been paid, or the refund is still pending
+    pub refund: Option<Refund>,
+}
+
+impl Cancellation {
+    pub fn event(&self) -> OrderEvent {
+        OrderEvent::Cancelled {
+            order_id: self.order.order_id as i32,
+            order_number: self.order.order_number.clone(),
+            user_id: self.order.user_id as i32,
+            reason: self.order.cancellation_reason.clone(),
+            cancelled_by: self.order.cancelled_by,
+            refunded_amount: self.refund.as_ref().map(|r| r.amount),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelOrderRequest {
+    pub reason: String,
+}
+
+// Cancels the order inside the caller's transaction; a paid order is only marked
+// refund_pending, call `finish` once the transaction has committed
+pub async fn cancel(
+    conn: &mut PgConnection,
+    order_id: i32,
+    reason: Option<&str>,
+    cancelled_by: Option<i32>,
+) -> Result<Order, ApiError> {
+    // Checks the transition and returns the stock
+    let order = order_status::change_status(conn, order_id, OrderStatus::Cancelled, cancelled_by, reason).await?;
+
+    let order = sqlx::query_as::<_, Order>(
+        "UPDATE orders SET cancellation_reason = $2, cancelled_at = NOW(), cancelled_by = $3, refund_pending = $4 WHERE order_id = $1 RETURNING *"
+    )
+        .bind(order_id)
+        .bind(reason)
+        .bind(cancelled_by)
+        .bind(order.payment_status.can_refund())
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(order)
+}
+
+// Refunds a cancelled order marked refund_pending: the pending refund it already
+// has, or a new one for everything not refunded yet
+pub async fn refund(
+    db: &Pool<Postgres>,
+    provider: &dyn PaymentProvider,
+    order_id: i32,
+) -> Result<Option<Refund>, ApiError> {
+    let mut tx = db.begin().await?;
+
+    let order = sqlx::query_as::<_, Order>("SELECT * FROM orders WHERE order_id = $1 AND refund_pending FOR UPDATE")
+        .bind(order_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let Some(order) = order else {
+        return Ok(None);
+    };
+
+    // Staff may have refunded it by hand meanwhile
+    if !order.payment_status.can_refund() {
+        sqlx::query("UPDATE orders SET refund_pending = FALSE WHERE order_id = $1")
+            .bind(order_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        return Ok(None);
+    }
+
+    let pending = sqlx::query_as::<_, Refund>(
+        "SELECT r.* FROM refunds r JOIN payments p ON p.payment_id = r.payment_id
+        WHERE p.order_id = $1 AND r.status = 'pending' AND (r.reason IS NULL OR r.reason NOT IN ($2, $3))
+        ORDER BY r.refund_id LIMIT 1"
+    )
+        .bind(order_id)
+        .bind(payment::DUPLICATE_PAYMENT)
+        .bind(payment::CANCELLED_ORDER_PAYMENT)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let refund = match pending {
+        Some(refund) => refund,
+        None => payment::record_refund(&mut tx, order_id, None, order.cancellation_reason.as_deref()).await?,
+    };
+
+    tx.commit().await?;
+
+    Ok(Some(payment::settle_refund(db, provider, refund.refund_id).await?))
+}
+
+// After the cancellation has committed: refunds it and publishes the event
+pub async fn finish(data: &AppState, order: Order) -> Result<Cancellation, ApiError> {
+    let order_id = order.order_id as i32;
+
+    let cancellation = match refund(&data.db, data.payments.as_ref(), order_id).await {
+        Ok(None) => Cancellation { order, refund: None },
+        // payment_status and refund_pending changed
+        Ok(refund) => {
+            let order = sqlx::query_as::<_, Order>("SELECT * FROM orders WHERE order_id = $1")
+                .bind(order_id)
+                .fetch_one(&data.db)
+                .await?;
+            Cancellation { order, refund }
+        }
+        Err(e) => {
+            eprintln!("❌ Refund of cancelled order {} failed, will retry: {}", order.order_number, e);
+            Cancellation { order, refund: None }
+        }
+    };
+
+    data.events.publish(cancellation.event());
+
+    Ok(cancellation)
+}
+
+// Retries the refunds of cancelled orders that are still refund_pending
+pub fn spawn_refund_retry(db: Pool<Postgres>, provider: Arc<dyn PaymentProvider>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(REFUND_RETRY_SECONDS));
+        loop {
+            interval.tick().await;
+            let pending = sqlx::query_scalar::<_, i32>("SELECT order_id FROM orders WHERE refund_pending ORDER BY order_id")
+                .fetch_all(&db)
+                .await;
+            let order_ids = match pending {
+                Ok(order_ids) => order_ids,
+                Err(e) => {
+                    eprintln!("❌ Failed to load pending refunds: {}", e);
+                    continue;
+                }
+            };
+            for order_id in order_ids {
+                if let Err(e) = refund(&db, provider.as_ref(), order_id).await {
+                    eprintln!("❌ Refund of cancelled order {} failed again: {}", order_id, e);
+                }
+            }
+        }
+    });
+}
+
+// Endpoint Callbacks
+// Cancel an order. Customers can cancel their own orders while they are pending,
+// staff and client_credentials tokens also while they are processing
+// curl -X POST http://localhost:8080/api/orders/1/cancel \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"reason": "ordered the wrong size"}'
+pub async fn cancel_order(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    cancel_req: web::Json<CancelOrderRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let order_id = path.into_inner();
+
+    let reason = cancel_req.reason.trim();
+    if reason.is_empty() {
+        return Err(ApiError::validation("reason is required"));
+    }
+    if reason.chars().count() > MAX_REASON_LENGTH {
+        return Err(ApiError::validation(format!(
+            "reason must be at most {} characters",
+            MAX_REASON_LENGTH
+        )));
+    }
+
+    let mut tx = data.db.begin().await?;
+
+    // Locked first so the status can't move between this check and the cancel
+    let (owner_id, status) = sqlx::query_as::<_, (i32, OrderStatus)>(
+        "SELECT user_id, status FROM orders WHERE order_id = $1 FOR UPDATE"
+    )
+        .bind(order_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Order"))?;
+
+    if let Some(user) = &auth
+        && !user.is_staff()
+    {
+        user.ensure_owner_or_staff(i64::from(owner_id))?;
+        if status != OrderStatus::Pending {
+            return Err(ApiError::Forbidden(format!(
+                "The order is {}, only staff can cancel it now",
+                status
+            )));
+        }
+    }
+
+    let order = cancel(&mut tx, order_id, Some(reason), auth.map(|a| a.user_id)).await?;
+
+    tx.commit().await?;
+
+    let cancellation = finish(&data, order).await?;
+
+    Ok(HttpResponse::Ok().json(cancellation))
+}
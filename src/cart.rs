@@ -0,0 +1,444 @@
+// Shopping cart.
+//
+// Every caller has at most one active cart, addressed as /api/cart:
+//   - signed-in users by their token;
+//   - guests by the `X-Cart-Token` header. The first write without a token
+//     creates a guest cart and returns its token in `X-Cart-Token`.
+// A request that carries both a bearer token and a guest cart token merges the
+// guest cart into the user's cart, so the frontend only has to keep sending the
+// header after login.
+//
+// Lines store no prices: they are always priced at the current product price.
+// POST /api/cart/checkout turns the cart into an order through checkout::place_order.
+use actix_web::{web, HttpRequest, HttpResponse};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::checkout::{self, CheckoutLine, CheckoutRequest};
+use crate::error::ApiError;
+use crate::money;
+use crate::oauth;
+use crate::order_number;
+use crate::pricing;
+
+pub const CART_TOKEN_HEADER: &str = "X-Cart-Token";
+
+// Per line, keeps typos like 1000 instead of 10 out of the cart
+const MAX_LINE_QUANTITY: i32 = 999;
+
+// Data models
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct CartLine {
+    pub product_id: i32,
+    pub name: String,
+    pub sku: Option<String>,
+    pub image_url: Option<String>,
+    pub quantity: i32,
+    // current product price, see pricing.rs
+    #[serde(with = "money")]
+    pub unit_price: Decimal,
+    #[serde(with = "money")]
+    pub subtotal: Decimal,
+    // false for deleted or unavailable products, checkout will refuse them
+    pub is_available: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Cart {
+    // None until something is added
+    pub cart_id: Option<i32>,
+    pub items: Vec<CartLine>,
+    #[serde(with = "money")]
+    pub total_amount: Decimal,
+    pub item_count: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddCartItemRequest {
+    pub product_id: i32,
+    #[serde(default = "default_quantity")]
+    pub quantity: i32,
+}
+
+fn default_quantity() -> i32 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateCartItemRequest {
+    pub quantity: i32,
+}
+
+// Body of POST /api/cart/checkout, the items come from the cart
+#[derive(Debug, Deserialize)]
+pub struct CartCheckoutRequest {
+    pub shipping_address: String,
+    pub billing_address: Option<String>,
+    pub payment_method: Option<String>,
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub coupon_codes: Vec<String>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+}
+
+// The caller's cart; `new_token` is set when a guest cart was just created
+struct CurrentCart {
+    cart_id: i32,
+    new_token: Option<String>,
+}
+
+fn validate_quantity(quantity: i32) -> Result<i32, ApiError> {
+    if !(1..=MAX_LINE_QUANTITY).contains(&quantity) {
+        return Err(ApiError::validation(format!(
+            "quantity must be between 1 and {}",
+            MAX_LINE_QUANTITY
+        )));
+    }
+    Ok(quantity)
+}
+
+fn guest_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(CART_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+}
+
+// Finds (and locks) the caller's active cart, merging a guest cart into the user's
+// one. With `create` a missing cart is created, otherwise None is returned
+async fn current_cart(
+    conn: &mut PgConnection,
+    req: &HttpRequest,
+    auth: Option<&AuthenticatedUser>,
+    create: bool,
+) -> Result<Option<CurrentCart>, ApiError> {
+    // Unknown, merged or converted tokens are ignored
+    let guest_cart = match guest_token(req) {
+        Some(token) => sqlx::query_scalar::<_, i32>(
+            "SELECT cart_id FROM carts WHERE guest_token_hash = $1 AND user_id IS NULL AND status = 'active' FOR UPDATE"
+        )
+            .bind(oauth::sha256_hex(token))
+            .fetch_optional(&mut *conn)
+            .await?,
+        None => None,
+    };
+
+    let Some(user) = auth else {
+        if let Some(cart_id) = guest_cart {
+            return Ok(Some(CurrentCart { cart_id, new_token: None }));
+        }
+        if !create {
+            return Ok(None);
+        }
+
+        let token = oauth::generate_token();
+        let cart_id = sqlx::query_scalar::<_, i32>(
+            "INSERT INTO carts (guest_token_hash) VALUES ($1) RETURNING cart_id"
+        )
+            .bind(oauth::sha256_hex(&token))
+            .fetch_one(&mut *conn)
+            .await?;
+        return Ok(Some(CurrentCart { cart_id, new_token: Some(token) }));
+    };
+
+    let user_cart = sqlx::query_scalar::<_, i32>(
+        "SELECT cart_id FROM carts WHERE user_id = $1 AND status = 'active' FOR UPDATE"
+    )
+        .bind(user.user_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    let cart_id = match (user_cart, guest_cart) {
+        // Quantities of the same product are added up
+        (Some(cart_id), Some(guest_cart_id)) => {
+            sqlx::query(
+                "INSERT INTO cart_items (cart_id, product_id, quantity) SELECT $1, product_id, quantity FROM cart_items WHERE cart_id = $2 ON CONFLICT (cart_id, product_id) DO UPDATE SET quantity = LEAST(cart_items.quantity + EXCLUDED.quantity, $3)"
+            )
+                .bind(cart_id)
+                .bind(guest_cart_id)
+                .bind(MAX_LINE_QUANTITY)
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("UPDATE carts SET status = 'merged' WHERE cart_id = $1")
+                .bind(guest_cart_id)
+                .execute(&mut *conn)
+                .await?;
+            Some(cart_id)
+        }
+        (Some(cart_id), None) => Some(cart_id),
+        // The guest cart simply becomes the user's cart
+        (None, Some(guest_cart_id)) => {
+            sqlx::query("UPDATE carts SET user_id = $1, guest_token_hash = NULL WHERE cart_id = $2")
+                .bind(user.user_id)
+                .bind(guest_cart_id)
+                .execute(&mut *conn)
+                .await?;
+            Some(guest_cart_id)
+        }
+        (None, None) if create => {
+            let cart_id = sqlx::query_scalar::<_, i32>(
+                "INSERT INTO carts (user_id) VALUES ($1) RETURNING cart_id"
+            )
+                .bind(user.user_id)
+                .fetch_one(&mut *conn)
+                .await?;
+            Some(cart_id)
+        }
+        (None, None) => None,
+    };
+
+    Ok(cart_id.map(|cart_id| CurrentCart { cart_id, new_token: None }))
+}
+
+async fn load_cart(conn: &mut PgConnection, cart_id: i32) -> Result<Cart, ApiError> {
+    let items = sqlx::query_as::<_, CartLine>(&format!(
+        "SELECT ci.product_id, p.name, p.sku, p.image_url, ci.quantity, l.unit_price, l.unit_price * ci.quantity AS subtotal,
+                (COALESCE(p.is_available, TRUE) AND p.deleted_at IS NULL) AS is_available
+        FROM cart_items ci
+        JOIN products p ON p.product_id = ci.product_id
+        CROSS JOIN LATERAL (SELECT {} AS unit_price) l
+        WHERE ci.cart_id = $1
+        ORDER BY ci.added_at, ci.cart_item_id",
+        pricing::CURRENT_PRICE
+    ))
+        .bind(cart_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    Ok(Cart {
+        cart_id: Some(cart_id),
+        total_amount: money::round(items.iter().map(|line| line.subtotal).sum()),
+        item_count: items.iter().map(|line| line.quantity).sum(),
+        items,
+    })
+}
+
+// The cart, plus the token of a freshly created guest cart
+fn cart_response(cart: Cart, new_token: Option<String>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if let Some(token) = new_token {
+        response.insert_header((CART_TOKEN_HEADER, token));
+    }
+    response.json(cart)
+}
+
+// Only products that can be ordered go into a cart
+async fn ensure_orderable(conn: &mut PgConnection, product_id: i32) -> Result<(), ApiError> {
+    let is_available = sqlx::query_scalar::<_, Option<bool>>(
+        "SELECT is_available FROM products WHERE product_id = $1 AND deleted_at IS NULL"
+    )
+        .bind(product_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| ApiError::Unprocessable {
+            code: "product_not_found",
+            detail: format!("Product {} does not exist", product_id),
+        })?;
+
+    if is_available == Some(false) {
+        return Err(ApiError::Unprocessable {
+            code: "product_unavailable",
+            detail: format!("Product {} is not available", product_id),
+        });
+    }
+    Ok(())
+}
+
+// Endpoint Callbacks
+// Current cart
+// curl http://localhost:8080/api/cart -H "X-Cart-Token: $CART_TOKEN"
+pub async fn get_cart(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = data.db.begin().await?;
+
+    let cart = match current_cart(&mut tx, &req, auth.as_ref(), false).await? {
+        Some(current) => load_cart(&mut tx, current.cart_id).await?,
+        None => Cart {
+            cart_id: None,
+            items: Vec::new(),
+            total_amount: Decimal::ZERO,
+            item_count: 0,
+        },
+    };
+
+    tx.commit().await?;
+
+    Ok(cart_response(cart, None))
+}
+
+// Add a product, or more of it
+// curl -i -X POST http://localhost:8080/api/cart/items \
+//   -H "Content-Type: application/json" -d '{"product_id": 4, "quantity": 2}'
+pub async fn add_cart_item(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    item_req: web::Json<AddCartItemRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let quantity = validate_quantity(item_req.quantity)?;
+    let mut tx = data.db.begin().await?;
+
+    ensure_orderable(&mut tx, item_req.product_id).await?;
+    let current = current_cart(&mut tx, &req, auth.as_ref(), true)
+        .await?
+        .ok_or_else(|| ApiError::Internal("cart was not created".to_string()))?;
+
+    let line_quantity = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO cart_items (cart_id, product_id, quantity) VALUES ($1, $2, $3) ON CONFLICT (cart_id, product_id) DO UPDATE SET quantity = cart_items.quantity + EXCLUDED.quantity RETURNING quantity"
+    )
+        .bind(current.cart_id)
+        .bind(item_req.product_id)
+        .bind(quantity)
+        .fetch_one(&mut *tx)
+        .await?;
+    validate_quantity(line_quantity)?;
+
+    let cart = load_cart(&mut tx, current.cart_id).await?;
+    tx.commit().await?;
+
+    Ok(cart_response(cart, current.new_token))
+}
+
+// Set the quantity of a line
+// curl -X PUT http://localhost:8080/api/cart/items/4 \
+//   -H "X-Cart-Token: $CART_TOKEN" -H "Content-Type: application/json" -d '{"quantity": 3}'
+pub async fn update_cart_item(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    item_req: web::Json<UpdateCartItemRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let quantity = validate_quantity(item_req.quantity)?;
+    let mut tx = data.db.begin().await?;
+
+    let current = current_cart(&mut tx, &req, auth.as_ref(), false)
+        .await?
+        .ok_or(ApiError::NotFound("Cart"))?;
+
+    let result = sqlx::query("UPDATE cart_items SET quantity = $1 WHERE cart_id = $2 AND product_id = $3")
+        .bind(quantity)
+        .bind(current.cart_id)
+        .bind(path.into_inner())
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Cart item"));
+    }
+
+    let cart = load_cart(&mut tx, current.cart_id).await?;
+    tx.commit().await?;
+
+    Ok(cart_response(cart, None))
+}
+
+// Remove a line
+// curl -X DELETE http://localhost:8080/api/cart/items/4 -H "X-Cart-Token: $CART_TOKEN"
+pub async fn delete_cart_item(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = data.db.begin().await?;
+
+    let current = current_cart(&mut tx, &req, auth.as_ref(), false)
+        .await?
+        .ok_or(ApiError::NotFound("Cart"))?;
+
+    let result = sqlx::query("DELETE FROM cart_items WHERE cart_id = $1 AND product_id = $2")
+        .bind(current.cart_id)
+        .bind(path.into_inner())
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Cart item"));
+    }
+
+    let cart = load_cart(&mut tx, current.cart_id).await?;
+    tx.commit().await?;
+
+    Ok(cart_response(cart, None))
+}
+
+// Empty the cart
+// curl -X DELETE http://localhost:8080/api/cart -H "X-Cart-Token: $CART_TOKEN"
+pub async fn clear_cart(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = data.db.begin().await?;
+
+    if let Some(current) = current_cart(&mut tx, &req, auth.as_ref(), false).await? {
+        sqlx::query("DELETE FROM cart_items WHERE cart_id = $1")
+            .bind(current.cart_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Turn the cart into an order (signed-in users only), same path as /api/orders/checkout
+// curl -X POST http://localhost:8080/api/cart/checkout \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"shipping_address": "Moscow, Tverskaya 1"}'
+pub async fn checkout_cart(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    checkout_req: web::Json<CartCheckoutRequest>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order_number = order_number::next(&data.db, &data.order_numbers).await?;
+    let mut tx = data.db.begin().await?;
+
+    let current = current_cart(&mut tx, &req, Some(&auth), false)
+        .await?
+        .ok_or_else(|| ApiError::validation("Cart is empty"))?;
+
+    let items: Vec<CheckoutLine> = sqlx::query_as::<_, (i32, i32)>(
+        "SELECT product_id, quantity FROM cart_items WHERE cart_id = $1 ORDER BY product_id"
+    )
+        .bind(current.cart_id)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|(product_id, quantity)| CheckoutLine { product_id, quantity })
+        .collect();
+    if items.is_empty() {
+        return Err(ApiError::validation("Cart is empty"));
+    }
+
+    let order_req = CheckoutRequest {
+        user_id: auth.user_id,
+        shipping_address: checkout_req.shipping_address.clone(),
+        billing_address: checkout_req.billing_address.clone(),
+        payment_method: checkout_req.payment_method.clone(),
+        notes: checkout_req.notes.clone(),
+        items,
+        coupon_codes: checkout_req.coupon_codes.clone(),
+        country: checkout_req.country.clone(),
+        region: checkout_req.region.clone(),
+    };
+    let placed = checkout::place_order(&mut tx, &order_req, data.inventory.reservation_ttl, order_number, &data.tax).await?;
+
+    sqlx::query("UPDATE carts SET status = 'converted', order_id = $1 WHERE cart_id = $2")
+        .bind(placed.order.order_id as i32)
+        .bind(current.cart_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(placed))
+}
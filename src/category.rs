@@ -0,0 +1,285 @@
+// Product categories.
+//
+// Categories form a tree through `parent_id`. Listing the products of a category
+// includes the products of all its descendants (see `descendant_ids`).
+use std::collections::HashMap;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres};
+
+use crate::AppState;
+use crate::error::ApiError;
+use crate::pagination::{self, PageParams};
+use crate::product::{self, Product};
+
+// Data models
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Category {
+    pub category_id: i32,
+    pub parent_id: Option<i32>,
+    pub name: String,
+    pub slug: String,
+    pub description: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Node of GET /api/categories/tree
+#[derive(Debug, Serialize)]
+pub struct CategoryNode {
+    #[serde(flatten)]
+    pub category: Category,
+    pub children: Vec<CategoryNode>,
+}
+
+// Body of POST and PUT (full replacement, no parent_id = root category)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CategoryRequest {
+    pub name: String,
+    // derived from name when omitted
+    pub slug: Option<String>,
+    pub parent_id: Option<i32>,
+    pub description: Option<String>,
+}
+
+impl CategoryRequest {
+    fn validated_slug(&self) -> Result<String, ApiError> {
+        if self.name.trim().is_empty() {
+            return Err(ApiError::validation("name must not be empty"));
+        }
+
+        let slug = match &self.slug {
+            Some(slug) if slugify(slug) != *slug => {
+                return Err(ApiError::validation(
+                    "slug may only contain lowercase letters, digits and dashes",
+                ));
+            }
+            Some(slug) => slug.clone(),
+            None => slugify(&self.name),
+        };
+        if slug.is_empty() {
+            return Err(ApiError::validation("slug must not be empty"));
+        }
+        Ok(slug)
+    }
+}
+
+// "Смартфоны и планшеты" -> "смартфоны-и-планшеты"
+fn slugify(value: &str) -> String {
+    let mut slug = String::new();
+    for c in value.trim().to_lowercase().chars() {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+// The category itself and everything below it; empty if it doesn't exist
+pub async fn descendant_ids(db: &Pool<Postgres>, category_id: i32) -> Result<Vec<i32>, ApiError> {
+    // UNION (not UNION ALL) terminates even on a corrupted, cyclic tree
+    let ids = sqlx::query_scalar::<_, i32>(
+        "WITH RECURSIVE tree AS (
+            SELECT category_id FROM categories WHERE category_id = $1
+            UNION
+            SELECT c.category_id FROM categories c JOIN tree t ON c.parent_id = t.category_id
+        )
+        SELECT category_id FROM tree"
+    )
+        .bind(category_id)
+        .fetch_all(db)
+        .await?;
+
+    Ok(ids)
+}
+
+// The category itself and everything above it
+async fn ancestor_ids(conn: &mut PgConnection, category_id: i32) -> Result<Vec<i32>, ApiError> {
+    let ids = sqlx::query_scalar::<_, i32>(
+        "WITH RECURSIVE up AS (
+            SELECT category_id, parent_id FROM categories WHERE category_id = $1
+            UNION
+            SELECT c.category_id, c.parent_id FROM categories c JOIN up ON c.category_id = up.parent_id
+        )
+        SELECT category_id FROM up"
+    )
+        .bind(category_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    Ok(ids)
+}
+
+fn build_tree(
+    parent_id: Option<i32>,
+    children_of: &mut HashMap<Option<i32>, Vec<Category>>,
+) -> Vec<CategoryNode> {
+    children_of
+        .remove(&parent_id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|category| {
+            let children = build_tree(Some(category.category_id), children_of);
+            CategoryNode { category, children }
+        })
+        .collect()
+}
+
+// Endpoint Callbacks
+// Flat list
+// curl http://localhost:8080/api/categories
+pub async fn get_categories(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let categories = sqlx::query_as::<_, Category>("SELECT * FROM categories ORDER BY name")
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(categories))
+}
+
+// Nested tree of all categories
+// curl http://localhost:8080/api/categories/tree
+pub async fn get_category_tree(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let categories = sqlx::query_as::<_, Category>("SELECT * FROM categories ORDER BY name")
+        .fetch_all(&data.db)
+        .await?;
+
+    let mut children_of: HashMap<Option<i32>, Vec<Category>> = HashMap::new();
+    for category in categories {
+        children_of.entry(category.parent_id).or_default().push(category);
+    }
+
+    Ok(HttpResponse::Ok().json(build_tree(None, &mut children_of)))
+}
+
+// Get category by ID (React frontend: /category/{id})
+// curl http://localhost:8080/api/categories/1
+pub async fn get_category(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let category_id = path.into_inner();
+
+    let category = sqlx::query_as::<_, Category>("SELECT * FROM categories WHERE category_id = $1")
+        .bind(category_id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Category"))?;
+
+    Ok(HttpResponse::Ok().json(category))
+}
+
+// Products of a category and all of its subcategories
+// curl "http://localhost:8080/api/categories/1/products?sort=price&limit=20"
+pub async fn get_category_products(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    page: web::Query<PageParams>,
+) -> Result<HttpResponse, ApiError> {
+    let category_ids = descendant_ids(&data.db, path.into_inner()).await?;
+    if category_ids.is_empty() {
+        return Err(ApiError::NotFound("Category"));
+    }
+
+    pagination::list::<Product>(&data.db, &req, &product::PRODUCT_LIST, &page, |q| {
+        q.push(" AND deleted_at IS NULL AND category_id = ANY(").push_bind(category_ids.clone()).push(")");
+    })
+        .await
+}
+
+// Create category (staff only)
+// curl -X POST http://localhost:8080/api/categories \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"name": "Смартфоны", "parent_id": 1}'
+pub async fn create_category(
+    data: web::Data<AppState>,
+    category_req: web::Json<CategoryRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = category_req.validated_slug()?;
+
+    // Duplicate slug -> 409, unknown parent_id -> 422 (ApiError)
+    let category = sqlx::query_as::<_, Category>(
+        "INSERT INTO categories (parent_id, name, slug, description) VALUES ($1, $2, $3, $4) RETURNING *"
+    )
+        .bind(category_req.parent_id)
+        .bind(category_req.name.trim())
+        .bind(&slug)
+        .bind(&category_req.description)
+        .fetch_one(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Created().json(category))
+}
+
+// Update / move category (staff only)
+// curl -X PUT http://localhost:8080/api/categories/2 \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"name": "Смартфоны", "slug": "smartphones", "parent_id": null}'
+pub async fn update_category(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    category_req: web::Json<CategoryRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let category_id = path.into_inner();
+    let slug = category_req.validated_slug()?;
+    let mut tx = data.db.begin().await?;
+
+    // A category can't be moved below itself. The category and the new parent's
+    // path to the root are locked first: two moves that could close a cycle
+    // between them share a row there, so the second one checks the tree the
+    // first one left
+    if let Some(parent_id) = category_req.parent_id {
+        let mut locked = ancestor_ids(&mut tx, parent_id).await?;
+        locked.push(category_id);
+        sqlx::query("SELECT category_id FROM categories WHERE category_id = ANY($1) ORDER BY category_id FOR UPDATE")
+            .bind(&locked)
+            .execute(&mut *tx)
+            .await?;
+
+        if ancestor_ids(&mut tx, parent_id).await?.contains(&category_id) {
+            return Err(ApiError::Unprocessable {
+                code: "category_cycle",
+                detail: format!("Category {} can't be moved under its own subcategory {}", category_id, parent_id),
+            });
+        }
+    }
+
+    let category = sqlx::query_as::<_, Category>(
+        "UPDATE categories SET parent_id = $1, name = $2, slug = $3, description = $4 WHERE category_id = $5 RETURNING *"
+    )
+        .bind(category_req.parent_id)
+        .bind(category_req.name.trim())
+        .bind(&slug)
+        .bind(&category_req.description)
+        .bind(category_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Category"))?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(category))
+}
+
+// Delete category (staff only); categories with subcategories or products -> 409
+// curl -X DELETE http://localhost:8080/api/categories/2 -H "Authorization: Bearer $TOKEN"
+pub async fn delete_category(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let category_id = path.into_inner();
+
+    let result = sqlx::query("DELETE FROM categories WHERE category_id = $1")
+        .bind(category_id)
+        .execute(&data.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Category"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
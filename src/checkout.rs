@@ -0,0 +1,252 @@
+// Server-side checkout.
+//
+// The client sends only product ids and quantities (and coupon codes); prices,
+// subtotals, discounts (see promotion.rs), taxes (see tax.rs) and the total are
+// computed here, and the order with its items is written in a single transaction
+// together with the stock reservation. The order number (see order_number.rs) is
+// taken just before that transaction.
+use std::collections::BTreeMap;
+
+use actix_web::{web, HttpResponse};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::inventory;
+use crate::money;
+use crate::order::Order;
+use crate::order_items::OrderItem;
+use crate::order_number;
+use crate::order_status;
+use crate::pricing;
+use crate::promotion::{self, OrderDiscount};
+use crate::tax::{self, TaxConfig, TaxLocation, TaxableLine};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckoutLine {
+    pub product_id: i32,
+    pub quantity: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckoutRequest {
+    pub user_id: i32,
+    pub shipping_address: String,
+    pub billing_address: Option<String>,
+    pub payment_method: Option<String>,
+    pub notes: Option<String>,
+    pub items: Vec<CheckoutLine>,
+    #[serde(default)]
+    pub coupon_codes: Vec<String>,
+    // where the order is taxed, TAX_DEFAULT_COUNTRY without a country
+    pub country: Option<String>,
+    pub region: Option<String>,
+}
+
+// Order together with its lines and discounts
+#[derive(Debug, Serialize)]
+pub struct PlacedOrder {
+    #[serde(flatten)]
+    pub order: Order,
+    pub items: Vec<OrderItem>,
+    pub discounts: Vec<OrderDiscount>,
+}
+
+// Line priced at the current product price
+#[derive(Debug)]
+pub struct PricedLine {
+    pub product_id: i32,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+    pub subtotal: Decimal,
+    pub tax_class: String,
+}
+
+// Current product data locked for the duration of the checkout
+#[derive(Debug, sqlx::FromRow)]
+struct ProductForCheckout {
+    product_id: i32,
+    name: String,
+    price: Decimal,
+    is_available: Option<bool>,
+    tax_class: String,
+}
+
+// Endpoint Callbacks
+// Checkout
+// curl -X POST http://localhost:8080/api/orders/checkout \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"user_id": 1, "shipping_address": "Moscow, Tverskaya 1", "items": [{"product_id": 4, "quantity": 2}]}'
+pub async fn checkout(
+    data: web::Data<AppState>,
+    checkout_req: web::Json<CheckoutRequest>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    // Customers order for themselves, staff may place orders on behalf of anyone
+    auth.ensure_owner_or_staff(checkout_req.user_id.into())?;
+
+    let order_number = order_number::next(&data.db, &data.order_numbers).await?;
+    let mut tx = data.db.begin().await?;
+
+    // Any error drops `tx` and rolls everything back
+    let placed = place_order(&mut tx, &checkout_req, data.inventory.reservation_ttl, order_number, &data.tax).await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(placed))
+}
+
+// Creates the order and its items under `order_number` inside the caller's
+// transaction and reserves their stock for `reservation_ttl`
+pub async fn place_order(
+    conn: &mut PgConnection,
+    req: &CheckoutRequest,
+    reservation_ttl: chrono::Duration,
+    order_number: String,
+    tax_config: &TaxConfig,
+) -> Result<PlacedOrder, ApiError> {
+    if req.shipping_address.trim().is_empty() {
+        return Err(ApiError::validation("shipping_address must not be empty"));
+    }
+    let location = TaxLocation::resolve(tax_config, req.country.as_deref(), req.region.as_deref())?;
+
+    let quantities = merge_lines(&req.items)?;
+    let lines = price_lines(conn, &quantities).await?;
+
+    let amounts: Vec<(i32, Decimal)> = lines.iter().map(|line| (line.product_id, line.subtotal)).collect();
+    let discount_lines = promotion::discount_lines(conn, &amounts).await?;
+    let candidates = promotion::candidates(conn, req.user_id, &req.coupon_codes).await?;
+    let discounts = promotion::choose(&candidates, &discount_lines)?;
+
+    let taxes = tax::calculate(conn, &location, tax_config.prices_include_tax, &taxable_lines(&lines), &discounts).await?;
+
+    let subtotal: Decimal = lines.iter().map(|line| line.subtotal).sum();
+    let discount_amount = promotion::total(&discounts);
+    let tax_amount = tax::total(&taxes);
+    let total_amount = tax::order_total(subtotal, discount_amount, tax_amount, tax_config.prices_include_tax);
+
+    let order = sqlx::query_as::<_, Order>(
+        "INSERT INTO orders (user_id, order_number, total_amount, discount_amount, tax_amount, tax_country, tax_region, prices_include_tax, shipping_address, billing_address, payment_method, notes) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING *"
+    )
+        .bind(req.user_id)
+        .bind(&order_number)
+        .bind(total_amount)
+        .bind(discount_amount)
+        .bind(tax_amount)
+        .bind(&location.country)
+        .bind(&location.region)
+        .bind(tax_config.prices_include_tax)
+        .bind(&req.shipping_address)
+        .bind(&req.billing_address)
+        .bind(&req.payment_method)
+        .bind(&req.notes)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    order_status::record_history(conn, order.order_id as i32, None, order.status, Some(req.user_id), None).await?;
+
+    // `taxes` is in the order of `lines`
+    let items = sqlx::query_as::<_, OrderItem>(
+        "INSERT INTO order_items (order_id, product_id, quantity, unit_price, product_name, product_sku, product_image_url, tax_class, tax_rate, taxable_amount, tax_amount)
+        SELECT $1, l.product_id, l.quantity, l.unit_price, p.name, p.sku, p.image_url, l.tax_class, l.tax_rate, l.taxable_amount, l.tax_amount
+        FROM UNNEST($2::INT[], $3::INT[], $4::NUMERIC[], $5::VARCHAR[], $6::NUMERIC[], $7::NUMERIC[], $8::NUMERIC[]) AS l(product_id, quantity, unit_price, tax_class, tax_rate, taxable_amount, tax_amount)
+        JOIN products p ON p.product_id = l.product_id ORDER BY l.product_id RETURNING *"
+    )
+        .bind(order.order_id as i32)
+        .bind(lines.iter().map(|line| line.product_id).collect::<Vec<_>>())
+        .bind(lines.iter().map(|line| line.quantity).collect::<Vec<_>>())
+        .bind(lines.iter().map(|line| line.unit_price).collect::<Vec<_>>())
+        .bind(lines.iter().map(|line| line.tax_class.clone()).collect::<Vec<_>>())
+        .bind(taxes.iter().map(|t| t.tax_rate).collect::<Vec<_>>())
+        .bind(taxes.iter().map(|t| t.taxable_amount).collect::<Vec<_>>())
+        .bind(taxes.iter().map(|t| t.tax_amount).collect::<Vec<_>>())
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let discounts = promotion::save(conn, order.order_id as i32, &discounts).await?;
+
+    // Stock is taken when the order moves to processing, see inventory.rs
+    inventory::reserve(conn, order.order_id as i32, &quantities, reservation_ttl).await?;
+
+    Ok(PlacedOrder { order, items, discounts })
+}
+
+// Locks the products (in a stable order, so concurrent checkouts can't deadlock)
+// and prices the lines at their current price
+pub(crate) async fn price_lines(
+    conn: &mut PgConnection,
+    quantities: &BTreeMap<i32, i32>,
+) -> Result<Vec<PricedLine>, ApiError> {
+    let product_ids: Vec<i32> = quantities.keys().copied().collect();
+
+    let products = sqlx::query_as::<_, ProductForCheckout>(&format!(
+        "SELECT product_id, name, {} AS price, is_available, tax_class FROM products WHERE product_id = ANY($1) AND deleted_at IS NULL ORDER BY product_id FOR UPDATE",
+        pricing::CURRENT_PRICE
+    ))
+        .bind(&product_ids)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let mut lines = Vec::with_capacity(products.len());
+    for (product_id, quantity) in quantities {
+        let product = products
+            .iter()
+            .find(|p| p.product_id == *product_id)
+            .ok_or_else(|| ApiError::Unprocessable {
+                code: "product_not_found",
+                detail: format!("Product {} does not exist", product_id),
+            })?;
+
+        if product.is_available == Some(false) {
+            return Err(ApiError::Unprocessable {
+                code: "product_unavailable",
+                detail: format!("Product {} is not available", product.name),
+            });
+        }
+
+        lines.push(PricedLine {
+            product_id: *product_id,
+            quantity: *quantity,
+            unit_price: product.price,
+            subtotal: money::round(product.price * Decimal::from(*quantity)),
+            tax_class: product.tax_class.clone(),
+        });
+    }
+    Ok(lines)
+}
+
+pub(crate) fn taxable_lines(lines: &[PricedLine]) -> Vec<TaxableLine> {
+    lines
+        .iter()
+        .map(|line| TaxableLine {
+            product_id: line.product_id,
+            tax_class: line.tax_class.clone(),
+            subtotal: line.subtotal,
+        })
+        .collect()
+}
+
+// One line per product (order_items has UNIQUE (order_id, product_id)), quantities summed
+pub(crate) fn merge_lines(lines: &[CheckoutLine]) -> Result<BTreeMap<i32, i32>, ApiError> {
+    if lines.is_empty() {
+        return Err(ApiError::validation("items must not be empty"));
+    }
+
+    let mut quantities = BTreeMap::new();
+    for line in lines {
+        if line.quantity <= 0 {
+            return Err(ApiError::validation(format!(
+                "quantity for product {} must be positive",
+                line.product_id
+            )));
+        }
+        let quantity = quantities.entry(line.product_id).or_insert(0i32);
+        *quantity = quantity
+            .checked_add(line.quantity)
+            .ok_or_else(|| ApiError::validation("quantity is too large"))?;
+    }
+    Ok(quantities)
+}
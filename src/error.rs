@@ -0,0 +1,262 @@
+// Unified API error type.
+//
+// Every handler returns `Result<HttpResponse, ApiError>`; the error is rendered as
+// RFC 7807 `application/problem+json` with a stable machine-readable `code`.
+// Raw database messages are logged, never sent to the client.
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+// Postgres SQLSTATE codes we map explicitly
+const UNIQUE_VIOLATION: &str = "23505";
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+const CHECK_VIOLATION: &str = "23514";
+const NOT_NULL_VIOLATION: &str = "23502";
+// numeric_value_out_of_range, e.g. stock_quantity + quantity past INTEGER
+const OUT_OF_RANGE: &str = "22003";
+
+#[derive(Debug)]
+pub enum ApiError {
+    // 400 - malformed request (bad JSON, bad path parameter)
+    BadRequest(String),
+    // 401 - missing or invalid credentials / signature
+    Unauthorized(String),
+    // 403 - authenticated, but not allowed to do this
+    Forbidden(String),
+    // 404 - resource name, e.g. "User"
+    NotFound(&'static str),
+    // 409 - duplicate value or resource still in use
+    Conflict { code: &'static str, detail: String },
+    // 413 - upload exceeds the configured size
+    PayloadTooLarge(String),
+    // 415 - file type we don't accept
+    UnsupportedMediaType(String),
+    // 422 - request is well-formed but violates a business or schema rule
+    Unprocessable { code: &'static str, detail: String },
+    // 502 - an external service (payment gateway) failed
+    BadGateway(String),
+    // 500 - anything unexpected; the message is only logged
+    Internal(String),
+}
+
+// RFC 7807 body
+#[derive(Debug, Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    pub code: &'static str,
+}
+
+impl ApiError {
+    pub fn validation(detail: impl Into<String>) -> Self {
+        ApiError::Unprocessable {
+            code: "validation_failed",
+            detail: detail.into(),
+        }
+    }
+
+    // Stable machine-readable code
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict { code, .. } => code,
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Unprocessable { code, .. } => code,
+            ApiError::BadGateway(_) => "upstream_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "Bad Request",
+            ApiError::Unauthorized(_) => "Unauthorized",
+            ApiError::Forbidden(_) => "Forbidden",
+            ApiError::NotFound(_) => "Not Found",
+            ApiError::Conflict { .. } => "Conflict",
+            ApiError::PayloadTooLarge(_) => "Payload Too Large",
+            ApiError::UnsupportedMediaType(_) => "Unsupported Media Type",
+            ApiError::Unprocessable { .. } => "Unprocessable Entity",
+            ApiError::BadGateway(_) => "Bad Gateway",
+            ApiError::Internal(_) => "Internal Server Error",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            ApiError::BadRequest(detail) => detail.clone(),
+            ApiError::Unauthorized(detail) => detail.clone(),
+            ApiError::Forbidden(detail) => detail.clone(),
+            ApiError::NotFound(resource) => format!("{} not found", resource),
+            ApiError::Conflict { detail, .. } => detail.clone(),
+            ApiError::PayloadTooLarge(detail) => detail.clone(),
+            ApiError::UnsupportedMediaType(detail) => detail.clone(),
+            ApiError::Unprocessable { detail, .. } => detail.clone(),
+            ApiError::BadGateway(detail) => detail.clone(),
+            ApiError::Internal(_) => "An unexpected error occurred".to_string(),
+        }
+    }
+
+    pub fn problem(&self) -> ProblemDetails {
+        ProblemDetails {
+            problem_type: format!("/problems/{}", self.code()),
+            title: self.title(),
+            status: self.status_code().as_u16(),
+            detail: self.detail(),
+            code: self.code(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Internal(message) => write!(f, "internal error: {}", message),
+            other => write!(f, "{}: {}", other.code(), other.detail()),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Unprocessable { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(message) = self {
+            eprintln!("❌ {}", message);
+        }
+
+        let body = serde_json::to_string(&self.problem()).unwrap_or_default();
+        HttpResponse::build(self.status_code())
+            .content_type("application/problem+json")
+            .body(body)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => ApiError::NotFound("Resource"),
+            sqlx::Error::Database(db) => {
+                let constraint = db.constraint().unwrap_or_default();
+                match db.code().as_deref() {
+                    Some(UNIQUE_VIOLATION) => ApiError::Conflict {
+                        code: "duplicate",
+                        detail: unique_violation_detail(constraint),
+                    },
+                    // Deleting a row that is still referenced vs. inserting a dangling reference
+                    Some(FOREIGN_KEY_VIOLATION) if db.message().starts_with("update or delete on table") => {
+                        ApiError::Conflict {
+                            code: "resource_in_use",
+                            detail: format!("Resource is still referenced ({})", constraint),
+                        }
+                    }
+                    Some(FOREIGN_KEY_VIOLATION) => ApiError::Unprocessable {
+                        code: "reference_not_found",
+                        detail: foreign_key_detail(constraint),
+                    },
+                    Some(CHECK_VIOLATION) => ApiError::Unprocessable {
+                        code: "constraint_violation",
+                        detail: check_violation_detail(constraint),
+                    },
+                    Some(NOT_NULL_VIOLATION) => ApiError::Unprocessable {
+                        code: "missing_field",
+                        detail: format!(
+                            "Required field {} is missing",
+                            db.try_downcast_ref::<sqlx::postgres::PgDatabaseError>()
+                                .and_then(|pg| pg.column())
+                                .unwrap_or("value")
+                        ),
+                    },
+                    Some(OUT_OF_RANGE) => ApiError::Unprocessable {
+                        code: "out_of_range",
+                        detail: "A value is out of range".to_string(),
+                    },
+                    _ => ApiError::Internal(e.to_string()),
+                }
+            }
+            _ => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+// Human readable messages for known unique constraints
+fn unique_violation_detail(constraint: &str) -> String {
+    match constraint {
+        "users_email_key" => "Email already exists".to_string(),
+        "users_username_key" => "Username already exists".to_string(),
+        "products_sku_key" => "Sku already exists".to_string(),
+        "categories_slug_key" => "Slug already exists".to_string(),
+        "orders_order_number_key" => "Order number already exists".to_string(),
+        "order_items_order_id_product_id_key" => "Product is already in this order".to_string(),
+        "promotions_code_key" => "Coupon code already exists".to_string(),
+        "tax_rates_scope_key" => "A tax rate for this country, region and tax class already exists".to_string(),
+        other => format!("Duplicate value ({})", other),
+    }
+}
+
+// Human readable messages for known CHECK constraints
+fn check_violation_detail(constraint: &str) -> String {
+    match constraint {
+        "products_stock_quantity_check" => "Not enough stock on hand".to_string(),
+        "products_sale_price_check" => "sale_price must be below regular_price".to_string(),
+        other => format!("Value violates constraint {}", other),
+    }
+}
+
+// Human readable messages for known foreign keys
+fn foreign_key_detail(constraint: &str) -> String {
+    match constraint {
+        "orders_user_id_fkey" => "User does not exist".to_string(),
+        "order_items_order_id_fkey" => "Order does not exist".to_string(),
+        "order_items_product_id_fkey" => "Product does not exist".to_string(),
+        "products_category_id_fkey" => "Category does not exist".to_string(),
+        "categories_parent_id_fkey" => "Parent category does not exist".to_string(),
+        "promotions_product_id_fkey" => "Product does not exist".to_string(),
+        "promotions_category_id_fkey" => "Category does not exist".to_string(),
+        other => format!("Referenced resource does not exist ({})", other),
+    }
+}
+
+// Turns extractor failures (bad JSON, bad path) into problem+json as well
+pub fn json_error_handler(
+    err: actix_web::error::JsonPayloadError,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    ApiError::BadRequest(err.to_string()).into()
+}
+
+pub fn path_error_handler(
+    err: actix_web::error::PathError,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    ApiError::BadRequest(err.to_string()).into()
+}
+
+pub fn query_error_handler(
+    err: actix_web::error::QueryPayloadError,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    ApiError::BadRequest(err.to_string()).into()
+}
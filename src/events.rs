@@ -0,0 +1,72 @@
+// In-process domain events.
+//
+// Handlers publish after their transaction commits, so a subscriber never sees
+// an event for a change that was rolled back. Delivery is best effort: nobody
+// listening is fine, and a subscriber that falls more than EVENT_BUFFER events
+// behind skips the oldest ones. Anything that must not be lost belongs in the
+// database, not here.
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::money;
+
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OrderEvent {
+    Cancelled {
+        order_id: i32,
+        order_number: String,
+        user_id: i32,
+        reason: Option<String>,
+        cancelled_by: Option<i32>,
+        // None when the order had not been paid
+        #[serde(with = "money::option")]
+        refunded_amount: Option<Decimal>,
+    },
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<OrderEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventBus { sender }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: OrderEvent) {
+        // Err only means there are no subscribers right now
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<OrderEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// Writes every event to stdout, the one subscriber we have for now
+pub fn spawn_logger(bus: &EventBus) {
+    let mut events = bus.subscribe();
+
+    actix_web::rt::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => match serde_json::to_string(&event) {
+                    Ok(json) => println!("📣 {}", json),
+                    Err(e) => eprintln!("❌ Failed to serialize event {:?}: {}", event, e),
+                },
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("⚠️ Event logger skipped {} events", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
@@ -0,0 +1,285 @@
+// Idempotency-Key support for mutating requests.
+//
+// A POST/PUT/PATCH/DELETE carrying `Idempotency-Key` is run once per caller and
+// key. The first response is stored with a fingerprint of the request (method,
+// path, body) for IDEMPOTENCY_TTL_HOURS:
+//
+//   same key, same request        stored response again, `Idempotent-Replayed: true`
+//   same key, different request   422 idempotency_key_reused
+//   same key, first still running 409 idempotency_key_in_progress
+//
+// 5xx responses are not stored, the client may retry them with the same key.
+// Keys are scoped to the token subject (guests: their cart token), so one caller
+// can never replay another caller's response. Callers with neither, and the
+// endpoints that hand out credentials, are passed through: nothing is stored.
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpResponse};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use sqlx::types::Json;
+use sqlx::{Pool, Postgres};
+
+use crate::AppState;
+use crate::auth::TokenSubject;
+use crate::cart::CART_TOKEN_HEADER;
+use crate::error::ApiError;
+use crate::oauth;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+// Their responses carry tokens, which must not sit in idempotency_keys
+const CREDENTIAL_PATHS: &[&str] = &["/api/login_check"];
+// Bodies are buffered to fingerprint them; the largest legitimate one is an image upload
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+// A first request that hasn't finished after this long is assumed dead
+const IN_PROGRESS_TIMEOUT_SECONDS: i64 = 300;
+
+// IDEMPOTENCY_TTL_HOURS (default 24): how long a key and its response are kept
+#[derive(Debug, Clone)]
+pub struct IdempotencyConfig {
+    pub ttl: chrono::Duration,
+}
+
+impl IdempotencyConfig {
+    pub fn from_env() -> Self {
+        let hours = std::env::var("IDEMPOTENCY_TTL_HOURS")
+            .ok()
+            .map(|v| v.parse::<i64>().expect("IDEMPOTENCY_TTL_HOURS must be a number"))
+            .unwrap_or(24);
+
+        IdempotencyConfig {
+            ttl: chrono::Duration::hours(hours),
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredResponse {
+    request_hash: String,
+    status_code: Option<i16>,
+    response_headers: Option<Json<Vec<(String, String)>>>,
+    response_body: Option<Vec<u8>>,
+}
+
+fn is_mutating(method: &Method) -> bool {
+    matches!(*method, Method::POST | Method::PUT | Method::PATCH | Method::DELETE)
+}
+
+// None for anonymous callers, there is nothing to scope their keys to
+fn owner(req: &ServiceRequest) -> Option<String> {
+    if let Some(subject) = req.extensions().get::<TokenSubject>() {
+        return Some(subject.0.clone());
+    }
+    req.headers()
+        .get(CART_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|token| format!("guest:{}", oauth::sha256_hex(token)))
+}
+
+// Takes the whole body off the request; it has to be put back with set_payload
+async fn read_body(req: &mut ServiceRequest) -> Result<web::Bytes, actix_web::Error> {
+    let mut payload = req.take_payload();
+    let mut body = web::BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(ApiError::PayloadTooLarge("Request body is too large".to_string()).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+fn fingerprint(req: &ServiceRequest, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(b"\n");
+    hasher.update(req.uri().to_string());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+// Registers the key as in progress; returns the stored row if it is already taken.
+// Expired keys and abandoned first requests are taken over
+async fn claim(
+    db: &Pool<Postgres>,
+    owner: &str,
+    key: &str,
+    request_hash: &str,
+    ttl: chrono::Duration,
+) -> Result<Option<StoredResponse>, ApiError> {
+    let claimed = sqlx::query(
+        "INSERT INTO idempotency_keys (owner, idempotency_key, request_hash, expires_at) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (owner, idempotency_key) DO UPDATE
+        SET request_hash = EXCLUDED.request_hash, status_code = NULL, response_headers = NULL, response_body = NULL,
+            created_at = CURRENT_TIMESTAMP, expires_at = EXCLUDED.expires_at
+        WHERE idempotency_keys.expires_at <= CURRENT_TIMESTAMP
+           OR (idempotency_keys.status_code IS NULL AND idempotency_keys.created_at < CURRENT_TIMESTAMP - make_interval(secs => $5))"
+    )
+        .bind(owner)
+        .bind(key)
+        .bind(request_hash)
+        .bind(chrono::Utc::now() + ttl)
+        .bind(IN_PROGRESS_TIMEOUT_SECONDS as f64)
+        .execute(db)
+        .await?
+        .rows_affected();
+
+    if claimed == 1 {
+        return Ok(None);
+    }
+
+    let stored = sqlx::query_as::<_, StoredResponse>(
+        "SELECT request_hash, status_code, response_headers, response_body FROM idempotency_keys WHERE owner = $1 AND idempotency_key = $2"
+    )
+        .bind(owner)
+        .bind(key)
+        .fetch_one(db)
+        .await?;
+
+    Ok(Some(stored))
+}
+
+// Frees the key after a failure so the client can retry with it
+async fn release(db: &Pool<Postgres>, owner: &str, key: &str) {
+    let result = sqlx::query("DELETE FROM idempotency_keys WHERE owner = $1 AND idempotency_key = $2")
+        .bind(owner)
+        .bind(key)
+        .execute(db)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("❌ Failed to release idempotency key {}: {}", key, e);
+    }
+}
+
+fn replay(stored: StoredResponse, request_hash: &str) -> Result<HttpResponse, ApiError> {
+    if stored.request_hash != request_hash {
+        return Err(ApiError::Unprocessable {
+            code: "idempotency_key_reused",
+            detail: "This Idempotency-Key was used for a different request".to_string(),
+        });
+    }
+
+    let Some(status_code) = stored.status_code else {
+        return Err(ApiError::Conflict {
+            code: "idempotency_key_in_progress",
+            detail: "A request with this Idempotency-Key is still being processed".to_string(),
+        });
+    };
+
+    let status = u16::try_from(status_code)
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| ApiError::Internal(format!("Stored idempotent response has status {}", status_code)))?;
+
+    let mut response = HttpResponse::build(status);
+    for (name, value) in stored.response_headers.map(|h| h.0).unwrap_or_default() {
+        response.append_header((name, value));
+    }
+    response.insert_header((REPLAYED_HEADER, "true"));
+
+    Ok(response.body(stored.response_body.unwrap_or_default()))
+}
+
+// Middleware on /api, inside `authenticate` so the caller is known
+pub async fn idempotency(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) if is_mutating(req.method()) => key
+            .to_str()
+            .ok()
+            .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LENGTH)
+            .map(str::to_string)
+            .ok_or_else(|| {
+                ApiError::BadRequest(format!(
+                    "{} must be 1 to {} visible ASCII characters",
+                    IDEMPOTENCY_KEY_HEADER, MAX_KEY_LENGTH
+                ))
+            })?,
+        _ => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+
+    let owner = match owner(&req) {
+        Some(owner) if !CREDENTIAL_PATHS.contains(&req.path()) => owner,
+        _ => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+    let data = req
+        .app_data::<web::Data<AppState>>()
+        .expect("AppState is registered")
+        .clone();
+
+    let body = read_body(&mut req).await?;
+    let request_hash = fingerprint(&req, &body);
+    req.set_payload(Payload::from(body));
+
+    if let Some(stored) = claim(&data.db, &owner, &key, &request_hash, data.idempotency.ttl).await? {
+        let response = replay(stored, &request_hash)?;
+        return Ok(req.into_response(response));
+    }
+
+    let res = match next.call(req).await {
+        Ok(res) if !res.status().is_server_error() => res,
+        other => {
+            release(&data.db, &owner, &key).await;
+            return other.map(ServiceResponse::map_into_boxed_body);
+        }
+    };
+
+    let (req, res) = res.into_parts();
+    let (res, response_body) = res.into_parts();
+    let response_body = match body::to_bytes(response_body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            release(&data.db, &owner, &key).await;
+            let e: Box<dyn std::error::Error> = e.into();
+            return Err(ApiError::Internal(format!("Failed to read response body: {}", e)).into());
+        }
+    };
+
+    let headers: Vec<(String, String)> = res
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+
+    sqlx::query(
+        "UPDATE idempotency_keys SET status_code = $3, response_headers = $4, response_body = $5 WHERE owner = $1 AND idempotency_key = $2"
+    )
+        .bind(&owner)
+        .bind(&key)
+        .bind(res.status().as_u16() as i16)
+        .bind(Json(&headers))
+        .bind(response_body.as_ref())
+        .execute(&data.db)
+        .await
+        .map_err(ApiError::from)?;
+
+    let res = res.set_body(response_body);
+    Ok(ServiceResponse::new(req, res).map_into_boxed_body())
+}
+
+// Hourly cleanup of expired keys
+pub fn spawn_purge(db: Pool<Postgres>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let result = sqlx::query("DELETE FROM idempotency_keys WHERE expires_at <= CURRENT_TIMESTAMP")
+                .execute(&db)
+                .await;
+            if let Err(e) = result {
+                eprintln!("❌ Failed to purge idempotency keys: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,503 @@
+// Inventory: stock ledger and reservations.
+//
+// products.stock_quantity is the quantity on hand. It only changes through
+// `record_movement`, which writes every change to `stock_movements`.
+// Checkout doesn't take stock, it reserves it for the pending order until
+// RESERVATION_TTL_MINUTES pass; the order status drives the rest:
+//
+//   pending -> processing   reservations become a sale (stock leaves)
+//   any     -> cancelled    reservations are released, sold stock comes back
+//
+// available = on hand - unexpired active reservations. Products are locked
+// (FOR UPDATE, in id order) before availability is checked, so concurrent
+// checkouts can't oversell.
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::pg_enum::impl_pg_string_enum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MovementType {
+    // goods received
+    Receipt,
+    // order moved to processing
+    Sale,
+    // sold stock returned by a cancelled order
+    Cancellation,
+    // stocktaking, damage, manual corrections
+    Adjustment,
+}
+
+impl MovementType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MovementType::Receipt => "receipt",
+            MovementType::Sale => "sale",
+            MovementType::Cancellation => "cancellation",
+            MovementType::Adjustment => "adjustment",
+        }
+    }
+}
+
+impl FromStr for MovementType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "receipt" => Ok(MovementType::Receipt),
+            "sale" => Ok(MovementType::Sale),
+            "cancellation" => Ok(MovementType::Cancellation),
+            "adjustment" => Ok(MovementType::Adjustment),
+            other => Err(format!("unknown movement type: {}", other)),
+        }
+    }
+}
+
+// Stored as VARCHAR(20)
+impl_pg_string_enum!(MovementType);
+
+// RESERVATION_TTL_MINUTES (default 30): how long checkout holds stock for an unpaid order
+#[derive(Debug, Clone)]
+pub struct InventoryConfig {
+    pub reservation_ttl: chrono::Duration,
+}
+
+impl InventoryConfig {
+    pub fn from_env() -> Self {
+        let minutes = std::env::var("RESERVATION_TTL_MINUTES")
+            .ok()
+            .map(|v| v.parse::<i64>().expect("RESERVATION_TTL_MINUTES must be a number"))
+            .unwrap_or(30);
+
+        InventoryConfig {
+            reservation_ttl: chrono::Duration::minutes(minutes),
+        }
+    }
+}
+
+// Data models
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct StockMovement {
+    pub movement_id: i32,
+    pub product_id: i32,
+    pub movement_type: MovementType,
+    // signed change of the quantity on hand
+    pub quantity: i32,
+    pub balance_after: i32,
+    pub order_id: Option<i32>,
+    pub created_by: Option<i32>,
+    pub comment: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Row of the product_stock view
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ProductStock {
+    pub product_id: i32,
+    pub name: String,
+    pub sku: Option<String>,
+    pub on_hand: i32,
+    pub reserved: i32,
+    pub available: i32,
+}
+
+// A stock change to apply
+pub struct NewMovement<'a> {
+    pub product_id: i32,
+    pub movement_type: MovementType,
+    pub quantity: i32,
+    pub order_id: Option<i32>,
+    pub created_by: Option<i32>,
+    pub comment: Option<&'a str>,
+}
+
+// Body of POST /api/products/{id}/stock/movements; sales and cancellations come from orders
+#[derive(Debug, Deserialize)]
+pub struct StockMovementRequest {
+    pub movement_type: MovementType,
+    // positive for receipts, signed for adjustments
+    pub quantity: i32,
+    pub comment: Option<String>,
+}
+
+// GET /api/inventory filters
+#[derive(Debug, Deserialize)]
+pub struct InventoryFilter {
+    // products that need restocking
+    pub max_available: Option<i32>,
+}
+
+const INVENTORY_LIST: ListSpec = ListSpec {
+    table: "product_stock",
+    key: "product_id",
+    sort_fields: &[
+        SortField { name: "name", expr: "name", sql_type: "TEXT" },
+        SortField { name: "on_hand", expr: "on_hand", sql_type: "INTEGER" },
+        SortField { name: "reserved", expr: "reserved", sql_type: "INTEGER" },
+        SortField { name: "available", expr: "available", sql_type: "INTEGER" },
+    ],
+    default_sort: "available",
+};
+
+const MOVEMENT_LIST: ListSpec = ListSpec {
+    table: "stock_movements",
+    key: "movement_id",
+    sort_fields: &[
+        SortField { name: "created_at", expr: "created_at", sql_type: "TIMESTAMPTZ" },
+    ],
+    default_sort: "-created_at",
+};
+
+// Locks the products and returns their stock; `exclude_order` leaves that order's
+// own reservations out of `reserved`
+async fn lock_stock(
+    conn: &mut PgConnection,
+    product_ids: &[i32],
+    exclude_order: Option<i32>,
+) -> Result<Vec<ProductStock>, ApiError> {
+    let stock = sqlx::query_as::<_, ProductStock>(
+        "SELECT p.product_id, p.name, p.sku, p.stock_quantity AS on_hand, r.reserved, p.stock_quantity - r.reserved AS available
+        FROM products p
+        CROSS JOIN LATERAL (
+            SELECT COALESCE(SUM(s.quantity), 0)::INTEGER AS reserved
+            FROM stock_reservations s
+            WHERE s.product_id = p.product_id AND s.status = 'active' AND s.expires_at > CURRENT_TIMESTAMP
+              AND s.order_id IS DISTINCT FROM $2
+        ) r
+        WHERE p.product_id = ANY($1)
+        ORDER BY p.product_id
+        FOR UPDATE OF p"
+    )
+        .bind(product_ids)
+        .bind(exclude_order)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    Ok(stock)
+}
+
+// 409 unless every product has `quantity` available; `stock` comes from lock_stock
+fn ensure_available(stock: &[ProductStock], quantities: &BTreeMap<i32, i32>) -> Result<(), ApiError> {
+    for (product_id, quantity) in quantities {
+        let product = stock
+            .iter()
+            .find(|s| s.product_id == *product_id)
+            .ok_or(ApiError::NotFound("Product"))?;
+
+        if product.available < *quantity {
+            return Err(ApiError::Conflict {
+                code: "insufficient_stock",
+                detail: format!("Only {} of {} available", product.available.max(0), product.name),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Applies the change to products.stock_quantity and writes it to the ledger.
+// Going below zero (products.stock_quantity CHECK) or past the INTEGER range is a 422
+pub async fn record_movement(
+    conn: &mut PgConnection,
+    movement: &NewMovement<'_>,
+) -> Result<StockMovement, ApiError> {
+    let balance = sqlx::query_scalar::<_, i32>(
+        "UPDATE products SET stock_quantity = stock_quantity + $2 WHERE product_id = $1 RETURNING stock_quantity"
+    )
+        .bind(movement.product_id)
+        .bind(movement.quantity)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    log_movement(conn, movement, balance).await
+}
+
+// Writes a change that has already been applied to products.stock_quantity
+pub async fn log_movement(
+    conn: &mut PgConnection,
+    movement: &NewMovement<'_>,
+    balance_after: i32,
+) -> Result<StockMovement, ApiError> {
+    let logged = sqlx::query_as::<_, StockMovement>(
+        "INSERT INTO stock_movements (product_id, movement_type, quantity, balance_after, order_id, created_by, comment) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *"
+    )
+        .bind(movement.product_id)
+        .bind(movement.movement_type)
+        .bind(movement.quantity)
+        .bind(balance_after)
+        .bind(movement.order_id)
+        .bind(movement.created_by)
+        .bind(movement.comment)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(logged)
+}
+
+// Sets the quantity on hand (PUT / PATCH of a product), recording the difference as an adjustment
+pub async fn set_on_hand(
+    conn: &mut PgConnection,
+    product_id: i32,
+    stock_quantity: i32,
+    created_by: Option<i32>,
+) -> Result<(), ApiError> {
+    let current = sqlx::query_scalar::<_, i32>(
+        "SELECT stock_quantity FROM products WHERE product_id = $1 AND deleted_at IS NULL FOR UPDATE"
+    )
+        .bind(product_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    if current != stock_quantity {
+        record_movement(conn, &NewMovement {
+            product_id,
+            movement_type: MovementType::Adjustment,
+            quantity: stock_quantity - current,
+            order_id: None,
+            created_by,
+            comment: Some("Product update"),
+        })
+            .await?;
+    }
+    Ok(())
+}
+
+// Holds stock for a new order (checkout); 409 if any product runs short
+pub async fn reserve(
+    conn: &mut PgConnection,
+    order_id: i32,
+    quantities: &BTreeMap<i32, i32>,
+    ttl: chrono::Duration,
+) -> Result<(), ApiError> {
+    let product_ids: Vec<i32> = quantities.keys().copied().collect();
+    let stock = lock_stock(conn, &product_ids, None).await?;
+    ensure_available(&stock, quantities)?;
+
+    let line_quantities: Vec<i32> = quantities.values().copied().collect();
+    sqlx::query(
+        "INSERT INTO stock_reservations (order_id, product_id, quantity, expires_at) SELECT $1, l.product_id, l.quantity, $4 FROM UNNEST($2::INT[], $3::INT[]) AS l(product_id, quantity)"
+    )
+        .bind(order_id)
+        .bind(&product_ids)
+        .bind(&line_quantities)
+        .bind(chrono::Utc::now() + ttl)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+// Line of a pending order changed: its reservation is replaced by one for
+// `quantity` (none for 0) and the hold starts over
+pub async fn reserve_line(
+    conn: &mut PgConnection,
+    order_id: i32,
+    product_id: i32,
+    quantity: i32,
+    ttl: chrono::Duration,
+) -> Result<(), ApiError> {
+    // Released first so the order's old hold doesn't count against the new one;
+    // a failed check below rolls the release back with the rest of the transaction
+    sqlx::query("UPDATE stock_reservations SET status = 'released' WHERE order_id = $1 AND product_id = $2 AND status = 'active'")
+        .bind(order_id)
+        .bind(product_id)
+        .execute(&mut *conn)
+        .await?;
+
+    if quantity > 0 {
+        reserve(conn, order_id, &BTreeMap::from([(product_id, quantity)]), ttl).await?;
+    }
+
+    Ok(())
+}
+
+// Order moved to processing: its items leave the warehouse. The order's own
+// reservations don't count against it, an expired one just has to be covered by
+// what is still available
+pub async fn sell_order(
+    conn: &mut PgConnection,
+    order_id: i32,
+    created_by: Option<i32>,
+) -> Result<(), ApiError> {
+    let quantities: BTreeMap<i32, i32> = sqlx::query_as::<_, (i32, i32)>(
+        "SELECT product_id, quantity FROM order_items WHERE order_id = $1"
+    )
+        .bind(order_id)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .collect();
+
+    let product_ids: Vec<i32> = quantities.keys().copied().collect();
+    let stock = lock_stock(conn, &product_ids, Some(order_id)).await?;
+    ensure_available(&stock, &quantities)?;
+
+    for (product_id, quantity) in &quantities {
+        record_movement(conn, &NewMovement {
+            product_id: *product_id,
+            movement_type: MovementType::Sale,
+            quantity: -quantity,
+            order_id: Some(order_id),
+            created_by,
+            comment: None,
+        })
+            .await?;
+    }
+
+    sqlx::query("UPDATE stock_reservations SET status = 'converted' WHERE order_id = $1 AND status = 'active'")
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+// Order cancelled: drop its reservations and return whatever was sold
+pub async fn return_order(
+    conn: &mut PgConnection,
+    order_id: i32,
+    created_by: Option<i32>,
+) -> Result<(), ApiError> {
+    sqlx::query("UPDATE stock_reservations SET status = 'released' WHERE order_id = $1 AND status = 'active'")
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+    // Net quantity that left the warehouse for this order
+    let sold = sqlx::query_as::<_, (i32, i32)>(
+        "SELECT product_id, -SUM(quantity)::INTEGER FROM stock_movements WHERE order_id = $1 GROUP BY product_id HAVING SUM(quantity) < 0 ORDER BY product_id"
+    )
+        .bind(order_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let product_ids: Vec<i32> = sold.iter().map(|(product_id, _)| *product_id).collect();
+    lock_stock(conn, &product_ids, None).await?;
+
+    for (product_id, quantity) in sold {
+        record_movement(conn, &NewMovement {
+            product_id,
+            movement_type: MovementType::Cancellation,
+            quantity,
+            order_id: Some(order_id),
+            created_by,
+            comment: None,
+        })
+            .await?;
+    }
+
+    Ok(())
+}
+
+// Endpoint Callbacks
+// On hand / reserved / available for every product (staff only)
+// curl "http://localhost:8080/api/inventory?max_available=5&sort=available" -H "Authorization: Bearer $TOKEN"
+pub async fn get_inventory(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    filter: web::Query<InventoryFilter>,
+) -> Result<HttpResponse, ApiError> {
+    pagination::list::<ProductStock>(&data.db, &req, &INVENTORY_LIST, &page, |q| {
+        if let Some(max_available) = filter.max_available {
+            q.push(" AND available <= ").push_bind(max_available);
+        }
+    })
+        .await
+}
+
+// Stock of one product (staff only)
+// curl http://localhost:8080/api/products/1/stock -H "Authorization: Bearer $TOKEN"
+pub async fn get_product_stock(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let stock = sqlx::query_as::<_, ProductStock>("SELECT * FROM product_stock WHERE product_id = $1")
+        .bind(path.into_inner())
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    Ok(HttpResponse::Ok().json(stock))
+}
+
+// Ledger of one product, newest first (staff only)
+// curl "http://localhost:8080/api/products/1/stock/movements?limit=50" -H "Authorization: Bearer $TOKEN"
+pub async fn get_stock_movements(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    page: web::Query<PageParams>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+
+    pagination::list::<StockMovement>(&data.db, &req, &MOVEMENT_LIST, &page, |q| {
+        q.push(" AND product_id = ").push_bind(product_id);
+    })
+        .await
+}
+
+// Receipt or manual adjustment (staff only)
+// curl -X POST http://localhost:8080/api/products/1/stock/movements \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"movement_type": "receipt", "quantity": 50, "comment": "Invoice 2231"}'
+pub async fn create_stock_movement(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    movement_req: web::Json<StockMovementRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+
+    match movement_req.movement_type {
+        MovementType::Receipt if movement_req.quantity <= 0 => {
+            return Err(ApiError::validation("quantity of a receipt must be positive"));
+        }
+        MovementType::Adjustment if movement_req.quantity == 0 => {
+            return Err(ApiError::validation("quantity of an adjustment must not be zero"));
+        }
+        MovementType::Sale | MovementType::Cancellation => {
+            return Err(ApiError::validation(
+                "sale and cancellation movements are created by orders",
+            ));
+        }
+        _ => {}
+    }
+
+    let mut tx = data.db.begin().await?;
+
+    let exists = sqlx::query_scalar::<_, i32>(
+        "SELECT product_id FROM products WHERE product_id = $1 AND deleted_at IS NULL FOR UPDATE"
+    )
+        .bind(product_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if exists.is_none() {
+        return Err(ApiError::NotFound("Product"));
+    }
+
+    // Taking more than is on hand violates the stock_quantity CHECK -> 422
+    let movement = record_movement(&mut tx, &NewMovement {
+        product_id,
+        movement_type: movement_req.movement_type,
+        quantity: movement_req.quantity,
+        order_id: None,
+        created_by: auth.map(|a| a.user_id),
+        comment: movement_req.comment.as_deref(),
+    })
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(movement))
+}
@@ -1,18 +1,43 @@
 //https://github.com/ladovod444/r-rest-api-orders
 
 // src/main.rs
-use actix_web::{web, App, HttpResponse, HttpServer, Result};
+use actix_web::{middleware, web, App, HttpResponse, HttpServer, Result};
 // use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use uuid::Uuid;
 
 
 use std::env;
+use std::sync::Arc;
 
+mod auth;
+mod cancellation;
+mod cart;
+mod category;
+mod checkout;
+mod error;
+mod events;
+mod idempotency;
+mod inventory;
+mod migrations;
+mod money;
+mod oauth;
 mod user;
 mod product;
+mod product_image;
+mod promotion;
 mod order;
 mod order_items;
+mod order_number;
+mod order_status;
+mod pagination;
+mod password;
+mod payment;
+mod payment_provider;
+mod pg_enum;
+mod pricing;
+mod search;
+mod storage;
+mod tax;
 // pub use user::User;
 // pub use user::CreateUserRequest;
 // pub use user::UpdateUserRequest;
@@ -32,23 +57,50 @@ use product::create_product;
 use product::get_products;
 
 use order::*;
-use crate::order_items::{create_order_item, get_order_items};
+use crate::order_items::{create_order_item, delete_order_item, get_order_items, update_order_item};
+use crate::payment_provider::PaymentProvider;
+
+// Route-level scope check for OAuth2 tokens, see auth::require_scope
+macro_rules! require_scope {
+    ($scope:literal) => {
+        middleware::from_fn(|req, next| auth::require_scope($scope, req, next))
+    };
+}
+
+// Route-level role check for user tokens, see auth::require_role
+macro_rules! require_role {
+    ($role:ident) => {
+        middleware::from_fn(|req, next| auth::require_role(user::Role::$role, req, next))
+    };
+}
 
 // App state
 struct AppState {
     db: Pool<Postgres>,
+    payments: Arc<dyn PaymentProvider>,
+    password_policy: password::PasswordPolicy,
+    jwt: auth::JwtConfig,
+    search_language: search::SearchLanguage,
+    storage: Arc<dyn storage::Storage>,
+    uploads: product_image::UploadConfig,
+    inventory: inventory::InventoryConfig,
+    order_numbers: order_number::OrderNumberConfig,
+    events: events::EventBus,
+    idempotency: idempotency::IdempotencyConfig,
+    tax: tax::TaxConfig,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Load environment variables
     dotenvy::dotenv().ok();
+    money::init_from_env();
 
     // let default_value = "default_val".to_string();
     // let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| default_value);
 
     let default_value = "DATABASE_URL=postgres://db:db@localhost:5432/shop-rust".to_string();
-    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| default_value);
+    let database_url = env::var("DATABASE_URL").unwrap_or(default_value);
     //     .expect("DATABASE_URL must be set in .env file");
 
     // let mut database_url
@@ -66,93 +118,66 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to create pool");
 
-    // Run migrations
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS users (
-            user_id SERIAL PRIMARY KEY,
-            username VARCHAR(50) UNIQUE NOT NULL,
-            email VARCHAR(100) UNIQUE NOT NULL,
-            password_hash VARCHAR(255) NOT NULL,
-            first_name VARCHAR(50),
-            last_name VARCHAR(50),
-            phone VARCHAR(20),
-            address TEXT,
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            is_active BOOLEAN DEFAULT TRUE
-        );
-        "#
-    )
-        .execute(&pool)
-        .await
-        .expect("Failed to create users table");
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS products (
-            product_id SERIAL PRIMARY KEY,
-            name VARCHAR(100) NOT NULL,
-            description TEXT,
-            sku VARCHAR(50) UNIQUE,
-            price DECIMAL(10, 2) NOT NULL CHECK (price >= 0),
-            stock_quantity INTEGER NOT NULL DEFAULT 0 CHECK (stock_quantity >= 0),
-            category VARCHAR(50),
-            image_url VARCHAR(255),
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            is_available BOOLEAN DEFAULT TRUE
-        );
-        "#
-    )
-        .execute(&pool)
-        .await
-        .expect("Failed to create products table");
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS orders (
-            order_id SERIAL PRIMARY KEY,
-            user_id INTEGER NOT NULL,
-            order_number VARCHAR(50) UNIQUE NOT NULL,
-            order_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            total_amount DECIMAL(10, 2) NOT NULL CHECK (total_amount >= 0),
-            status VARCHAR(20) DEFAULT 'pending'
-                CHECK (status IN ('pending', 'processing', 'shipped', 'delivered', 'cancelled')),
-            shipping_address TEXT NOT NULL,
-            billing_address TEXT,
-            payment_method VARCHAR(30),
-            payment_status VARCHAR(20) DEFAULT 'unpaid'
-                CHECK (payment_status IN ('unpaid', 'paid', 'refunded', 'failed')),
-            notes TEXT,
-            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
-        );
-        "#
-    )
-        .execute(&pool)
-        .await
-        .expect("Failed to create orders table");
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS order_items (
-            order_item_id SERIAL PRIMARY KEY,
-            order_id INTEGER NOT NULL,
-            product_id INTEGER NOT NULL,
-            quantity INTEGER NOT NULL CHECK (quantity > 0),
-            unit_price DECIMAL(10, 2) NOT NULL CHECK (unit_price >= 0),
-            subtotal DECIMAL(10, 2) GENERATED ALWAYS AS (quantity * unit_price) STORED,
-            FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE CASCADE,
-            FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE RESTRICT,
-            UNIQUE (order_id, product_id) -- предотвращает дублирование одного продукта в заказе
-        );
-        "#
-    )
-        .execute(&pool)
-        .await
-        .expect("Failed to create order_items table");
+    // Migrations: `rest-api-orders migrate [up|down <version>|status]`
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        return run_migrate_command(&pool, &args[2..]).await;
+    }
+    // OAuth2 clients: `rest-api-orders oauth create-client <client_id> --scopes ...`
+    if args.get(1).map(String::as_str) == Some("oauth") {
+        return oauth::run_cli(&pool, &args[2..]).await;
+    }
+    // Roles: `rest-api-orders user set-role <email> <role>`
+    if args.get(1).map(String::as_str) == Some("user") {
+        return user::run_cli(&pool, &args[2..]).await;
+    }
+
+    // AUTO_MIGRATE=true applies pending migrations on boot (handy for local development)
+    let auto_migrate = env::var("AUTO_MIGRATE").map(|v| v == "true" || v == "1").unwrap_or(false);
+    if auto_migrate {
+        migrations::run(&pool)
+            .await
+            .expect("Failed to apply migrations");
+    }
+
+    // Refuse to serve if the schema doesn't match this build
+    if let Err(e) = migrations::ensure_up_to_date(&pool).await {
+        eprintln!("❌ Database schema check failed: {}", e);
+        return Err(std::io::Error::other(e));
+    }
+
+    // Local storage is served by this process under UPLOAD_BASE_URL
+    let storage = storage::from_env();
+    let static_files = storage
+        .local_root()
+        .map(|(url, dir)| (url.to_string(), dir.to_path_buf()));
+    if let Some((_, dir)) = &static_files {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let payments = payment_provider::from_env();
 
-    let app_state = web::Data::new(AppState { db: pool });
+    let events = events::EventBus::default();
+    events::spawn_logger(&events);
+    idempotency::spawn_purge(pool.clone());
+    pricing::spawn_scheduler(pool.clone());
+    cancellation::spawn_refund_retry(pool.clone(), payments.clone());
+    payment::spawn_refund_settlement(pool.clone(), payments.clone());
+
+    let app_state = web::Data::new(AppState {
+        db: pool,
+        payments,
+        password_policy: password::PasswordPolicy::from_env(),
+        jwt: auth::JwtConfig::from_env(),
+        search_language: search::SearchLanguage::from_env(),
+        storage,
+        uploads: product_image::UploadConfig::from_env(),
+        inventory: inventory::InventoryConfig::from_env(),
+        order_numbers: order_number::OrderNumberConfig::from_env(),
+        events,
+        idempotency: idempotency::IdempotencyConfig::from_env(),
+        tax: tax::TaxConfig::from_env(),
+    });
 
     println!("🚀 Server running at http://localhost:8080");
     println!("📊 Database connected: {}", database_url);
@@ -160,26 +185,113 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            // Extractor errors are rendered as problem+json too
+            .app_data(web::JsonConfig::default().error_handler(error::json_error_handler))
+            .app_data(web::PathConfig::default().error_handler(error::path_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(error::query_error_handler))
+            // OAuth2 token endpoint, RFC 6749 errors instead of problem+json
+            .route("/oauth/token", web::post().to(oauth::token))
+            // Uploaded product images: /uploads/images/products/{id}/...
+            .configure(|cfg| {
+                if let Some((url, dir)) = &static_files {
+                    cfg.service(actix_files::Files::new(url, dir));
+                }
+            })
             .service(
                 web::scope("/api")
+                    // Idempotency-Key on POST/PUT/PATCH/DELETE, needs the caller from authenticate
+                    .wrap(middleware::from_fn(idempotency::idempotency))
+                    // Bearer token -> AuthenticatedUser
+                    .wrap(middleware::from_fn(auth::authenticate))
                     .route("/health", web::get().to(health_check))
-                    .route("/users", web::get().to(get_users))
-                    .route("/users", web::post().to(create_user))
-                    .route("/users/{id}", web::get().to(get_user))
-                    .route("/users/{id}", web::put().to(update_user))
-                    .route("/users/{id}", web::delete().to(delete_user))
-
+                    // React frontend: REACT_APP_SHOP_JWT_TOKEN_URL
+                    .route("/login_check", web::post().to(auth::login_check))
+                    .route("/users", web::get().to(get_users).wrap(require_role!(Staff)).wrap(require_scope!("users:read")))
+                    .route("/users", web::post().to(create_user).wrap(require_role!(Admin)).wrap(require_scope!("users:write")))
+                    .route("/users/register", web::post().to(register_user))
+                    // React frontend: REACT_APP_SHOP_API_USER_REGISTER
+                    .route("/user/dto", web::post().to(register_user))
+                    .route("/users/me", web::get().to(auth::get_me).wrap(require_scope!("users:read")))
+                    .route("/users/{id}", web::get().to(get_user).wrap(require_scope!("users:read")))
+                    .route("/users/{id}", web::put().to(update_user).wrap(require_scope!("users:write")))
+                    .route("/users/{id}", web::delete().to(delete_user).wrap(require_role!(Staff)).wrap(require_scope!("users:write")))
+                    .route("/users/{id}/orders", web::get().to(get_user_orders).wrap(require_scope!("orders:read")))
+                    .route("/users/{id}/role", web::put().to(update_user_role).wrap(require_role!(Admin)).wrap(require_scope!("users:write")))
 
-                    // TODO доделать остальные методы
 
-                    .route("/products", web::post().to(create_product))
+                    .route("/products", web::post().to(create_product).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
                     .route("/products", web::get().to(get_products))
+                    .route("/products/search", web::get().to(search::search_products))
+                    .route("/products/{id}", web::get().to(product::get_product))
+                    .route("/products/{id}", web::put().to(product::update_product).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}", web::patch().to(product::patch_product).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}", web::delete().to(product::delete_product).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}/restore", web::post().to(product::restore_product).wrap(require_role!(Admin)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}/sale", web::put().to(pricing::set_sale).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}/sale", web::delete().to(pricing::delete_sale).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}/price", web::get().to(pricing::get_price_at).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/products/{id}/price-history", web::get().to(pricing::get_price_history).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/products/{id}/stock", web::get().to(inventory::get_product_stock).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/products/{id}/stock/movements", web::get().to(inventory::get_stock_movements).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/products/{id}/stock/movements", web::post().to(inventory::create_stock_movement).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/inventory", web::get().to(inventory::get_inventory).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/products/{id}/images", web::get().to(product_image::get_product_images))
+                    .route("/products/{id}/images", web::post().to(product_image::upload_product_images).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}/images/order", web::put().to(product_image::reorder_product_images).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/products/{id}/images/{image_id}", web::delete().to(product_image::delete_product_image).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+
+                    .route("/categories", web::get().to(category::get_categories))
+                    .route("/categories", web::post().to(category::create_category).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/categories/tree", web::get().to(category::get_category_tree))
+                    .route("/categories/{id}", web::get().to(category::get_category))
+                    .route("/categories/{id}", web::put().to(category::update_category).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/categories/{id}", web::delete().to(category::delete_category).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/categories/{id}/products", web::get().to(category::get_category_products))
+                    // React frontend: /category/{id}
+                    .route("/category/{id}", web::get().to(category::get_category))
 
-                    .route("/orders", web::post().to(create_order))
-                    .route("/orders", web::get().to(get_orders))
+                    // Guests (X-Cart-Token) and signed-in users, see cart.rs
+                    .route("/cart", web::get().to(cart::get_cart))
+                    .route("/cart", web::delete().to(cart::clear_cart))
+                    .route("/cart/items", web::post().to(cart::add_cart_item))
+                    .route("/cart/items/{product_id}", web::put().to(cart::update_cart_item))
+                    .route("/cart/items/{product_id}", web::delete().to(cart::delete_cart_item))
+                    .route("/cart/checkout", web::post().to(cart::checkout_cart).wrap(require_scope!("orders:write")))
 
-                    .route("/order-items", web::post().to(create_order_item))
-                    .route("/order-items", web::get().to(get_order_items)),
+                    .route("/promotions", web::get().to(promotion::get_promotions).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/promotions", web::post().to(promotion::create_promotion).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/promotions/preview", web::post().to(promotion::preview_discounts).wrap(require_scope!("orders:read")))
+                    .route("/promotions/{id}", web::get().to(promotion::get_promotion).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/promotions/{id}", web::put().to(promotion::update_promotion).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+                    .route("/promotions/{id}", web::delete().to(promotion::delete_promotion).wrap(require_role!(Staff)).wrap(require_scope!("products:write")))
+
+                    .route("/tax-rates", web::get().to(tax::get_tax_rates).wrap(require_role!(Staff)).wrap(require_scope!("products:read")))
+                    .route("/tax-rates", web::post().to(tax::create_tax_rate).wrap(require_role!(Admin)).wrap(require_scope!("products:write")))
+                    .route("/tax-rates/{id}", web::put().to(tax::update_tax_rate).wrap(require_role!(Admin)).wrap(require_scope!("products:write")))
+                    .route("/tax-rates/{id}", web::delete().to(tax::delete_tax_rate).wrap(require_role!(Admin)).wrap(require_scope!("products:write")))
+                    .route("/reports/tax", web::get().to(tax::get_tax_report).wrap(require_role!(Staff)).wrap(require_scope!("orders:read")))
+
+                    .route("/orders/checkout", web::post().to(checkout::checkout).wrap(require_scope!("orders:write")))
+                    .route("/orders", web::post().to(create_order).wrap(require_role!(Staff)).wrap(require_scope!("orders:write")))
+                    .route("/orders", web::get().to(get_orders).wrap(require_scope!("orders:read")))
+                    .route("/orders/number/{order_number}", web::get().to(order_number::get_order_by_number).wrap(require_scope!("orders:read")))
+                    .route("/orders/{id}", web::get().to(get_order).wrap(require_scope!("orders:read")))
+                    .route("/orders/{id}/transitions", web::post().to(order_status::transition_order).wrap(require_role!(Staff)).wrap(require_scope!("orders:write")))
+                    .route("/orders/{id}/cancel", web::post().to(cancellation::cancel_order).wrap(require_scope!("orders:write")))
+                    .route("/orders/{id}/history", web::get().to(order_status::get_order_history).wrap(require_scope!("orders:read")))
+                    .route("/orders/{id}/payments", web::post().to(payment::initiate_payment).wrap(require_scope!("orders:write")))
+                    .route("/orders/{id}/payments", web::get().to(payment::get_order_payments).wrap(require_scope!("orders:read")))
+                    .route("/orders/{id}/refunds", web::post().to(payment::refund_payment).wrap(require_role!(Staff)).wrap(require_scope!("orders:write")))
+                    .route("/payments/webhook", web::post().to(payment::payment_webhook))
+                    // React frontend: REACT_APP_SHOP_API_CHANGE_ORDER_STATE
+                    .route("/order/change-state/{id}", web::post().to(order_status::transition_order).wrap(require_role!(Staff)).wrap(require_scope!("orders:write")))
+                    // React frontend: /order/user/{id}
+                    .route("/order/user/{id}", web::get().to(get_user_orders).wrap(require_scope!("orders:read")))
+
+                    .route("/order-items", web::post().to(create_order_item).wrap(require_role!(Staff)).wrap(require_scope!("orders:write")))
+                    .route("/order-items", web::get().to(get_order_items).wrap(require_role!(Staff)).wrap(require_scope!("orders:read")))
+                    .route("/order-items/{id}", web::patch().to(update_order_item).wrap(require_role!(Staff)).wrap(require_scope!("orders:write")))
+                    .route("/order-items/{id}", web::delete().to(delete_order_item).wrap(require_role!(Staff)).wrap(require_scope!("orders:write"))),
             )
     })
         .bind("127.0.0.1:8080")?
@@ -196,10 +308,41 @@ async fn health_check(data: web::Data<AppState>) -> Result<HttpResponse> {
             "status": "ok",
             "database": "connected"
         }))),
-        Err(e) => Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
-            "status": "error",
-            "database": "disconnected",
-            "error": e.to_string()
-        }))),
+        Err(e) => {
+            // Raw driver errors stay in the log
+            eprintln!("❌ Health check failed: {}", e);
+            Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                "status": "error",
+                "database": "disconnected"
+            })))
+        }
     }
 }
+
+// CLI for the migration subsystem
+// cargo run -- migrate up
+// cargo run -- migrate down 0     (revert everything above version 0)
+// cargo run -- migrate status
+async fn run_migrate_command(pool: &Pool<Postgres>, args: &[String]) -> std::io::Result<()> {
+    let result = match args.first().map(String::as_str) {
+        Some("up") | None => migrations::run(pool).await.map(|_| println!("✅ Migrations applied")),
+        Some("down") => {
+            let target = args
+                .get(1)
+                .and_then(|v| v.parse::<i64>().ok())
+                .ok_or_else(|| std::io::Error::other("usage: migrate down <target_version>"))?;
+            migrations::undo(pool, target)
+                .await
+                .map(|_| println!("✅ Reverted to version {}", target))
+        }
+        Some("status") => migrations::status(pool).await.map(|list| {
+            for m in list {
+                let mark = if m.applied { "applied" } else { "pending" };
+                println!("{:>6} {:<40} {}", m.version, m.description, mark);
+            }
+        }),
+        Some(other) => return Err(std::io::Error::other(format!("unknown migrate command: {}", other))),
+    };
+
+    result.map_err(std::io::Error::other)
+}
@@ -0,0 +1,121 @@
+// Versioned schema migrations.
+//
+// Scripts live in ./migrations as `<version>_<name>.up.sql` / `<version>_<name>.down.sql`
+// and are embedded into the binary at compile time. Applied versions and their
+// checksums are recorded in the `_sqlx_migrations` history table.
+use std::fmt;
+
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::{Pool, Postgres};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// Why the schema in the database can't be served by this binary
+#[derive(Debug)]
+pub enum SchemaError {
+    Migrate(MigrateError),
+    // A migration failed half way and must be fixed by hand
+    Dirty(i64),
+    // Applied script differs from the one embedded in the binary
+    ChecksumMismatch(i64),
+    // Database has a version this binary doesn't know about (newer build?)
+    Unknown(i64),
+    // Migrations that exist in the binary but are not applied yet
+    Behind(Vec<i64>),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Migrate(e) => write!(f, "migration error: {}", e),
+            SchemaError::Dirty(v) => write!(f, "migration {} is partially applied", v),
+            SchemaError::ChecksumMismatch(v) => {
+                write!(f, "migration {} was modified after it was applied", v)
+            }
+            SchemaError::Unknown(v) => write!(f, "migration {} is applied but unknown to this build", v),
+            SchemaError::Behind(versions) => write!(
+                f,
+                "schema is behind, pending migrations: {:?} (run `rest-api-orders migrate up`)",
+                versions
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl From<MigrateError> for SchemaError {
+    fn from(e: MigrateError) -> Self {
+        SchemaError::Migrate(e)
+    }
+}
+
+// Status of a single migration known to the binary
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
+// Apply all pending migrations
+pub async fn run(pool: &Pool<Postgres>) -> Result<(), MigrateError> {
+    MIGRATOR.run(pool).await
+}
+
+// Revert migrations down to (but not including) `target`
+pub async fn undo(pool: &Pool<Postgres>, target: i64) -> Result<(), MigrateError> {
+    MIGRATOR.undo(pool, target).await
+}
+
+// List every migration of the binary and whether it is applied
+pub async fn status(pool: &Pool<Postgres>) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied = conn.list_applied_migrations().await?;
+
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            applied: applied.iter().any(|a| a.version == m.version),
+        })
+        .collect())
+}
+
+// Startup check: the database must have exactly the migrations of this binary applied
+pub async fn ensure_up_to_date(pool: &Pool<Postgres>) -> Result<(), SchemaError> {
+    let mut conn = pool.acquire().await.map_err(MigrateError::from)?;
+    conn.ensure_migrations_table().await?;
+
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(SchemaError::Dirty(version));
+    }
+
+    let applied = conn.list_applied_migrations().await?;
+    let known: Vec<_> = MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .collect();
+
+    for a in &applied {
+        match known.iter().find(|m| m.version == a.version) {
+            Some(m) if m.checksum != a.checksum => return Err(SchemaError::ChecksumMismatch(a.version)),
+            Some(_) => {}
+            None => return Err(SchemaError::Unknown(a.version)),
+        }
+    }
+
+    let pending: Vec<i64> = known
+        .iter()
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+        .map(|m| m.version)
+        .collect();
+
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(SchemaError::Behind(pending))
+    }
+}
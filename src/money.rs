@@ -0,0 +1,153 @@
+// Money handling.
+//
+// All monetary values are `Decimal` end to end: NUMERIC(10, 2) in Postgres,
+// `rust_decimal::Decimal` in Rust and a string or number in JSON depending on
+// MONEY_JSON_FORMAT (`string` by default, `number` for legacy clients).
+//
+// Use with serde as `#[serde(with = "crate::money")]` or
+// `#[serde(with = "crate::money::option")]` for nullable amounts.
+use std::sync::OnceLock;
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Deserializer, Serializer};
+
+use crate::error::ApiError;
+
+// Two digits after the point, as in NUMERIC(10, 2)
+pub const SCALE: u32 = 2;
+
+// NUMERIC(10, 2) can hold at most 8 digits before the point
+const MAX_INTEGER_DIGITS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoneyFormat {
+    // "1000.00"
+    String,
+    // 1000.00
+    Number,
+}
+
+static FORMAT: OnceLock<MoneyFormat> = OnceLock::new();
+
+// Read MONEY_JSON_FORMAT once at startup
+pub fn init_from_env() {
+    let format = match std::env::var("MONEY_JSON_FORMAT").as_deref() {
+        Ok("number") => MoneyFormat::Number,
+        _ => MoneyFormat::String,
+    };
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> MoneyFormat {
+    *FORMAT.get().unwrap_or(&MoneyFormat::String)
+}
+
+// Commercial rounding to cents: 0.005 -> 0.01, -0.005 -> -0.01
+pub fn round(value: Decimal) -> Decimal {
+    value.round_dp_with_strategy(SCALE, RoundingStrategy::MidpointAwayFromZero)
+}
+
+// Validate a client supplied amount: non-negative, at most 2 decimals, fits NUMERIC(10, 2)
+pub fn validate(value: Decimal, field: &str) -> Result<Decimal, ApiError> {
+    if value.is_sign_negative() && !value.is_zero() {
+        return Err(ApiError::validation(format!("{} must not be negative", field)));
+    }
+    if value.normalize().scale() > SCALE {
+        return Err(ApiError::validation(format!(
+            "{} must have at most {} decimal places",
+            field, SCALE
+        )));
+    }
+    if value.trunc() >= Decimal::from(10u64.pow(MAX_INTEGER_DIGITS)) {
+        return Err(ApiError::validation(format!("{} is too large", field)));
+    }
+    Ok(round(value))
+}
+
+pub fn serialize<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let value = round(*value);
+    match format() {
+        // Always two decimals: "10.50", not "10.5"
+        MoneyFormat::String => serializer.serialize_str(&format!("{:.2}", value)),
+        // NUMERIC(10, 2) has at most 10 significant digits, so f64 prints it exactly
+        MoneyFormat::Number => serializer.serialize_f64(value.to_f64().unwrap_or_default()),
+    }
+}
+
+// Accepts both "10.50" and 10.5
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    <Decimal as Deserialize>::deserialize(deserializer)
+}
+
+pub mod option {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(value: &Option<Decimal>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match value {
+            Some(value) => super::serialize(value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <Option<Decimal> as Deserialize>::deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn validate_accepts_cents() {
+        assert_eq!(validate(dec("10.5"), "price").unwrap(), dec("10.50"));
+        assert_eq!(validate(dec("0"), "price").unwrap(), Decimal::ZERO);
+        assert_eq!(validate(dec("99999999.99"), "price").unwrap(), dec("99999999.99"));
+    }
+
+    #[test]
+    fn validate_allows_trailing_zeros() {
+        assert_eq!(validate(dec("10.5000"), "price").unwrap(), dec("10.50"));
+        assert!(validate(dec("-0.00"), "price").is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_negative_amounts() {
+        assert!(validate(dec("-0.01"), "price").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_fractions_of_a_cent() {
+        assert!(validate(dec("10.005"), "price").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_what_numeric_10_2_cannot_hold() {
+        assert!(validate(dec("100000000"), "price").is_err());
+    }
+
+    #[test]
+    fn round_half_away_from_zero() {
+        assert_eq!(round(dec("0.005")), dec("0.01"));
+        assert_eq!(round(dec("-0.005")), dec("-0.01"));
+        assert_eq!(round(dec("0.004")), dec("0.00"));
+    }
+}
@@ -0,0 +1,446 @@
+// OAuth2 token endpoint (RFC 6749).
+//
+// POST /oauth/token supports the `password`, `client_credentials` and
+// `refresh_token` grants. Clients live in `oauth_clients` with an Argon2 hash of
+// their secret, the grants they may use and the scopes they may request.
+// Access tokens are the same JWTs as login_check issues, plus `scope` and
+// `client_id` claims; refresh tokens are opaque, stored as SHA-256 and rotated
+// on every use.
+use std::fmt;
+
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, Pool, Postgres};
+
+use crate::AppState;
+use crate::auth;
+use crate::error::ApiError;
+use crate::password;
+use crate::user::Role;
+
+// Errors are reported in the RFC 6749 format, not problem+json, because that is
+// what OAuth2 client libraries parse
+#[derive(Debug)]
+pub struct OAuthError {
+    error: &'static str,
+    description: String,
+}
+
+impl OAuthError {
+    fn new(error: &'static str, description: impl Into<String>) -> Self {
+        OAuthError {
+            error,
+            description: description.into(),
+        }
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.description)
+    }
+}
+
+impl ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        match self.error {
+            "invalid_client" => StatusCode::UNAUTHORIZED,
+            "server_error" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.error == "server_error" {
+            eprintln!("❌ oauth: {}", self.description);
+        }
+
+        let description = match self.error {
+            "server_error" => "An unexpected error occurred",
+            _ => self.description.as_str(),
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        response.insert_header((header::CACHE_CONTROL, "no-store"));
+        if self.error == "invalid_client" {
+            response.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"oauth\""));
+        }
+        response.json(serde_json::json!({
+                "error": self.error,
+                "error_description": description,
+            }))
+    }
+}
+
+impl From<sqlx::Error> for OAuthError {
+    fn from(e: sqlx::Error) -> Self {
+        OAuthError::new("server_error", e.to_string())
+    }
+}
+
+impl From<ApiError> for OAuthError {
+    fn from(e: ApiError) -> Self {
+        match e {
+            ApiError::Unauthorized(detail) => OAuthError::new("invalid_grant", detail),
+            other => OAuthError::new("server_error", other.to_string()),
+        }
+    }
+}
+
+// Data models
+#[derive(Debug, sqlx::FromRow)]
+struct OAuthClient {
+    client_id: String,
+    client_secret_hash: String,
+    allowed_grants: Vec<String>,
+    allowed_scopes: Vec<String>,
+    is_active: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RefreshTokenRow {
+    client_id: String,
+    user_id: Option<i32>,
+    scopes: Vec<String>,
+    expires_at: chrono::DateTime<Utc>,
+    revoked_at: Option<chrono::DateTime<Utc>>,
+}
+
+// application/x-www-form-urlencoded body of /oauth/token
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+}
+
+// Endpoint Callbacks
+// Token endpoint (React frontend: REACT_APP_SHOP_OAUTH_TOKEN_URL)
+// curl -X POST http://localhost:8080/oauth/token \
+//   -d grant_type=password -d client_id=shop-frontend -d client_secret=app \
+//   -d username=beam@example.com -d password=secret123 -d scope="orders:read orders:write"
+pub async fn token(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    token_req: web::Form<TokenRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let client = authenticate_client(&data.db, &req, &token_req).await?;
+
+    if !client.allowed_grants.iter().any(|g| g == &token_req.grant_type) {
+        return Err(OAuthError::new(
+            "unauthorized_client",
+            format!("Client may not use the {} grant", token_req.grant_type),
+        ));
+    }
+
+    let response = match token_req.grant_type.as_str() {
+        "password" => password_grant(&data, &client, &token_req).await?,
+        "client_credentials" => client_credentials_grant(&data, &client, &token_req)?,
+        "refresh_token" => refresh_token_grant(&data, &client, &token_req).await?,
+        other => {
+            return Err(OAuthError::new(
+                "unsupported_grant_type",
+                format!("Unsupported grant_type: {}", other),
+            ));
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(response))
+}
+
+async fn password_grant(
+    data: &AppState,
+    client: &OAuthClient,
+    token_req: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let (Some(username), Some(password)) = (&token_req.username, &token_req.password) else {
+        return Err(OAuthError::new("invalid_request", "username and password are required"));
+    };
+
+    let scopes = resolve_scopes(client, token_req.scope.as_deref(), &client.allowed_scopes)?;
+    let user = auth::check_credentials(data, username, password).await?;
+
+    let access_token = sign_access_token(data, client, Some((user.user_id, user.email, user.role)), &scopes)?;
+    let mut conn = data.db.acquire().await?;
+    let refresh_token = store_refresh_token(&mut conn, client, Some(user.user_id), &scopes).await?;
+
+    Ok(token_response(data, access_token, Some(refresh_token), &scopes))
+}
+
+// No refresh token: the client can always authenticate again
+fn client_credentials_grant(
+    data: &AppState,
+    client: &OAuthClient,
+    token_req: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let scopes = resolve_scopes(client, token_req.scope.as_deref(), &client.allowed_scopes)?;
+    let access_token = sign_access_token(data, client, None, &scopes)?;
+
+    Ok(token_response(data, access_token, None, &scopes))
+}
+
+async fn refresh_token_grant(
+    data: &AppState,
+    client: &OAuthClient,
+    token_req: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let Some(refresh_token) = &token_req.refresh_token else {
+        return Err(OAuthError::new("invalid_request", "refresh_token is required"));
+    };
+    let token_hash = sha256_hex(refresh_token);
+
+    let mut tx = data.db.begin().await?;
+
+    let stored = sqlx::query_as::<_, RefreshTokenRow>(
+        "SELECT client_id, user_id, scopes, expires_at, revoked_at FROM oauth_refresh_tokens WHERE token_hash = $1 FOR UPDATE"
+    )
+        .bind(&token_hash)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| OAuthError::new("invalid_grant", "Unknown refresh token"))?;
+
+    if stored.client_id != client.client_id {
+        return Err(OAuthError::new("invalid_grant", "Refresh token was issued to another client"));
+    }
+    if stored.revoked_at.is_some() || stored.expires_at < Utc::now() {
+        return Err(OAuthError::new("invalid_grant", "Refresh token is expired or revoked"));
+    }
+
+    // A refresh may narrow the original scopes, never widen them
+    let scopes = resolve_scopes(client, token_req.scope.as_deref(), &stored.scopes)?;
+
+    // Email and role are read again, a refreshed token reflects role changes
+    let user = match stored.user_id {
+        Some(user_id) => {
+            let (email, role) = sqlx::query_as::<_, (String, Role)>(
+                "SELECT email, role FROM users WHERE user_id = $1 AND is_active IS NOT FALSE"
+            )
+                .bind(user_id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| OAuthError::new("invalid_grant", "User is no longer active"))?;
+            Some((user_id, email, role))
+        }
+        None => None,
+    };
+
+    // Rotation: a used refresh token can't be replayed
+    sqlx::query("UPDATE oauth_refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE token_hash = $1")
+        .bind(&token_hash)
+        .execute(&mut *tx)
+        .await?;
+
+    let access_token = sign_access_token(data, client, user, &scopes)?;
+    let new_refresh_token = store_refresh_token(&mut tx, client, stored.user_id, &scopes).await?;
+    tx.commit().await?;
+
+    Ok(token_response(data, access_token, Some(new_refresh_token), &scopes))
+}
+
+// `sub` is the user id, or "client:<client_id>" when there is no user
+fn sign_access_token(
+    data: &AppState,
+    client: &OAuthClient,
+    user: Option<(i32, String, Role)>,
+    scopes: &[String],
+) -> Result<String, OAuthError> {
+    let (sub, email, role) = match user {
+        Some((user_id, email, role)) => (user_id.to_string(), Some(email), Some(role)),
+        None => (format!("client:{}", client.client_id), None, None),
+    };
+
+    Ok(data.jwt.sign(sub, email, role, Some(scopes.join(" ")), Some(client.client_id.clone()))?)
+}
+
+// Only the SHA-256 of the token is stored
+async fn store_refresh_token(
+    conn: &mut PgConnection,
+    client: &OAuthClient,
+    user_id: Option<i32>,
+    scopes: &[String],
+) -> Result<String, OAuthError> {
+    let token = generate_token();
+
+    sqlx::query(
+        "INSERT INTO oauth_refresh_tokens (token_hash, client_id, user_id, scopes, expires_at) VALUES ($1, $2, $3, $4, $5)"
+    )
+        .bind(sha256_hex(&token))
+        .bind(&client.client_id)
+        .bind(user_id)
+        .bind(scopes)
+        .bind(Utc::now() + Duration::seconds(refresh_ttl_seconds()))
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(token)
+}
+
+fn token_response(
+    data: &AppState,
+    access_token: String,
+    refresh_token: Option<String>,
+    scopes: &[String],
+) -> TokenResponse {
+    TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: data.jwt.ttl_seconds,
+        refresh_token,
+        scope: scopes.join(" "),
+    }
+}
+
+// Client credentials come from HTTP Basic auth or the form body
+async fn authenticate_client(
+    db: &Pool<Postgres>,
+    req: &HttpRequest,
+    token_req: &TokenRequest,
+) -> Result<OAuthClient, OAuthError> {
+    let (client_id, client_secret) = basic_credentials(req)
+        .or_else(|| Some((token_req.client_id.clone()?, token_req.client_secret.clone()?)))
+        .ok_or_else(|| OAuthError::new("invalid_client", "Client authentication required"))?;
+
+    let client = sqlx::query_as::<_, OAuthClient>(
+        "SELECT client_id, client_secret_hash, allowed_grants, allowed_scopes, is_active FROM oauth_clients WHERE client_id = $1"
+    )
+        .bind(&client_id)
+        .fetch_optional(db)
+        .await?
+        .filter(|c| c.is_active)
+        .ok_or_else(|| OAuthError::new("invalid_client", "Unknown client"))?;
+
+    let hash = client.client_secret_hash.clone();
+    let valid = web::block(move || password::verify_password(&client_secret, &hash))
+        .await
+        .map_err(|e| OAuthError::new("server_error", e.to_string()))?;
+    if !valid {
+        return Err(OAuthError::new("invalid_client", "Invalid client secret"));
+    }
+
+    Ok(client)
+}
+
+fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    Some((id.to_string(), secret.to_string()))
+}
+
+// Requested scopes must be a subset of `available`; none requested means all of them
+fn resolve_scopes(
+    client: &OAuthClient,
+    requested: Option<&str>,
+    available: &[String],
+) -> Result<Vec<String>, OAuthError> {
+    let Some(requested) = requested.filter(|s| !s.trim().is_empty()) else {
+        return Ok(available.to_vec());
+    };
+
+    let mut scopes = Vec::new();
+    for scope in requested.split_whitespace() {
+        if !available.iter().any(|s| s == scope) {
+            return Err(OAuthError::new(
+                "invalid_scope",
+                format!("Scope {} is not allowed for client {}", scope, client.client_id),
+            ));
+        }
+        if !scopes.iter().any(|s: &String| s == scope) {
+            scopes.push(scope.to_string());
+        }
+    }
+    Ok(scopes)
+}
+
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub(crate) fn sha256_hex(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+// OAUTH_REFRESH_TTL_SECONDS, 30 days by default
+fn refresh_ttl_seconds() -> i64 {
+    std::env::var("OAUTH_REFRESH_TTL_SECONDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30 * 24 * 3600)
+}
+
+// CLI: register a client
+// cargo run -- oauth create-client shop-frontend --scopes "orders:read orders:write" \
+//   --grants password,refresh_token [--secret app] [--name "React shop"]
+pub async fn run_cli(pool: &Pool<Postgres>, args: &[String]) -> std::io::Result<()> {
+    let usage = || std::io::Error::other(
+        "usage: oauth create-client <client_id> --scopes <s1 s2> [--grants g1,g2] [--secret S] [--name N]",
+    );
+
+    if args.first().map(String::as_str) != Some("create-client") {
+        return Err(usage());
+    }
+    let client_id = args.get(1).ok_or_else(usage)?.clone();
+
+    let option = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let scopes: Vec<String> = option("--scopes")
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect();
+    let grants: Vec<String> = option("--grants")
+        .unwrap_or_else(|| "password,refresh_token".to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .collect();
+    let secret = option("--secret").unwrap_or_else(generate_token);
+    let name = option("--name").unwrap_or_else(|| client_id.clone());
+
+    let secret_hash = password::hash_password(&secret).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    sqlx::query(
+        "INSERT INTO oauth_clients (client_id, client_secret_hash, name, allowed_grants, allowed_scopes) VALUES ($1, $2, $3, $4, $5)"
+    )
+        .bind(&client_id)
+        .bind(&secret_hash)
+        .bind(&name)
+        .bind(&grants)
+        .bind(&scopes)
+        .execute(pool)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    println!("✅ Client {} created", client_id);
+    println!("   client_secret: {} (shown only once)", secret);
+    Ok(())
+}
@@ -1,36 +1,71 @@
-use std::str::FromStr;
-use actix_web::{web, HttpResponse};
-use serde::{Deserialize, Deserializer, Serialize};
-use uuid::Uuid;
-
+use actix_web::{web, HttpRequest, HttpResponse};
 use rust_decimal::Decimal;
-use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{Pool, Postgres};
+
 use crate::AppState;
-use crate::user::User;
-// use sqlx::types::Decimal;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::money;
+use crate::order_items::OrderItem;
+use crate::order_number;
+use crate::order_status::{self, OrderStatus};
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::payment::PaymentStatus;
+use crate::promotion::OrderDiscount;
 
 
 // Data models
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Order {
-    // id: Uuid, // TODO
-    // Order_id: i64, // TODO
-    // Order_id: i64, // TODO
+    #[sqlx(try_from = "i32")]
+    pub order_id: i64,
 
     #[sqlx(try_from = "i32")]
     pub user_id: i64,
 
+    pub order_number: String,
+
     order_date: Option<chrono::DateTime<chrono::Utc>>,
 
-    #[sqlx(try_from = "i32")]
-    pub total_amount: f64, // use sqlx::types::Decimal?
-    pub status: String,
+    #[serde(with = "money")]
+    pub total_amount: Decimal,
+    // sum of the order_discounts lines, already taken off total_amount
+    #[serde(with = "money")]
+    pub discount_amount: Decimal,
+    // sum of the line taxes, on top of the items unless prices_include_tax (see tax.rs)
+    #[serde(with = "money")]
+    pub tax_amount: Decimal,
+    pub tax_country: Option<String>,
+    pub tax_region: Option<String>,
+    pub prices_include_tax: bool,
+    pub status: OrderStatus,
     pub shipping_address: String,
-    pub billing_address: String,
-    pub payment_method: String,
-    pub payment_status: String,
-    pub notes: String
+    pub billing_address: Option<String>,
+    pub payment_method: Option<String>,
+    pub payment_status: PaymentStatus,
+    pub notes: Option<String>,
+    // set by cancellation::cancel
+    pub cancellation_reason: Option<String>,
+    pub cancelled_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub cancelled_by: Option<i32>,
+    // paid and cancelled, the refund hasn't gone through yet
+    pub refund_pending: bool,
+}
 
+// Order with its lines and discounts, GET /api/orders/{id} and GET /api/users/{id}/orders
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct OrderDetails {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub order: Order,
+    pub items: Json<Vec<OrderItem>>,
+    pub item_count: i32,
+    // sum of the line subtotals
+    #[serde(with = "money")]
+    pub items_total: Decimal,
+    pub discounts: Json<Vec<OrderDiscount>>,
 }
 
 // TODO Requests ...
@@ -39,18 +74,81 @@ pub struct Order {
 pub struct CreateOrderRequest {
     pub user_id: i64,
     // order_date: Option<chrono::DateTime<chrono::Utc>>,
-    pub total_amount: f64, // use sqlx::types::Decimal?
-    pub status: String,
+    #[serde(with = "money")]
+    pub total_amount: Decimal,
+    // status is always "pending" on creation, see order_status.rs for transitions
     pub shipping_address: String,
     pub billing_address: String,
+    // payment_status is managed by payment.rs (gateway callbacks and refunds)
     pub payment_method: String,
-    pub payment_status: String,
     pub notes: String
 }
 
+// GET /api/users/{id}/orders filters
+#[derive(Debug, Deserialize)]
+pub struct UserOrderFilter {
+    pub status: Option<OrderStatus>,
+}
+
+// GET /api/orders filters
+#[derive(Debug, Deserialize)]
+pub struct OrderFilter {
+    pub status: Option<OrderStatus>,
+    pub payment_status: Option<PaymentStatus>,
+    // staff only, customers always get their own orders
+    pub user_id: Option<i32>,
+    // order_date range, RFC 3339
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+const ORDER_LIST: ListSpec = ListSpec {
+    table: "orders",
+    key: "order_id",
+    sort_fields: &[
+        SortField { name: "order_date", expr: "COALESCE(order_date, 'epoch')", sql_type: "TIMESTAMPTZ" },
+        SortField { name: "total_amount", expr: "total_amount", sql_type: "NUMERIC" },
+        SortField { name: "order_number", expr: "order_number", sql_type: "TEXT" },
+    ],
+    default_sort: "-order_date",
+};
+
+// Orders with their lines aggregated into JSON, so a page of orders is one query.
+// Amounts go through TEXT to keep NUMERIC precision in JSON
+pub(crate) const ORDER_DETAILS: &str = "(
+    SELECT o.*, i.items, i.item_count, i.items_total, d.discounts
+    FROM orders o
+    CROSS JOIN LATERAL (
+        SELECT COALESCE(jsonb_agg(
+                   to_jsonb(oi) || jsonb_build_object('unit_price', oi.unit_price::TEXT, 'subtotal', oi.subtotal::TEXT,
+                       'tax_rate', oi.tax_rate::TEXT, 'taxable_amount', oi.taxable_amount::TEXT, 'tax_amount', oi.tax_amount::TEXT)
+                   ORDER BY oi.order_item_id
+               ), '[]') AS items,
+               COALESCE(SUM(oi.quantity), 0)::INTEGER AS item_count,
+               COALESCE(SUM(oi.subtotal), 0) AS items_total
+        FROM order_items oi
+        WHERE oi.order_id = o.order_id
+    ) i
+    CROSS JOIN LATERAL (
+        SELECT COALESCE(jsonb_agg(
+                   to_jsonb(od) || jsonb_build_object('amount', od.amount::TEXT)
+                   ORDER BY od.order_discount_id
+               ), '[]') AS discounts
+        FROM order_discounts od
+        WHERE od.order_id = o.order_id
+    ) d
+) AS order_details";
+
+const ORDER_DETAILS_LIST: ListSpec = ListSpec {
+    table: ORDER_DETAILS,
+    key: "order_id",
+    sort_fields: ORDER_LIST.sort_fields,
+    default_sort: "-order_date",
+};
 
 // Endpoint Callbacks
-// Create Order
+// Create Order (staff only): the total is taken as given, customers order
+// through /orders/checkout which prices the items
 // curl -X POST http://localhost:8080/api/orders \
 //   -H "Content-Type: application/json" \
 //   -d '{"name": "Iphone", "sku": "iphone1234567tt", "description": "best phone(", "price": 1000, "category_id": 1, "image_url": "https://images/1.webp", "is_available": true}'
@@ -58,74 +156,128 @@ pub struct CreateOrderRequest {
 pub(crate) async fn create_order(
     data: web::Data<AppState>,
     order_req: web::Json<CreateOrderRequest>,
-) -> actix_web::Result<HttpResponse> {
-    match sqlx::query_as::<_, Order>(
-        "INSERT INTO orders (user_id, total_amount, status, shipping_address, billing_address, payment_method, payment_status, notes) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *"
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    auth.ensure_owner_or_staff(order_req.user_id)?;
+    // orders.user_id is INTEGER, an INT8 parameter is rejected by Postgres
+    let user_id = i32::try_from(order_req.user_id).map_err(|_| ApiError::validation("user_id is out of range"))?;
+    let total_amount = money::validate(order_req.total_amount, "total_amount")?;
+
+    let order_number = order_number::next(&data.db, &data.order_numbers).await?;
+    let mut tx = data.db.begin().await?;
+
+    // Unknown user_id is mapped to 422 by ApiError
+    let order = sqlx::query_as::<_, Order>(
+        "INSERT INTO orders (user_id, order_number, total_amount, shipping_address, billing_address, payment_method, notes) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *"
     )
-        .bind(&order_req.user_id)
-        .bind(&order_req.total_amount)
-        .bind(&order_req.status)
+        .bind(user_id)
+        .bind(&order_number)
+        .bind(total_amount)
         .bind(&order_req.shipping_address)
         // .bind(&order_req.regular_price)
         .bind(&order_req.billing_address)
         .bind(&order_req.payment_method)
-        .bind(&order_req.payment_status)
         .bind(&order_req.notes)
-        .fetch_one(&data.db)
-        .await
-    {
-        Ok(order) => Ok(HttpResponse::Created().json(order)),
-        Err(e) => {
-            // Handle unique constraint violation
-            if e.to_string().contains("unique constraint") {
-                Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "Sku already exists"
-                })))
-            } else {
-                Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": e.to_string()
-                })))
-            }
+        .fetch_one(&mut *tx)
+        .await?;
+
+    order_status::record_history(&mut tx, order.order_id as i32, None, order.status, None, None).await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(order))
+}
+
+
+// Customers see their own orders, staff see all of them
+// curl "http://localhost:8080/api/orders?status=pending&from=2026-01-01T00:00:00Z&sort=-total_amount" -H "Authorization: Bearer $TOKEN"
+pub async fn get_orders(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    filter: web::Query<OrderFilter>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let owner = match filter.user_id {
+        Some(user_id) => {
+            auth.ensure_owner_or_staff(user_id.into())?;
+            Some(user_id)
+        }
+        None => (!auth.is_staff()).then_some(auth.user_id),
+    };
+
+    pagination::list::<Order>(&data.db, &req, &ORDER_LIST, &page, |q| {
+        if let Some(user_id) = owner {
+            q.push(" AND user_id = ").push_bind(user_id);
+        }
+        if let Some(status) = filter.status {
+            q.push(" AND status = ").push_bind(status);
+        }
+        if let Some(payment_status) = filter.payment_status {
+            q.push(" AND payment_status = ").push_bind(payment_status);
+        }
+        if let Some(from) = filter.from {
+            q.push(" AND order_date >= ").push_bind(from);
         }
-    }
+        if let Some(to) = filter.to {
+            q.push(" AND order_date < ").push_bind(to);
+        }
+    })
+        .await
 }
 
+// Order with its lines
+// curl http://localhost:8080/api/orders/1 -H "Authorization: Bearer $TOKEN"
+pub async fn get_order(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order = sqlx::query_as::<_, OrderDetails>(&format!("SELECT * FROM {} WHERE order_id = $1", ORDER_DETAILS))
+        .bind(path.into_inner())
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Order"))?;
 
-// curl http://localhost:8080/api/orders
-pub async fn get_orders(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    auth.ensure_owner_or_staff(order.order.user_id)?;
 
-    // SELECT amount::FLOAT8
+    Ok(HttpResponse::Ok().json(order))
+}
 
-    // match sqlx::query_as::<_, Order>("SELECT * FROM orders ORDER BY created_at DESC")
-    match sqlx::query_as::<_, Order>("SELECT order_id, name, sku, description, price::FLOAT8, category_id::INT2, image_url, created_at, updated_at, is_available FROM orders ORDER BY created_at DESC")
-        .fetch_all(&data.db)
+// Order history of a user with the lines of every order (React frontend: /order/user/{id})
+// curl "http://localhost:8080/api/users/1/orders?status=delivered&page=1" -H "Authorization: Bearer $TOKEN"
+pub async fn get_user_orders(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    page: web::Query<PageParams>,
+    filter: web::Query<UserOrderFilter>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    auth.ensure_owner_or_staff(user_id.into())?;
+
+    pagination::list::<OrderDetails>(&data.db, &req, &ORDER_DETAILS_LIST, &page, |q| {
+        q.push(" AND user_id = ").push_bind(user_id);
+        if let Some(status) = filter.status {
+            q.push(" AND status = ").push_bind(status);
+        }
+    })
         .await
-    {
-        Ok(orders) => Ok(HttpResponse::Ok().json(orders)),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e.to_string()
-        }))),
-    }
 }
 
+// 404 for unknown orders, 403 for orders of other customers
+pub async fn ensure_order_access(
+    db: &Pool<Postgres>,
+    order_id: i32,
+    auth: &AuthenticatedUser,
+) -> Result<(), ApiError> {
+    let owner_id = sqlx::query_scalar::<_, i32>("SELECT user_id FROM orders WHERE order_id = $1")
+        .bind(order_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("Order"))?;
+
+    auth.ensure_owner_or_staff(owner_id.into())
+}
 
-// Вариант 1: Если Decimal передается как строка
-// fn deserialize_decimal_to_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
-// where
-//     D: Deserializer<'de>,
-// {
-//     // Пробуем десериализовать как строку
-//     let s = String::deserialize(deserializer)?;
-//     Decimal::from_str(&s)
-//         .map(|d| d.to_f64().unwrap_or(0.0))
-//         .map_err(serde::de::Error::custom)
-// }
-
-fn deserialize_number_to_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    // Пробуем как f64 напрямую
-    let num = f64::deserialize(deserializer)?;
-    Ok(num)
-}
\ No newline at end of file
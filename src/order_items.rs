@@ -1,14 +1,22 @@
-use std::str::FromStr;
-use actix_web::{web, HttpResponse};
-use serde::{Deserialize, Deserializer, Serialize};
-use uuid::Uuid;
-
+// Order lines.
+//
+// Lines can only change while the order is pending and nothing has been paid.
+// Every change re-reserves stock for the line (see inventory::reserve_line) and
+// recomputes the discounts and orders.total_amount in the same transaction.
+use actix_web::{web, HttpRequest, HttpResponse};
 use rust_decimal::Decimal;
-use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
 use crate::AppState;
-use crate::order::Order;
-use crate::product::{CreateProductRequest, Product};
-use crate::user::User;
+use crate::error::ApiError;
+use crate::inventory;
+use crate::money;
+use crate::order_status::OrderStatus;
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::payment::PaymentStatus;
+use crate::promotion;
+use crate::tax;
 
 // Data models
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -29,17 +37,25 @@ pub struct OrderItem {
     #[sqlx(try_from = "i32")]
     pub quantity: i64,
 
-    // pub price: Decimal,
-
-    /* // TODO
-    pub unit_price: f64, // use sqlx::types::Decimal?
-
-    pub subtotal: f64, // use sqlx::types::Decimal?
-    */
-
+    #[serde(with = "money")]
+    pub unit_price: Decimal,
 
+    // Generated column: quantity * unit_price
+    #[serde(with = "money")]
+    pub subtotal: Decimal,
 
+    // Product as it was when the line was added
+    pub product_name: String,
+    pub product_sku: Option<String>,
+    pub product_image_url: Option<String>,
 
+    // See tax.rs; taxable_amount is net of discounts and tax
+    pub tax_class: String,
+    pub tax_rate: Decimal,
+    #[serde(with = "money")]
+    pub taxable_amount: Decimal,
+    #[serde(with = "money")]
+    pub tax_amount: Decimal,
 }
 
 // TODO Requests ...
@@ -57,65 +73,276 @@ pub struct CreateOrderItemRequest {
     // #[sqlx(try_from = "i32")]
     pub quantity: i64,
 
-    // pub price: Decimal,
+    #[serde(with = "money")]
+    pub unit_price: Decimal,
+}
+
+// PATCH /api/order-items/{id}, omitted fields are left as they are
+#[derive(Debug, Deserialize)]
+pub struct UpdateOrderItemRequest {
+    pub quantity: Option<i32>,
+    #[serde(default, with = "money::option")]
+    pub unit_price: Option<Decimal>,
+}
+
+// GET /api/order-items filters
+#[derive(Debug, Deserialize)]
+pub struct OrderItemFilter {
+    pub order_id: Option<i32>,
+    pub product_id: Option<i32>,
+}
+
+const ORDER_ITEM_LIST: ListSpec = ListSpec {
+    table: "order_items",
+    key: "order_item_id",
+    sort_fields: &[
+        SortField { name: "order_id", expr: "order_id", sql_type: "INTEGER" },
+        SortField { name: "quantity", expr: "quantity", sql_type: "INTEGER" },
+        SortField { name: "subtotal", expr: "subtotal", sql_type: "NUMERIC" },
+    ],
+    default_sort: "-order_id",
+};
+
+// Locks the order; 409 once it is past pending, has been paid for or is being paid
+async fn lock_editable_order(conn: &mut PgConnection, order_id: i32) -> Result<(), ApiError> {
+    let (status, payment_status) = sqlx::query_as::<_, (OrderStatus, PaymentStatus)>(
+        "SELECT status, payment_status FROM orders WHERE order_id = $1 FOR UPDATE"
+    )
+        .bind(order_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Order"))?;
+
+    if status != OrderStatus::Pending {
+        return Err(ApiError::Conflict {
+            code: "order_not_editable",
+            detail: format!("Items of a {} order cannot be changed", status),
+        });
+    }
+    if !payment_status.can_initiate() {
+        return Err(ApiError::Conflict {
+            code: "order_not_editable",
+            detail: format!("Items cannot be changed, the order is {}", payment_status),
+        });
+    }
+    // A charge for the current total is already at the gateway
+    let charging = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM payments WHERE order_id = $1 AND status = 'pending')"
+    )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if charging {
+        return Err(ApiError::Conflict {
+            code: "order_not_editable",
+            detail: "Items cannot be changed while a payment is pending".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Locks the line and its order, checking the order can still change
+async fn lock_editable_item(conn: &mut PgConnection, order_item_id: i32) -> Result<OrderItem, ApiError> {
+    let order_id = sqlx::query_scalar::<_, i32>("SELECT order_id FROM order_items WHERE order_item_id = $1")
+        .bind(order_item_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Order item"))?;
+
+    lock_editable_order(conn, order_id).await?;
+
+    // Read again under the order lock, it may have gone in the meantime
+    sqlx::query_as::<_, OrderItem>("SELECT * FROM order_items WHERE order_item_id = $1 FOR UPDATE")
+        .bind(order_item_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Order item"))
+}
+
+fn validate_quantity(quantity: i64) -> Result<i32, ApiError> {
+    match i32::try_from(quantity) {
+        Ok(quantity) if quantity > 0 => Ok(quantity),
+        _ => Err(ApiError::validation("quantity must be a positive number")),
+    }
+}
+
+// The line with the tax recalculate_total gave it
+async fn reload_item(conn: &mut PgConnection, order_item_id: i32) -> Result<OrderItem, ApiError> {
+    let order_item = sqlx::query_as::<_, OrderItem>("SELECT * FROM order_items WHERE order_item_id = $1")
+        .bind(order_item_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok(order_item)
+}
+
+// orders.total_amount = sum of the line subtotals - discounts (+ tax), inside the
+// caller's transaction; the order's promotions and taxes are worked out again for
+// the new lines
+pub async fn recalculate_total(conn: &mut PgConnection, order_id: i32) -> Result<Decimal, ApiError> {
+    let discounts = promotion::reapply(conn, order_id).await?;
+    let discount_amount = promotion::total(&discounts);
+    let tax_amount = tax::retax(conn, order_id, &discounts).await?;
 
-    pub unit_price: f64, // use sqlx::types::Decimal?
+    // See tax::order_total
+    let total = sqlx::query_scalar::<_, Decimal>(
+        "UPDATE orders SET discount_amount = $2, tax_amount = $3,
+            total_amount = (SELECT COALESCE(SUM(subtotal), 0) FROM order_items WHERE order_id = $1) - $2 + CASE WHEN prices_include_tax THEN 0 ELSE $3 END
+        WHERE order_id = $1 RETURNING total_amount"
+    )
+        .bind(order_id)
+        .bind(discount_amount)
+        .bind(tax_amount)
+        .fetch_one(&mut *conn)
+        .await?;
 
-    // pub subtotal: f64, // use sqlx::types::Decimal?
+    Ok(total)
 }
 
 // Endpoint Callbacks
 // Create Order Item
 // curl -X POST http://localhost:8080/api/order-items \
 // -H "Content-Type: application/json" \
-// -d '{"order_id": 7, "product_id": 4, "quantity": 10, "unit_price": 1000}'
+// -d '{"order_id": 7, "product_id": 4, "quantity": 10, "unit_price": "1000.00"}'
 
 pub(crate) async fn create_order_item(
     data: web::Data<AppState>,
     order_item_req: web::Json<CreateOrderItemRequest>,
-) -> actix_web::Result<HttpResponse> {
-    match sqlx::query_as::<_, OrderItem>(
+) -> Result<HttpResponse, ApiError> {
+    let unit_price = money::validate(order_item_req.unit_price, "unit_price")?;
+    let quantity = validate_quantity(order_item_req.quantity)?;
+    // INTEGER columns, an INT8 parameter is rejected by Postgres
+    let order_id = i32::try_from(order_item_req.order_id).map_err(|_| ApiError::NotFound("Order"))?;
+    let product_id = i32::try_from(order_item_req.product_id).map_err(|_| ApiError::Unprocessable {
+        code: "reference_not_found",
+        detail: "Product does not exist".to_string(),
+    })?;
+
+    let mut tx = data.db.begin().await?;
+    lock_editable_order(&mut tx, order_id).await?;
+
+    // Duplicate product in the order -> 409 (see ApiError)
+    let order_item = sqlx::query_as::<_, OrderItem>(
         // "INSERT INTO order_items (order_id, product_id, quantity, unit_price, subtotal) VALUES ($1, $2, $3, $4, $5) RETURNING *"
-        "INSERT INTO order_items (order_id, product_id, quantity, unit_price) VALUES ($1, $2, $3, $4) RETURNING *"
+        "INSERT INTO order_items (order_id, product_id, quantity, unit_price, product_name, product_sku, product_image_url, tax_class) SELECT $1, p.product_id, $3, $4, p.name, p.sku, p.image_url, p.tax_class FROM products p WHERE p.product_id = $2 RETURNING *"
     )
-        .bind(&order_item_req.order_id)
-        .bind(&order_item_req.product_id)
-        .bind(&order_item_req.quantity)
-        .bind(&order_item_req.unit_price)
+        .bind(order_id)
+        .bind(product_id)
+        .bind(quantity)
+        .bind(unit_price)
         // .bind(&order_item_req.subtotal)
 
-        .fetch_one(&data.db)
-        .await
-    {
-        Ok(order_item) => Ok(HttpResponse::Created().json(order_item)),
-        Err(e) => {
-            // Handle unique constraint violation
-            if e.to_string().contains("unique constraint") {
-                Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "Item is already exists"
-                })))
-            } else {
-                Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": e.to_string()
-                })))
-            }
-        }
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| ApiError::Unprocessable {
+            code: "reference_not_found",
+            detail: "Product does not exist".to_string(),
+        })?;
+
+    inventory::reserve_line(&mut tx, order_id, product_id, quantity, data.inventory.reservation_ttl).await?;
+    recalculate_total(&mut tx, order_id).await?;
+    let order_item = reload_item(&mut tx, order_item.order_item_id as i32).await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(order_item))
+}
+
+// Change quantity and/or price of a line of a pending order
+// curl -X PATCH http://localhost:8080/api/order-items/3 \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"quantity": 2}'
+pub async fn update_order_item(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    update_req: web::Json<UpdateOrderItemRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let order_item_id = path.into_inner();
+    let quantity = update_req.quantity.map(|q| validate_quantity(q.into())).transpose()?;
+    let unit_price = update_req
+        .unit_price
+        .map(|price| money::validate(price, "unit_price"))
+        .transpose()?;
+
+    let mut tx = data.db.begin().await?;
+    let current = lock_editable_item(&mut tx, order_item_id).await?;
+
+    let order_item = sqlx::query_as::<_, OrderItem>(
+        "UPDATE order_items SET quantity = COALESCE($2, quantity), unit_price = COALESCE($3, unit_price) WHERE order_item_id = $1 RETURNING *"
+    )
+        .bind(order_item_id)
+        .bind(quantity)
+        .bind(unit_price)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if order_item.quantity != current.quantity {
+        inventory::reserve_line(
+            &mut tx,
+            order_item.order_id as i32,
+            order_item.product_id as i32,
+            order_item.quantity as i32,
+            data.inventory.reservation_ttl,
+        )
+            .await?;
     }
+    recalculate_total(&mut tx, order_item.order_id as i32).await?;
+    let order_item = reload_item(&mut tx, order_item_id).await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(order_item))
 }
 
-// curl http://localhost:8080/api/order-items
-pub async fn get_order_items(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+// Remove a line from a pending order; the last one can't go, cancel the order instead
+// curl -X DELETE http://localhost:8080/api/order-items/3 -H "Authorization: Bearer $TOKEN"
+pub async fn delete_order_item(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let order_item_id = path.into_inner();
 
-    // SELECT amount::FLOAT8
+    let mut tx = data.db.begin().await?;
+    let order_item = lock_editable_item(&mut tx, order_item_id).await?;
+    let order_id = order_item.order_id as i32;
 
-    // match sqlx::query_as::<_, Order>("SELECT * FROM orders ORDER BY created_at DESC")
-    match sqlx::query_as::<_, OrderItem>("SELECT order_item_id, order_id, product_id, quantity, unit_price::FLOAT8, subtotal::FLOAT8 FROM order_items ORDER BY order_id DESC")
-        .fetch_all(&data.db)
-        .await
-    {
-        Ok(order_items) => Ok(HttpResponse::Ok().json(order_items)),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e.to_string()
-        }))),
+    let remaining = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM order_items WHERE order_id = $1")
+        .bind(order_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if remaining <= 1 {
+        return Err(ApiError::Conflict {
+            code: "last_order_item",
+            detail: "An order needs at least one item, cancel the order instead".to_string(),
+        });
     }
+
+    sqlx::query("DELETE FROM order_items WHERE order_item_id = $1")
+        .bind(order_item_id)
+        .execute(&mut *tx)
+        .await?;
+
+    inventory::reserve_line(&mut tx, order_id, order_item.product_id as i32, 0, data.inventory.reservation_ttl).await?;
+    recalculate_total(&mut tx, order_id).await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// curl "http://localhost:8080/api/order-items?order_id=1&sort=-subtotal" -H "Authorization: Bearer $TOKEN"
+pub async fn get_order_items(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    filter: web::Query<OrderItemFilter>,
+) -> Result<HttpResponse, ApiError> {
+    pagination::list::<OrderItem>(&data.db, &req, &ORDER_ITEM_LIST, &page, |q| {
+        if let Some(order_id) = filter.order_id {
+            q.push(" AND order_id = ").push_bind(order_id);
+        }
+        if let Some(product_id) = filter.product_id {
+            q.push(" AND product_id = ").push_bind(product_id);
+        }
+    })
+        .await
 }
\ No newline at end of file
@@ -0,0 +1,237 @@
+// Order numbers.
+//
+//   ORD-20261018-00042-6
+//   │   │        │     └ check digit (Luhn over all digits before it)
+//   │   │        └ sequence, restarts with every new date component
+//   │   └ date component
+//   └ prefix
+//
+// The sequence lives in `order_number_counters` and is taken in its own short
+// statement before the order's transaction starts, so concurrent checkouts only
+// wait on the counter row for that statement. An order that fails or is rolled
+// back leaves a gap; nothing relies on numbers being gapless, the UNIQUE
+// constraint on orders.order_number is the only guarantee.
+use actix_web::{web, HttpResponse};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{TimeZone, Utc};
+use sqlx::{Pool, Postgres};
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::order::{self, OrderDetails};
+
+// order_number_counters.period is VARCHAR(20), orders.order_number VARCHAR(50)
+const MAX_PERIOD_LENGTH: usize = 20;
+const MAX_NUMBER_LENGTH: usize = 50;
+
+// ORDER_NUMBER_PREFIX (default ORD, empty for none), ORDER_NUMBER_DATE_FORMAT
+// (strftime, default %Y%m%d, empty for one sequence forever), ORDER_NUMBER_PADDING
+// (default 5) and ORDER_NUMBER_CHECK_DIGIT (default true)
+#[derive(Debug, Clone)]
+pub struct OrderNumberConfig {
+    pub prefix: String,
+    pub date_format: Option<String>,
+    pub padding: usize,
+    pub check_digit: bool,
+}
+
+impl Default for OrderNumberConfig {
+    fn default() -> Self {
+        OrderNumberConfig {
+            prefix: "ORD".to_string(),
+            date_format: Some("%Y%m%d".to_string()),
+            padding: 5,
+            check_digit: true,
+        }
+    }
+}
+
+impl OrderNumberConfig {
+    pub fn from_env() -> Self {
+        let defaults = OrderNumberConfig::default();
+
+        let date_format = match std::env::var("ORDER_NUMBER_DATE_FORMAT") {
+            Ok(format) if format.is_empty() => None,
+            Ok(format) => Some(format),
+            Err(_) => defaults.date_format,
+        };
+        // A bad format would only fail when the first order is placed
+        if let Some(format) = &date_format
+            && StrftimeItems::new(format).any(|item| item == Item::Error)
+        {
+            panic!("Invalid ORDER_NUMBER_DATE_FORMAT: {}", format);
+        }
+
+        let config = OrderNumberConfig {
+            prefix: std::env::var("ORDER_NUMBER_PREFIX").unwrap_or(defaults.prefix),
+            date_format,
+            padding: std::env::var("ORDER_NUMBER_PADDING")
+                .ok()
+                .map(|v| v.parse().expect("ORDER_NUMBER_PADDING must be a number"))
+                .unwrap_or(defaults.padding),
+            check_digit: std::env::var("ORDER_NUMBER_CHECK_DIGIT")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(defaults.check_digit),
+        };
+
+        // Same reason: a number that doesn't fit its column fails every checkout.
+        // September and Wednesday have the longest names
+        let widest = Utc.with_ymd_and_hms(2026, 9, 30, 23, 59, 59).unwrap();
+        let period = config
+            .date_format
+            .as_ref()
+            .map(|format| widest.format(format).to_string())
+            .unwrap_or_default();
+        if period.len() > MAX_PERIOD_LENGTH {
+            panic!(
+                "ORDER_NUMBER_DATE_FORMAT renders up to {} characters, at most {} fit",
+                period.len(),
+                MAX_PERIOD_LENGTH
+            );
+        }
+        let number = config.format(&period, 1);
+        if number.len() > MAX_NUMBER_LENGTH {
+            panic!(
+                "Order numbers would be {} characters long, at most {} fit; shorten ORDER_NUMBER_PREFIX, ORDER_NUMBER_DATE_FORMAT or ORDER_NUMBER_PADDING",
+                number.len(),
+                MAX_NUMBER_LENGTH
+            );
+        }
+        config
+    }
+
+    fn format(&self, period: &str, value: i64) -> String {
+        let sequence = format!("{:0width$}", value, width = self.padding);
+        let mut number = [self.prefix.as_str(), period, sequence.as_str()]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("-");
+
+        if self.check_digit {
+            let check = luhn_check_digit(&number);
+            number.push('-');
+            number.push(check);
+        }
+        number
+    }
+}
+
+// Luhn check digit over the ASCII digits of `payload`; catches single-digit typos
+// and most swaps of adjacent digits
+fn luhn_check_digit(payload: &str) -> char {
+    let sum: u32 = payload
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .rev()
+        .enumerate()
+        .map(|(i, digit)| match i % 2 {
+            0 if digit * 2 > 9 => digit * 2 - 9,
+            0 => digit * 2,
+            _ => digit,
+        })
+        .sum();
+
+    char::from_digit((10 - sum % 10) % 10, 10).unwrap_or('0')
+}
+
+// false if the last "-" part is a digit that doesn't match the rest
+fn check_digit_matches(number: &str) -> bool {
+    match number.rsplit_once('-') {
+        Some((payload, check)) if check.len() == 1 => luhn_check_digit(payload).to_string() == check,
+        _ => true,
+    }
+}
+
+// Next number. Autocommitted on the pool: call it before opening the order's
+// transaction, never inside one, or the counter row stays locked until commit
+pub async fn next(db: &Pool<Postgres>, config: &OrderNumberConfig) -> Result<String, ApiError> {
+    let period = config
+        .date_format
+        .as_ref()
+        .map(|format| Utc::now().format(format).to_string())
+        .unwrap_or_default();
+
+    let value = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO order_number_counters (period, last_value) VALUES ($1, 1) ON CONFLICT (period) DO UPDATE SET last_value = order_number_counters.last_value + 1 RETURNING last_value"
+    )
+        .bind(&period)
+        .fetch_one(db)
+        .await?;
+
+    Ok(config.format(&period, value))
+}
+
+// Endpoint Callbacks
+// Find an order by the number the customer reads out to support
+// curl http://localhost:8080/api/orders/number/ORD-20261018-00042-6 -H "Authorization: Bearer $TOKEN"
+pub async fn get_order_by_number(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order_number = path.into_inner();
+
+    let order = sqlx::query_as::<_, OrderDetails>(&format!(
+        "SELECT * FROM {} WHERE order_number = $1",
+        order::ORDER_DETAILS
+    ))
+        .bind(&order_number)
+        .fetch_optional(&data.db)
+        .await?;
+
+    let Some(order) = order else {
+        // Older numbers have no check digit, so this is only a hint for misses
+        if data.order_numbers.check_digit && !check_digit_matches(&order_number) {
+            return Err(ApiError::Unprocessable {
+                code: "invalid_order_number",
+                detail: format!("{} has a wrong check digit, probably a typo", order_number),
+            });
+        }
+        return Err(ApiError::NotFound("Order"));
+    };
+
+    auth.ensure_owner_or_staff(order.order.user_id)?;
+
+    Ok(HttpResponse::Ok().json(order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn luhn_check_digit_of_the_reference_example() {
+        assert_eq!(luhn_check_digit("7992739871"), '3');
+    }
+
+    #[test]
+    fn luhn_check_digit_ignores_everything_but_digits() {
+        assert_eq!(luhn_check_digit("ORD-79927-39871"), '3');
+        assert_eq!(luhn_check_digit("ORD-20261018-00042"), '6');
+    }
+
+    #[test]
+    fn check_digit_catches_typos_and_swaps() {
+        assert!(check_digit_matches("ORD-20261018-00042-6"));
+        assert!(!check_digit_matches("ORD-20261018-00043-6"));
+        assert!(!check_digit_matches("ORD-20261018-00024-6"));
+    }
+
+    #[test]
+    fn numbers_without_a_check_digit_are_not_judged() {
+        assert!(check_digit_matches("ORD-20261018-00042"));
+        assert!(check_digit_matches("12345"));
+    }
+
+    #[test]
+    fn format_skips_empty_parts() {
+        let config = OrderNumberConfig::default();
+        assert_eq!(config.format("20261018", 42), "ORD-20261018-00042-6");
+
+        let config = OrderNumberConfig { prefix: String::new(), date_format: None, padding: 3, check_digit: false };
+        assert_eq!(config.format("", 7), "007");
+    }
+}
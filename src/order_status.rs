@@ -0,0 +1,273 @@
+// Order status state machine.
+//
+//   pending ──► processing ──► shipped ──► delivered
+//      │             │
+//      └──► cancelled ◄┘
+//
+// Every change goes through `change_status`, which enforces the graph above,
+// records the move in `order_status_history` and moves stock (see inventory.rs).
+// Cancelling also refunds the order, see cancellation.rs.
+use std::str::FromStr;
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::cancellation;
+use crate::error::ApiError;
+use crate::inventory;
+use crate::order::{self, Order};
+use crate::pg_enum::impl_pg_string_enum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    Pending,
+    Processing,
+    Shipped,
+    Delivered,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Processing => "processing",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Delivered => "delivered",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+
+    // Allowed transition graph
+    pub fn allowed_transitions(&self) -> &'static [OrderStatus] {
+        match self {
+            OrderStatus::Pending => &[OrderStatus::Processing, OrderStatus::Cancelled],
+            OrderStatus::Processing => &[OrderStatus::Shipped, OrderStatus::Cancelled],
+            OrderStatus::Shipped => &[OrderStatus::Delivered],
+            OrderStatus::Delivered | OrderStatus::Cancelled => &[],
+        }
+    }
+
+    pub fn can_transition_to(&self, next: OrderStatus) -> bool {
+        self.allowed_transitions().contains(&next)
+    }
+}
+
+impl FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(OrderStatus::Pending),
+            "processing" => Ok(OrderStatus::Processing),
+            "shipped" => Ok(OrderStatus::Shipped),
+            "delivered" => Ok(OrderStatus::Delivered),
+            "cancelled" => Ok(OrderStatus::Cancelled),
+            other => Err(format!("unknown order status: {}", other)),
+        }
+    }
+}
+
+// Stored as VARCHAR(20)
+impl_pg_string_enum!(OrderStatus);
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct OrderStatusHistory {
+    pub history_id: i32,
+    pub order_id: i32,
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub changed_by: Option<i32>,
+    pub comment: Option<String>,
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransitionRequest {
+    pub status: OrderStatus,
+    pub comment: Option<String>,
+}
+
+// Moves the order to `next` inside the caller's transaction
+pub async fn change_status(
+    conn: &mut PgConnection,
+    order_id: i32,
+    next: OrderStatus,
+    changed_by: Option<i32>,
+    comment: Option<&str>,
+) -> Result<Order, ApiError> {
+    let current = sqlx::query_scalar::<_, OrderStatus>(
+        "SELECT status FROM orders WHERE order_id = $1 FOR UPDATE"
+    )
+        .bind(order_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Order"))?;
+
+    if !current.can_transition_to(next) {
+        return Err(ApiError::Conflict {
+            code: "illegal_transition",
+            detail: format!("Cannot move order from {} to {}", current, next),
+        });
+    }
+
+    let order = sqlx::query_as::<_, Order>(
+        "UPDATE orders SET status = $2 WHERE order_id = $1 RETURNING *"
+    )
+        .bind(order_id)
+        .bind(next)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    match next {
+        OrderStatus::Processing => inventory::sell_order(conn, order_id, changed_by).await?,
+        OrderStatus::Cancelled => inventory::return_order(conn, order_id, changed_by).await?,
+        _ => {}
+    }
+
+    record_history(conn, order_id, Some(current), next, changed_by, comment).await?;
+
+    Ok(order)
+}
+
+// Appends a row to order_status_history
+pub async fn record_history(
+    conn: &mut PgConnection,
+    order_id: i32,
+    from: Option<OrderStatus>,
+    to: OrderStatus,
+    changed_by: Option<i32>,
+    comment: Option<&str>,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "INSERT INTO order_status_history (order_id, from_status, to_status, changed_by, comment) VALUES ($1, $2, $3, $4, $5)"
+    )
+        .bind(order_id)
+        .bind(from)
+        .bind(to)
+        .bind(changed_by)
+        .bind(comment)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+// Endpoint Callbacks
+// Change order status (staff only)
+// curl -X POST http://localhost:8080/api/orders/1/transitions \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"status": "processing", "comment": "paid by card"}'
+pub async fn transition_order(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    transition_req: web::Json<TransitionRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let order_id = path.into_inner();
+    // Recorded as the authenticated user, NULL for client_credentials tokens
+    let changed_by = auth.map(|a| a.user_id);
+    let mut tx = data.db.begin().await?;
+
+    // The comment becomes the cancellation reason
+    if transition_req.status == OrderStatus::Cancelled {
+        let order = cancellation::cancel(&mut tx, order_id, transition_req.comment.as_deref(), changed_by).await?;
+
+        tx.commit().await?;
+
+        let cancellation = cancellation::finish(&data, order).await?;
+
+        return Ok(HttpResponse::Ok().json(cancellation));
+    }
+
+    let order = change_status(
+        &mut tx,
+        order_id,
+        transition_req.status,
+        changed_by,
+        transition_req.comment.as_deref(),
+    )
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(order))
+}
+
+// Status history of an order
+// curl http://localhost:8080/api/orders/1/history -H "Authorization: Bearer $TOKEN"
+pub async fn get_order_history(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order_id = path.into_inner();
+    order::ensure_order_access(&data.db, order_id, &auth).await?;
+
+    let history = sqlx::query_as::<_, OrderStatusHistory>(
+        "SELECT * FROM order_status_history WHERE order_id = $1 ORDER BY changed_at, history_id"
+    )
+        .bind(order_id)
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [OrderStatus; 5] = [
+        OrderStatus::Pending,
+        OrderStatus::Processing,
+        OrderStatus::Shipped,
+        OrderStatus::Delivered,
+        OrderStatus::Cancelled,
+    ];
+
+    #[test]
+    fn transition_graph() {
+        let allowed = [
+            (OrderStatus::Pending, OrderStatus::Processing),
+            (OrderStatus::Pending, OrderStatus::Cancelled),
+            (OrderStatus::Processing, OrderStatus::Shipped),
+            (OrderStatus::Processing, OrderStatus::Cancelled),
+            (OrderStatus::Shipped, OrderStatus::Delivered),
+        ];
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    allowed.contains(&(from, to)),
+                    "{} -> {}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn delivered_and_cancelled_are_final() {
+        assert!(OrderStatus::Delivered.allowed_transitions().is_empty());
+        assert!(OrderStatus::Cancelled.allowed_transitions().is_empty());
+    }
+
+    #[test]
+    fn shipped_orders_cannot_be_cancelled() {
+        assert!(!OrderStatus::Shipped.can_transition_to(OrderStatus::Cancelled));
+    }
+
+    #[test]
+    fn parses_what_it_stores() {
+        for status in ALL {
+            assert_eq!(status.as_str().parse::<OrderStatus>(), Ok(status));
+        }
+        assert!("refunded".parse::<OrderStatus>().is_err());
+    }
+}
@@ -0,0 +1,288 @@
+// Shared query-parameter layer for list endpoints.
+//
+//   ?page=2&limit=20&sort=-price     offset pagination
+//   ?limit=20&sort=name              cursor (keyset) pagination, follow the links
+//
+// `sort` is a whitelisted field name, `-` prefix for descending order. The body
+// stays a plain JSON array (the frontend expects arrays); the total count is sent
+// in `X-Total-Count` and first/prev/next/last links in `Link` (RFC 8288).
+// Resources add their own typed filters through the `filters` callback of `list`.
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder, Row};
+
+use crate::error::ApiError;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+// Deeper pages are refused rather than scanned; cursors have no such limit
+const MAX_PAGE: i64 = 100_000;
+
+#[derive(Debug, Deserialize)]
+pub struct PageParams {
+    pub page: Option<i64>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+}
+
+// `expr` must be NOT NULL (wrap nullable columns in COALESCE), `sql_type` is used
+// to cast the cursor value back
+pub struct SortField {
+    pub name: &'static str,
+    pub expr: &'static str,
+    pub sql_type: &'static str,
+}
+
+pub struct ListSpec {
+    pub table: &'static str,
+    // unique INTEGER column, tie-breaker for equal sort values
+    pub key: &'static str,
+    pub sort_fields: &'static [SortField],
+    pub default_sort: &'static str,
+}
+
+// Position after (or, with `back`, before) a row; opaque to clients
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    value: String,
+    key: i32,
+    #[serde(default)]
+    back: bool,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Result<Self, ApiError> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| ApiError::BadRequest("Invalid cursor".to_string()))
+    }
+}
+
+// Validated `page` (1 when missing) and the OFFSET of its first row
+pub fn page_offset(page: Option<i64>, limit: i64) -> Result<(i64, i64), ApiError> {
+    let page = page.unwrap_or(1);
+    if !(1..=MAX_PAGE).contains(&page) {
+        return Err(ApiError::BadRequest(format!("page must be between 1 and {}", MAX_PAGE)));
+    }
+    let offset = (page - 1)
+        .checked_mul(limit)
+        .ok_or_else(|| ApiError::BadRequest("page is out of range".to_string()))?;
+    Ok((page, offset))
+}
+
+fn parse_sort<'a>(spec: &'a ListSpec, sort: &str) -> Result<(&'a SortField, bool), ApiError> {
+    let (name, descending) = match sort.strip_prefix('-') {
+        Some(name) => (name, true),
+        None => (sort, false),
+    };
+
+    spec.sort_fields
+        .iter()
+        .find(|f| f.name == name)
+        .map(|f| (f, descending))
+        .ok_or_else(|| {
+            let allowed: Vec<_> = spec.sort_fields.iter().map(|f| f.name).collect();
+            ApiError::BadRequest(format!("Unknown sort field {}, allowed: {}", name, allowed.join(", ")))
+        })
+}
+
+// Runs the list query and renders the page. `filters` appends " AND ..." conditions,
+// it is called twice: for the rows and for the total count.
+pub async fn list<T>(
+    db: &Pool<Postgres>,
+    req: &HttpRequest,
+    spec: &ListSpec,
+    params: &PageParams,
+    filters: impl Fn(&mut QueryBuilder<'_, Postgres>),
+) -> Result<HttpResponse, ApiError>
+where
+    T: for<'r> FromRow<'r, PgRow> + Serialize,
+{
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!("limit must be between 1 and {}", MAX_LIMIT)));
+    }
+    if params.page.is_some() && params.cursor.is_some() {
+        return Err(ApiError::BadRequest("Use either page or cursor, not both".to_string()));
+    }
+    let (page, offset) = page_offset(params.page, limit)?;
+
+    let sort = params.sort.as_deref().unwrap_or(spec.default_sort);
+    let (field, descending) = parse_sort(spec, sort)?;
+
+    let cursor = params.cursor.as_deref().map(Cursor::decode).transpose()?;
+    if let Some(cursor) = &cursor
+        && cursor.sort != sort
+    {
+        return Err(ApiError::BadRequest("Cursor was issued for a different sort".to_string()));
+    }
+    let back = cursor.as_ref().is_some_and(|c| c.back);
+
+    // Total count, without the cursor condition
+    let mut count_query = QueryBuilder::new(format!("SELECT COUNT(*) FROM {} WHERE TRUE", spec.table));
+    filters(&mut count_query);
+    let total: i64 = count_query.build_query_scalar().fetch_one(db).await?;
+
+    // Rows: one extra row tells whether there is a next page
+    let mut query = QueryBuilder::new(format!(
+        "SELECT *, ({})::TEXT AS sort_value_ FROM {} WHERE TRUE",
+        field.expr, spec.table
+    ));
+    filters(&mut query);
+
+    // Walking backwards flips both the comparison and the order
+    let reversed = descending != back;
+    if let Some(cursor) = &cursor {
+        query.push(format!(" AND ({}, {}) {} (CAST(", field.expr, spec.key, if reversed { "<" } else { ">" }));
+        query.push_bind(cursor.value.clone());
+        query.push(format!(" AS {}), ", field.sql_type));
+        query.push_bind(cursor.key);
+        query.push(")");
+    }
+    let direction = if reversed { "DESC" } else { "ASC" };
+    query.push(format!(" ORDER BY {} {direction}, {} {direction} LIMIT ", field.expr, spec.key));
+    query.push_bind(limit + 1);
+    if params.page.is_some() {
+        query.push(" OFFSET ");
+        query.push_bind(offset);
+    }
+
+    let mut rows = query.build().fetch_all(db).await?;
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    if back {
+        rows.reverse();
+    }
+
+    let items = rows.iter().map(T::from_row).collect::<Result<Vec<_>, _>>()?;
+
+    let position = |row: &PgRow, back: bool| -> Result<String, ApiError> {
+        Ok(Cursor {
+            sort: sort.to_string(),
+            value: row.try_get("sort_value_")?,
+            key: row.try_get(spec.key)?,
+            back,
+        }
+            .encode())
+    };
+
+    let mut links = Vec::new();
+    if params.page.is_some() {
+        let last_page = ((total + limit - 1) / limit).max(1);
+        links.push((link(req, &[("page", "1".to_string())]), "first"));
+        if page > 1 {
+            links.push((link(req, &[("page", (page - 1).to_string())]), "prev"));
+        }
+        if has_more {
+            links.push((link(req, &[("page", (page + 1).to_string())]), "next"));
+        }
+        links.push((link(req, &[("page", last_page.to_string())]), "last"));
+    } else {
+        links.push((link(req, &[]), "first"));
+        if let (Some(first), Some(last)) = (rows.first(), rows.last()) {
+            // Coming back from a later page there is always a next one
+            if (back && has_more) || (!back && cursor.is_some()) {
+                links.push((link(req, &[("cursor", position(first, true)?)]), "prev"));
+            }
+            if has_more || back {
+                links.push((link(req, &[("cursor", position(last, false)?)]), "next"));
+            }
+        }
+    }
+
+    let link_header = links
+        .iter()
+        .map(|(url, rel)| format!("<{}>; rel=\"{}\"", url, rel))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Total-Count", total.to_string()))
+        .insert_header((header::LINK, link_header))
+        .json(items))
+}
+
+// Current URL with `page` / `cursor` replaced
+fn link(req: &HttpRequest, set: &[(&str, String)]) -> String {
+    let mut query: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+    query.retain(|(name, _)| name != "page" && name != "cursor");
+    query.extend(set.iter().map(|(name, value)| (name.to_string(), value.clone())));
+
+    match serde_urlencoded::to_string(&query) {
+        Ok(qs) if !qs.is_empty() => format!("{}?{}", req.path(), qs),
+        _ => req.path().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: ListSpec = ListSpec {
+        table: "things",
+        key: "thing_id",
+        sort_fields: &[
+            SortField { name: "name", expr: "name", sql_type: "TEXT" },
+            SortField { name: "price", expr: "price", sql_type: "NUMERIC" },
+        ],
+        default_sort: "name",
+    };
+
+    #[test]
+    fn parse_sort_ascending_and_descending() {
+        let (field, descending) = parse_sort(&SPEC, "price").unwrap();
+        assert_eq!((field.name, descending), ("price", false));
+
+        let (field, descending) = parse_sort(&SPEC, "-name").unwrap();
+        assert_eq!((field.name, descending), ("name", true));
+    }
+
+    #[test]
+    fn parse_sort_rejects_unknown_fields() {
+        for sort in ["password_hash", "--name", "", "name; DROP TABLE things"] {
+            match parse_sort(&SPEC, sort) {
+                Err(ApiError::BadRequest(detail)) => assert!(detail.ends_with("allowed: name, price"), "{}", detail),
+                other => panic!("{:?} for {}", other.map(|(f, d)| (f.name, d)), sort),
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor { sort: "-price".to_string(), value: "10.50".to_string(), key: 42, back: true };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+
+        assert_eq!(decoded.sort, "-price");
+        assert_eq!(decoded.value, "10.50");
+        assert_eq!(decoded.key, 42);
+        assert!(decoded.back);
+    }
+
+    #[test]
+    fn cursor_rejects_garbage() {
+        assert!(Cursor::decode("not a cursor").is_err());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode(b"{\"sort\": \"name\"}")).is_err());
+    }
+
+    #[test]
+    fn page_offset_bounds() {
+        assert_eq!(page_offset(None, 20).unwrap(), (1, 0));
+        assert_eq!(page_offset(Some(3), 20).unwrap(), (3, 40));
+        assert!(page_offset(Some(0), 20).is_err());
+        assert!(page_offset(Some(MAX_PAGE + 1), 20).is_err());
+        assert!(page_offset(Some(i64::MAX), 20).is_err());
+    }
+}
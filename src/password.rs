@@ -0,0 +1,172 @@
+// Password policy and Argon2id hashing.
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+use crate::error::ApiError;
+
+// Longer inputs only slow hashing down, nobody types them
+const MAX_LENGTH: usize = 128;
+
+// Configured with PASSWORD_MIN_LENGTH, PASSWORD_REQUIRE_DIGIT, PASSWORD_REQUIRE_LETTER,
+// PASSWORD_REQUIRE_UPPERCASE and PASSWORD_REQUIRE_SYMBOL
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_digit: bool,
+    pub require_letter: bool,
+    pub require_uppercase: bool,
+    pub require_symbol: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            require_digit: true,
+            require_letter: true,
+            require_uppercase: false,
+            require_symbol: false,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        let defaults = PasswordPolicy::default();
+        let flag = |name: &str, default: bool| {
+            std::env::var(name)
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(default)
+        };
+
+        PasswordPolicy {
+            min_length: std::env::var("PASSWORD_MIN_LENGTH")
+                .ok()
+                .map(|v| v.parse().expect("PASSWORD_MIN_LENGTH must be a number"))
+                .unwrap_or(defaults.min_length),
+            require_digit: flag("PASSWORD_REQUIRE_DIGIT", defaults.require_digit),
+            require_letter: flag("PASSWORD_REQUIRE_LETTER", defaults.require_letter),
+            require_uppercase: flag("PASSWORD_REQUIRE_UPPERCASE", defaults.require_uppercase),
+            require_symbol: flag("PASSWORD_REQUIRE_SYMBOL", defaults.require_symbol),
+        }
+    }
+
+    // All violations are reported at once. `email` is the account's own email,
+    // which is the first thing tried against it
+    pub fn validate(&self, password: &str, email: &str) -> Result<(), ApiError> {
+        let mut problems = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            problems.push(format!("be at least {} characters long", self.min_length));
+        }
+        if length > MAX_LENGTH {
+            problems.push(format!("be at most {} characters long", MAX_LENGTH));
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_numeric()) {
+            problems.push("contain a digit".to_string());
+        }
+        if self.require_letter && !password.chars().any(|c| c.is_alphabetic()) {
+            problems.push("contain a letter".to_string());
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            problems.push("contain an uppercase letter".to_string());
+        }
+        if self.require_symbol && !password.chars().any(|c| !c.is_alphanumeric()) {
+            problems.push("contain a symbol".to_string());
+        }
+        if password.trim().eq_ignore_ascii_case(email.trim()) {
+            problems.push("differ from the email".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Unprocessable {
+                code: "weak_password",
+                detail: format!("Password must {}", problems.join(", ")),
+            })
+        }
+    }
+}
+
+// Hash of a throwaway password with the default parameters. Logins for unknown
+// emails are verified against it, so they take as long as the ones for real users
+pub const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$umIX6IxOTN2nuKQSx7NwTQ$GY7VQifSyzqwmVH7OxW6+fxa0u6mMh9fNCryeLR1FW4";
+
+// PHC string: $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+pub fn hash_password(password: &str) -> Result<String, ApiError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ApiError::Internal(format!("password hashing failed: {}", e)))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(policy: &PasswordPolicy, password: &str) -> Option<String> {
+        match policy.validate(password, "jim@example.com") {
+            Ok(()) => None,
+            Err(ApiError::Unprocessable { code: "weak_password", detail }) => Some(detail),
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn default_policy_accepts_letters_and_digits() {
+        assert_eq!(problems(&PasswordPolicy::default(), "secret123"), None);
+    }
+
+    #[test]
+    fn min_length_counts_characters() {
+        let policy = PasswordPolicy { min_length: 8, ..PasswordPolicy::default() };
+        assert_eq!(problems(&policy, "abc1234").as_deref(), Some("Password must be at least 8 characters long"));
+        // 8 characters, 16 bytes
+        assert_eq!(problems(&policy, "пароль12"), None);
+    }
+
+    #[test]
+    fn required_classes_are_all_reported() {
+        let policy = PasswordPolicy {
+            min_length: 1,
+            require_digit: true,
+            require_letter: true,
+            require_uppercase: true,
+            require_symbol: true,
+        };
+        assert_eq!(
+            problems(&policy, "12345678").as_deref(),
+            Some("Password must contain a letter, contain an uppercase letter, contain a symbol")
+        );
+        assert_eq!(
+            problems(&policy, "abcdefgh").as_deref(),
+            Some("Password must contain a digit, contain an uppercase letter, contain a symbol")
+        );
+        assert_eq!(problems(&policy, "Abcdef1!"), None);
+    }
+
+    #[test]
+    fn password_equal_to_the_email_is_rejected() {
+        let policy = PasswordPolicy {
+            require_digit: false,
+            require_symbol: false,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(problems(&policy, "Jim@Example.com").as_deref(), Some("Password must differ from the email"));
+        assert_eq!(problems(&policy, "jim@example.org"), None);
+    }
+}
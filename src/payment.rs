@@ -0,0 +1,643 @@
+// Payments and refunds.
+//
+// orders.payment_status is owned by this module: it becomes `paid` / `failed` only
+// through a signed gateway callback and `partially_refunded` / `refunded` only
+// through a refund issued via the provider.
+//
+// An order has at most one charge in flight: a new payment can't be started while
+// another one is pending or has succeeded. A charge that still succeeds for an
+// order that is paid by then, or was cancelled meanwhile, is refunded right away.
+//
+// Money moves only outside of transactions. A charge or refund is first committed
+// as a pending row, then sent to the gateway with the row id as idempotency key,
+// then marked with the answer in a second transaction. A refund that was never
+// marked is sent again by spawn_refund_settlement, with the same key, so the
+// gateway doesn't pay it twice.
+use std::str::FromStr;
+use std::sync::Arc;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres};
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::money;
+use crate::order::{self, Order};
+use crate::order_status::OrderStatus;
+use crate::payment_provider::{ChargeRequest, PaymentProvider};
+use crate::pg_enum::impl_pg_string_enum;
+
+// Header carrying the HMAC signature of a gateway callback
+pub const SIGNATURE_HEADER: &str = "X-Payment-Signature";
+
+// Reasons of the refunds the webhook issues for charges the order didn't need.
+// They give a whole charge back and leave orders.payment_status alone
+pub const DUPLICATE_PAYMENT: &str = "Duplicate payment";
+pub const CANCELLED_ORDER_PAYMENT: &str = "Payment received for a cancelled order";
+
+// A charge without a gateway reference this old never reached the customer
+const CHARGE_TIMEOUT_SECONDS: i32 = 900;
+// Pending refunds this old were left behind by a handler that didn't finish
+const REFUND_SETTLE_SECONDS: u64 = 60;
+
+// orders.payment_status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    Unpaid,
+    Paid,
+    PartiallyRefunded,
+    Refunded,
+    Failed,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Unpaid => "unpaid",
+            PaymentStatus::Paid => "paid",
+            PaymentStatus::PartiallyRefunded => "partially_refunded",
+            PaymentStatus::Refunded => "refunded",
+            PaymentStatus::Failed => "failed",
+        }
+    }
+
+    // A new payment can be started only if nothing was collected yet
+    pub fn can_initiate(&self) -> bool {
+        matches!(self, PaymentStatus::Unpaid | PaymentStatus::Failed)
+    }
+
+    pub fn can_refund(&self) -> bool {
+        matches!(self, PaymentStatus::Paid | PaymentStatus::PartiallyRefunded)
+    }
+}
+
+impl FromStr for PaymentStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unpaid" => Ok(PaymentStatus::Unpaid),
+            "paid" => Ok(PaymentStatus::Paid),
+            "partially_refunded" => Ok(PaymentStatus::PartiallyRefunded),
+            "refunded" => Ok(PaymentStatus::Refunded),
+            "failed" => Ok(PaymentStatus::Failed),
+            other => Err(format!("unknown payment status: {}", other)),
+        }
+    }
+}
+
+impl_pg_string_enum!(PaymentStatus);
+
+// payments.status and refunds.status - state of a single charge or refund at the gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChargeStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+impl ChargeStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChargeStatus::Pending => "pending",
+            ChargeStatus::Succeeded => "succeeded",
+            ChargeStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for ChargeStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ChargeStatus::Pending),
+            "succeeded" => Ok(ChargeStatus::Succeeded),
+            "failed" => Ok(ChargeStatus::Failed),
+            other => Err(format!("unknown charge status: {}", other)),
+        }
+    }
+}
+
+impl_pg_string_enum!(ChargeStatus);
+
+// Data models
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Payment {
+    pub payment_id: i32,
+    pub order_id: i32,
+    pub provider: String,
+    pub provider_reference: Option<String>,
+    #[serde(with = "money")]
+    pub amount: Decimal,
+    pub status: ChargeStatus,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Refund {
+    pub refund_id: i32,
+    pub payment_id: i32,
+    #[serde(with = "money")]
+    pub amount: Decimal,
+    pub provider_reference: Option<String>,
+    pub reason: Option<String>,
+    pub status: ChargeStatus,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InitiatePaymentRequest {
+    // "card", "sbp", ...
+    pub payment_method: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InitiatePaymentResponse {
+    #[serde(flatten)]
+    pub payment: Payment,
+    pub redirect_url: Option<String>,
+}
+
+// Body of the gateway callback
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentCallback {
+    pub provider_reference: String,
+    pub status: ChargeStatus,
+    #[serde(with = "money")]
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RefundRequest {
+    // Full refund of what is left when omitted
+    #[serde(default, with = "money::option")]
+    pub amount: Option<Decimal>,
+    pub reason: Option<String>,
+}
+
+// Endpoint Callbacks
+// Start a payment for an order
+// curl -X POST http://localhost:8080/api/orders/1/payments \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"payment_method": "card"}'
+pub async fn initiate_payment(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    payment_req: web::Json<InitiatePaymentRequest>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order_id = path.into_inner();
+    order::ensure_order_access(&data.db, order_id, &auth).await?;
+    let mut tx = data.db.begin().await?;
+
+    let order = sqlx::query_as::<_, Order>("SELECT * FROM orders WHERE order_id = $1 FOR UPDATE")
+        .bind(order_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Order"))?;
+
+    if order.status == OrderStatus::Cancelled {
+        return Err(ApiError::Conflict {
+            code: "order_cancelled",
+            detail: "Cancelled orders can't be paid".to_string(),
+        });
+    }
+    if !order.payment_status.can_initiate() {
+        return Err(ApiError::Conflict {
+            code: "already_paid",
+            detail: format!("Order payment is already {}", order.payment_status),
+        });
+    }
+    // The customer never got the payment page of a charge that has no reference
+    // after this long, it can't be paid any more
+    sqlx::query(
+        "UPDATE payments SET status = 'failed'
+        WHERE order_id = $1 AND status = 'pending' AND provider_reference IS NULL
+          AND created_at < CURRENT_TIMESTAMP - $2 * INTERVAL '1 second'"
+    )
+        .bind(order_id)
+        .bind(CHARGE_TIMEOUT_SECONDS)
+        .execute(&mut *tx)
+        .await?;
+
+    // payment_status stays unpaid until the gateway calls back
+    let charge = sqlx::query_scalar::<_, ChargeStatus>(
+        "SELECT status FROM payments WHERE order_id = $1 AND status IN ('pending', 'succeeded') LIMIT 1"
+    )
+        .bind(order_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if let Some(charge) = charge {
+        return Err(ApiError::Conflict {
+            code: "payment_in_progress",
+            detail: format!("The order already has a {} payment", charge),
+        });
+    }
+
+    // The pending row holds the order's one charge in flight while the gateway is called
+    let method = payment_req.payment_method.as_deref().or(order.payment_method.as_deref());
+    let payment = sqlx::query_as::<_, Payment>(
+        "INSERT INTO payments (order_id, provider, amount) VALUES ($1, $2, $3) RETURNING *"
+    )
+        .bind(order_id)
+        .bind(data.payments.name())
+        .bind(order.total_amount)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query("UPDATE orders SET payment_method = $2 WHERE order_id = $1")
+        .bind(order_id)
+        .bind(method)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    let intent = data
+        .payments
+        .charge(&ChargeRequest {
+            idempotency_key: &format!("payment-{}", payment.payment_id),
+            order_number: &order.order_number,
+            amount: order.total_amount,
+            method,
+        })
+        .await;
+    let intent = match intent {
+        Ok(intent) => intent,
+        Err(e) => {
+            sqlx::query("UPDATE payments SET status = 'failed' WHERE payment_id = $1 AND status = 'pending'")
+                .bind(payment.payment_id)
+                .execute(&data.db)
+                .await?;
+            return Err(ApiError::BadGateway(e.to_string()));
+        }
+    };
+
+    let payment = sqlx::query_as::<_, Payment>(
+        "UPDATE payments SET provider_reference = $2 WHERE payment_id = $1 RETURNING *"
+    )
+        .bind(payment.payment_id)
+        .bind(&intent.provider_reference)
+        .fetch_one(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Created().json(InitiatePaymentResponse {
+        payment,
+        redirect_url: intent.redirect_url,
+    }))
+}
+
+// Gateway callback (webhook)
+// BODY='{"provider_reference": "mock_...", "status": "succeeded", "amount": "999.99"}'
+// SIG=$(printf '%s' "$BODY" | openssl dgst -sha256 -hmac "$PAYMENT_WEBHOOK_SECRET" | cut -d' ' -f2)
+// curl -X POST http://localhost:8080/api/payments/webhook \
+//   -H "Content-Type: application/json" -H "X-Payment-Signature: $SIG" -d "$BODY"
+pub async fn payment_webhook(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if !data.payments.verify_callback(&body, signature) {
+        return Err(ApiError::Unauthorized("Invalid payment callback signature".to_string()));
+    }
+
+    let callback: PaymentCallback = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+    let mut tx = data.db.begin().await?;
+
+    let order_id = sqlx::query_scalar::<_, i32>("SELECT order_id FROM payments WHERE provider_reference = $1")
+        .bind(&callback.provider_reference)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Payment"))?;
+
+    // Order before payment, as cancellation and refunds lock them
+    let (order_status, order_payment_status) = sqlx::query_as::<_, (OrderStatus, PaymentStatus)>(
+        "SELECT status, payment_status FROM orders WHERE order_id = $1 FOR UPDATE"
+    )
+        .bind(order_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let payment = sqlx::query_as::<_, Payment>(
+        "SELECT * FROM payments WHERE provider_reference = $1 FOR UPDATE"
+    )
+        .bind(&callback.provider_reference)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    // Gateways retry callbacks: a processed payment is acknowledged again, not changed
+    if payment.status != ChargeStatus::Pending {
+        return Ok(HttpResponse::Ok().json(payment));
+    }
+
+    if callback.status == ChargeStatus::Pending {
+        return Err(ApiError::validation("callback status must be succeeded or failed"));
+    }
+    if callback.status == ChargeStatus::Succeeded && callback.amount != payment.amount {
+        return Err(ApiError::Unprocessable {
+            code: "amount_mismatch",
+            detail: format!("Expected {}, gateway reported {}", payment.amount, callback.amount),
+        });
+    }
+
+    let payment = sqlx::query_as::<_, Payment>(
+        "UPDATE payments SET status = $2 WHERE payment_id = $1 RETURNING *"
+    )
+        .bind(payment.payment_id)
+        .bind(callback.status)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    // The money arrived, but the order doesn't need it any more: give it back
+    if callback.status == ChargeStatus::Succeeded
+        && (order_status == OrderStatus::Cancelled || !order_payment_status.can_initiate())
+    {
+        let reason = if order_status == OrderStatus::Cancelled {
+            CANCELLED_ORDER_PAYMENT
+        } else {
+            DUPLICATE_PAYMENT
+        };
+        let refund = insert_refund(&mut tx, payment.payment_id, payment.amount, Some(reason)).await?;
+
+        tx.commit().await?;
+
+        let reference = payment.provider_reference.as_deref().unwrap_or_default();
+        match settle_refund(&data.db, data.payments.as_ref(), refund.refund_id).await {
+            Ok(_) => eprintln!("⚠️ {} {}, refunded", reason, reference),
+            Err(e) => eprintln!("❌ {} {}, refund {} failed: {}", reason, reference, refund.refund_id, e),
+        }
+        return Ok(HttpResponse::Ok().json(payment));
+    }
+
+    let order_payment_status = match callback.status {
+        ChargeStatus::Succeeded => PaymentStatus::Paid,
+        _ => PaymentStatus::Failed,
+    };
+    // A late failure of an abandoned attempt must not override a successful payment
+    sqlx::query("UPDATE orders SET payment_status = $2 WHERE order_id = $1 AND payment_status IN ('unpaid', 'failed')")
+        .bind(payment.order_id)
+        .bind(order_payment_status)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(payment))
+}
+
+// Full or partial refund (staff only)
+// curl -X POST http://localhost:8080/api/orders/1/refunds \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"amount": "100.00", "reason": "damaged box"}'
+pub async fn refund_payment(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    refund_req: web::Json<RefundRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let order_id = path.into_inner();
+    let mut tx = data.db.begin().await?;
+
+    let refund = record_refund(&mut tx, order_id, refund_req.amount, refund_req.reason.as_deref()).await?;
+
+    tx.commit().await?;
+
+    let refund = settle_refund(&data.db, data.payments.as_ref(), refund.refund_id).await?;
+
+    Ok(HttpResponse::Created().json(refund))
+}
+
+// Payments of an order
+// curl http://localhost:8080/api/orders/1/payments -H "Authorization: Bearer $TOKEN"
+pub async fn get_order_payments(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let order_id = path.into_inner();
+    order::ensure_order_access(&data.db, order_id, &auth).await?;
+
+    let payments = sqlx::query_as::<_, Payment>(
+        "SELECT * FROM payments WHERE order_id = $1 ORDER BY created_at, payment_id"
+    )
+        .bind(order_id)
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(payments))
+}
+
+// Records a pending refund of `amount` (or everything not refunded yet) of the
+// charge that paid the order; settle_refund sends it once the caller has committed
+pub async fn record_refund(
+    conn: &mut PgConnection,
+    order_id: i32,
+    amount: Option<Decimal>,
+    reason: Option<&str>,
+) -> Result<Refund, ApiError> {
+    let payment_status = sqlx::query_scalar::<_, PaymentStatus>(
+        "SELECT payment_status FROM orders WHERE order_id = $1 FOR UPDATE"
+    )
+        .bind(order_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Order"))?;
+
+    if !payment_status.can_refund() {
+        return Err(ApiError::Conflict {
+            code: "not_refundable",
+            detail: format!("Order payment is {}", payment_status),
+        });
+    }
+
+    // Charges the webhook gave back are not the one that paid
+    let payment = sqlx::query_as::<_, Payment>(
+        "SELECT * FROM payments p WHERE order_id = $1 AND status = 'succeeded'
+            AND NOT EXISTS (SELECT 1 FROM refunds r WHERE r.payment_id = p.payment_id AND r.reason IN ($2, $3))
+        ORDER BY payment_id LIMIT 1 FOR UPDATE"
+    )
+        .bind(order_id)
+        .bind(DUPLICATE_PAYMENT)
+        .bind(CANCELLED_ORDER_PAYMENT)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    // Pending refunds are on their way, only failed ones gave nothing back
+    let refunded = sqlx::query_scalar::<_, Decimal>(
+        "SELECT COALESCE(SUM(amount), 0) FROM refunds WHERE payment_id = $1 AND status <> 'failed'"
+    )
+        .bind(payment.payment_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let refundable = payment.amount - refunded;
+    if refundable <= Decimal::ZERO {
+        return Err(ApiError::Conflict {
+            code: "refund_in_progress",
+            detail: "The rest of the payment is already being refunded".to_string(),
+        });
+    }
+    let amount = match amount {
+        Some(amount) => money::validate(amount, "amount")?,
+        None => refundable,
+    };
+    if amount <= Decimal::ZERO || amount > refundable {
+        return Err(ApiError::validation(format!(
+            "amount must be between 0.01 and {}",
+            refundable
+        )));
+    }
+
+    insert_refund(conn, payment.payment_id, amount, reason).await
+}
+
+async fn insert_refund(
+    conn: &mut PgConnection,
+    payment_id: i32,
+    amount: Decimal,
+    reason: Option<&str>,
+) -> Result<Refund, ApiError> {
+    let refund = sqlx::query_as::<_, Refund>(
+        "INSERT INTO refunds (payment_id, amount, reason) VALUES ($1, $2, $3) RETURNING *"
+    )
+        .bind(payment_id)
+        .bind(amount)
+        .bind(reason)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(refund)
+}
+
+// Sends a pending refund to the gateway and records the answer; a refund that is
+// no longer pending is returned as it is. The refund id is the idempotency key, so
+// settling the same refund again never pays it twice
+pub async fn settle_refund(
+    db: &Pool<Postgres>,
+    provider: &dyn PaymentProvider,
+    refund_id: i32,
+) -> Result<Refund, ApiError> {
+    let refund = sqlx::query_as::<_, Refund>("SELECT * FROM refunds WHERE refund_id = $1")
+        .bind(refund_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("Refund"))?;
+    if refund.status != ChargeStatus::Pending {
+        return Ok(refund);
+    }
+    let payment = sqlx::query_as::<_, Payment>("SELECT * FROM payments WHERE payment_id = $1")
+        .bind(refund.payment_id)
+        .fetch_one(db)
+        .await?;
+
+    let receipt = provider
+        .refund(
+            &format!("refund-{}", refund_id),
+            payment.provider_reference.as_deref().unwrap_or_default(),
+            refund.amount,
+        )
+        .await;
+
+    let mut tx = db.begin().await?;
+
+    // Order before refund, as record_refund locks them
+    sqlx::query("SELECT order_id FROM orders WHERE order_id = $1 FOR UPDATE")
+        .bind(payment.order_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let (status, provider_reference, error) = match receipt {
+        Ok(receipt) => (ChargeStatus::Succeeded, Some(receipt.provider_reference), None),
+        Err(e) => (ChargeStatus::Failed, None, Some(e)),
+    };
+    let settled = sqlx::query_as::<_, Refund>(
+        "UPDATE refunds SET status = $2, provider_reference = $3 WHERE refund_id = $1 AND status = 'pending' RETURNING *"
+    )
+        .bind(refund_id)
+        .bind(status)
+        .bind(&provider_reference)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    // Settled meanwhile by another call
+    let Some(refund) = settled else {
+        drop(tx);
+        return sqlx::query_as::<_, Refund>("SELECT * FROM refunds WHERE refund_id = $1")
+            .bind(refund_id)
+            .fetch_one(db)
+            .await
+            .map_err(ApiError::from);
+    };
+
+    let counts_for_order = !matches!(refund.reason.as_deref(), Some(DUPLICATE_PAYMENT | CANCELLED_ORDER_PAYMENT));
+    if status == ChargeStatus::Succeeded && counts_for_order {
+        // A cancelled order waits for its refund until it is refunded in full
+        sqlx::query(
+            "UPDATE orders o SET payment_status = s.status, refund_pending = o.refund_pending AND s.status <> 'refunded'
+            FROM (
+                SELECT CASE
+                    WHEN (SELECT SUM(amount) FROM refunds WHERE payment_id = $2 AND status = 'succeeded') >= $3 THEN 'refunded'
+                    ELSE 'partially_refunded'
+                END AS status
+            ) s
+            WHERE o.order_id = $1"
+        )
+            .bind(payment.order_id)
+            .bind(payment.payment_id)
+            .bind(payment.amount)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    match error {
+        Some(e) => Err(ApiError::BadGateway(e.to_string())),
+        None => Ok(refund),
+    }
+}
+
+// Every minute: settles the refunds a handler recorded but didn't get to settle
+pub fn spawn_refund_settlement(db: Pool<Postgres>, provider: Arc<dyn PaymentProvider>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(REFUND_SETTLE_SECONDS));
+        loop {
+            interval.tick().await;
+            let pending = sqlx::query_scalar::<_, i32>(
+                "SELECT refund_id FROM refunds
+                WHERE status = 'pending' AND created_at < CURRENT_TIMESTAMP - $1 * INTERVAL '1 second'
+                ORDER BY refund_id"
+            )
+                .bind(REFUND_SETTLE_SECONDS as i32)
+                .fetch_all(&db)
+                .await;
+            let refund_ids = match pending {
+                Ok(refund_ids) => refund_ids,
+                Err(e) => {
+                    eprintln!("❌ Failed to load pending refunds: {}", e);
+                    continue;
+                }
+            };
+            for refund_id in refund_ids {
+                if let Err(e) = settle_refund(&db, provider.as_ref(), refund_id).await {
+                    eprintln!("❌ Refund {} failed: {}", refund_id, e);
+                }
+            }
+        }
+    });
+}
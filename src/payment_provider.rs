@@ -0,0 +1,157 @@
+// Payment provider abstraction.
+//
+// A provider creates a payment at the gateway, issues refunds and verifies the
+// signature of the callbacks (webhooks) the gateway sends back. The only built-in
+// implementation is `MockGateway`, an in-process gateway for development.
+//
+// Charges and refunds carry an idempotency key (the id of the row recorded before
+// the call): a call repeated with the same key returns the first answer and
+// moves no money again.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use rust_decimal::Decimal;
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// What the gateway needs to know to charge an order
+#[derive(Debug)]
+pub struct ChargeRequest<'a> {
+    pub idempotency_key: &'a str,
+    pub order_number: &'a str,
+    pub amount: Decimal,
+    pub method: Option<&'a str>,
+}
+
+// Gateway's answer to a new charge
+#[derive(Debug)]
+pub struct ChargeIntent {
+    // Gateway id of the payment, used to match callbacks
+    pub provider_reference: String,
+    // Page where the customer completes the payment, if the gateway has one
+    pub redirect_url: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RefundReceipt {
+    pub provider_reference: String,
+}
+
+#[derive(Debug)]
+pub struct ProviderError(pub String);
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "payment provider error: {}", self.0)
+    }
+}
+
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    // Stored in payments.provider
+    fn name(&self) -> &'static str;
+
+    async fn charge(&self, request: &ChargeRequest<'_>) -> Result<ChargeIntent, ProviderError>;
+
+    async fn refund(
+        &self,
+        idempotency_key: &str,
+        provider_reference: &str,
+        amount: Decimal,
+    ) -> Result<RefundReceipt, ProviderError>;
+
+    // Check the signature header of a callback against its raw body
+    fn verify_callback(&self, body: &[u8], signature: &str) -> bool;
+}
+
+// In-process gateway: charges by "card" or "sbp" are accepted and wait for a callback,
+// every refund succeeds. Callbacks are signed with HMAC-SHA256 (hex) of the body.
+pub struct MockGateway {
+    webhook_secret: String,
+    // idempotency key -> provider reference of the charge or refund it created
+    seen: Mutex<HashMap<String, String>>,
+}
+
+impl MockGateway {
+    pub fn new(webhook_secret: impl Into<String>) -> Self {
+        MockGateway {
+            webhook_secret: webhook_secret.into(),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn reference(&self, idempotency_key: &str, prefix: &str) -> String {
+        self.seen
+            .lock()
+            .expect("mock gateway lock")
+            .entry(idempotency_key.to_string())
+            .or_insert_with(|| format!("{}_{}", prefix, Uuid::new_v4().simple()))
+            .clone()
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for MockGateway {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn charge(&self, request: &ChargeRequest<'_>) -> Result<ChargeIntent, ProviderError> {
+        if let Some(method) = request.method
+            && !matches!(method, "card" | "sbp")
+        {
+            return Err(ProviderError(format!("unsupported payment method: {}", method)));
+        }
+
+        let reference = self.reference(request.idempotency_key, "mock");
+        Ok(ChargeIntent {
+            redirect_url: Some(format!(
+                "https://mock-gateway.local/pay/{}?order={}&amount={}",
+                reference, request.order_number, request.amount
+            )),
+            provider_reference: reference,
+        })
+    }
+
+    async fn refund(
+        &self,
+        idempotency_key: &str,
+        _provider_reference: &str,
+        amount: Decimal,
+    ) -> Result<RefundReceipt, ProviderError> {
+        if amount <= Decimal::ZERO {
+            return Err(ProviderError("refund amount must be positive".to_string()));
+        }
+        Ok(RefundReceipt {
+            provider_reference: self.reference(idempotency_key, "mock_refund"),
+        })
+    }
+
+    fn verify_callback(&self, body: &[u8], signature: &str) -> bool {
+        let Ok(expected) = hex::decode(signature.trim()) else {
+            return false;
+        };
+        let mut mac = HmacSha256::new_from_slice(self.webhook_secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(body);
+        // constant-time comparison
+        mac.verify_slice(&expected).is_ok()
+    }
+}
+
+// PAYMENT_PROVIDER selects the implementation (only "mock" for now),
+// PAYMENT_WEBHOOK_SECRET is the shared secret for callback signatures
+pub fn from_env() -> Arc<dyn PaymentProvider> {
+    let provider = std::env::var("PAYMENT_PROVIDER").unwrap_or_else(|_| "mock".to_string());
+    let secret = std::env::var("PAYMENT_WEBHOOK_SECRET").unwrap_or_else(|_| "mock-webhook-secret".to_string());
+
+    match provider.as_str() {
+        "mock" => Arc::new(MockGateway::new(secret)),
+        other => panic!("Unknown PAYMENT_PROVIDER: {}", other),
+    }
+}
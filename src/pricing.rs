@@ -0,0 +1,263 @@
+// Product pricing.
+//
+// A product has a regular price and optionally a sale price with a window
+// (sale_starts_at / sale_ends_at, either end may be open). products.price is the
+// price charged right now and is derived by the database (product_price_at in
+// migration 0018): a trigger recomputes it on every write and the scheduler below
+// flips it when a sale window opens or closes.
+//
+// Every change of the price fields is recorded in product_price_history, with the
+// user who made it (app.user_id, see set_changed_by). GET /products/{id}/price
+// replays that history to tell what a product cost at any point in time.
+use actix_web::{web, HttpRequest, HttpResponse};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres};
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::money;
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::product::Product;
+
+// The price of a products row at this moment, for queries that price lines.
+// Unlike products.price it is exact at a sale boundary, before the scheduler runs
+pub(crate) const CURRENT_PRICE: &str =
+    "product_price_at(regular_price, sale_price, sale_starts_at, sale_ends_at, CURRENT_TIMESTAMP)";
+
+const SCHEDULER_INTERVAL_SECONDS: u64 = 60;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PriceHistory {
+    pub price_history_id: i32,
+    pub product_id: i32,
+    // price charged right after the change
+    #[serde(with = "money")]
+    pub price: Decimal,
+    #[serde(with = "money")]
+    pub regular_price: Decimal,
+    #[serde(with = "money::option")]
+    pub sale_price: Option<Decimal>,
+    pub sale_starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub sale_ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    // None for scheduled changes
+    pub changed_by: Option<i32>,
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Answer of GET /products/{id}/price
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PriceAt {
+    pub product_id: i32,
+    pub at: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "money")]
+    pub price: Decimal,
+    pub on_sale: bool,
+    // the history entry in force at `at`
+    #[serde(with = "money")]
+    pub regular_price: Decimal,
+    #[serde(with = "money::option")]
+    pub sale_price: Option<Decimal>,
+    pub sale_starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub sale_ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub changed_by: Option<i32>,
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Body of PUT /products/{id}/sale
+#[derive(Debug, Deserialize)]
+pub struct SaleRequest {
+    #[serde(with = "money")]
+    pub sale_price: Decimal,
+    // open ended when missing: from now on, until cleared
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// GET /products/{id}/price query, RFC 3339; now when missing
+#[derive(Debug, Deserialize)]
+pub struct PriceAtQuery {
+    pub at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+const PRICE_HISTORY_LIST: ListSpec = ListSpec {
+    table: "product_price_history",
+    key: "price_history_id",
+    sort_fields: &[
+        SortField { name: "changed_at", expr: "changed_at", sql_type: "TIMESTAMPTZ" },
+    ],
+    default_sort: "-changed_at",
+};
+
+// Checks a sale against the regular price; a sale price without a sale is rejected
+pub fn validate_sale(
+    regular_price: Decimal,
+    sale_price: Option<Decimal>,
+    starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    ends_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Option<Decimal>, ApiError> {
+    let Some(sale_price) = sale_price else {
+        if starts_at.is_some() || ends_at.is_some() {
+            return Err(ApiError::validation("sale_starts_at and sale_ends_at require a sale_price"));
+        }
+        return Ok(None);
+    };
+
+    let sale_price = money::validate(sale_price, "sale_price")?;
+    if sale_price >= regular_price {
+        return Err(ApiError::validation("sale_price must be below regular_price"));
+    }
+    if let (Some(starts_at), Some(ends_at)) = (starts_at, ends_at)
+        && ends_at <= starts_at
+    {
+        return Err(ApiError::validation("sale_ends_at must be after sale_starts_at"));
+    }
+    Ok(Some(sale_price))
+}
+
+// Records `user_id` as the author of the price changes made in the current transaction
+pub async fn set_changed_by(conn: &mut PgConnection, user_id: Option<i32>) -> Result<(), ApiError> {
+    sqlx::query("SELECT set_config('app.user_id', $1, TRUE)")
+        .bind(user_id.map(|id| id.to_string()).unwrap_or_default())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// Every minute: starts and ends the sales whose window boundary has passed
+pub fn spawn_scheduler(db: Pool<Postgres>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(SCHEDULER_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            // The set_product_price trigger computes the new price
+            let result = sqlx::query(&format!(
+                "UPDATE products SET price = {0} WHERE price IS DISTINCT FROM {0}",
+                CURRENT_PRICE
+            ))
+                .execute(&db)
+                .await;
+            match result {
+                Ok(done) if done.rows_affected() > 0 => {
+                    println!("🏷️  Repriced {} products", done.rows_affected());
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("❌ Failed to apply scheduled prices: {}", e),
+            }
+        }
+    });
+}
+
+// Endpoint Callbacks
+// Schedule a sale (staff only), replacing the current one
+// curl -X PUT http://localhost:8080/api/products/1/sale \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"sale_price": "799.00", "starts_at": "2026-11-27T00:00:00Z", "ends_at": "2026-11-30T23:59:59Z"}'
+pub async fn set_sale(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    sale_req: web::Json<SaleRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let mut tx = data.db.begin().await?;
+
+    let regular_price = sqlx::query_scalar::<_, Decimal>(
+        "SELECT regular_price FROM products WHERE product_id = $1 AND deleted_at IS NULL FOR UPDATE"
+    )
+        .bind(product_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    let sale_price = validate_sale(regular_price, Some(sale_req.sale_price), sale_req.starts_at, sale_req.ends_at)?;
+
+    set_changed_by(&mut tx, auth.map(|a| a.user_id)).await?;
+
+    let product = sqlx::query_as::<_, Product>(
+        "UPDATE products SET sale_price = $1, sale_starts_at = $2, sale_ends_at = $3 WHERE product_id = $4 RETURNING *"
+    )
+        .bind(sale_price)
+        .bind(sale_req.starts_at)
+        .bind(sale_req.ends_at)
+        .bind(product_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(product))
+}
+
+// End the sale now, or cancel a scheduled one (staff only)
+// curl -X DELETE http://localhost:8080/api/products/1/sale -H "Authorization: Bearer $TOKEN"
+pub async fn delete_sale(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let mut tx = data.db.begin().await?;
+
+    set_changed_by(&mut tx, auth.map(|a| a.user_id)).await?;
+
+    let product = sqlx::query_as::<_, Product>(
+        "UPDATE products SET sale_price = NULL, sale_starts_at = NULL, sale_ends_at = NULL WHERE product_id = $1 AND deleted_at IS NULL RETURNING *"
+    )
+        .bind(product_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(product))
+}
+
+// Price of a product at a point in time (staff only), e.g. to settle a dispute
+// about an order. Deleted products are included
+// curl "http://localhost:8080/api/products/1/price?at=2026-11-28T12:00:00Z" -H "Authorization: Bearer $TOKEN"
+pub async fn get_price_at(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    query: web::Query<PriceAtQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let at = query.at.unwrap_or_else(chrono::Utc::now);
+
+    // The last change made before `at`, with its sale window evaluated at `at`
+    let price = sqlx::query_as::<_, PriceAt>(
+        "SELECT product_id, $2::TIMESTAMPTZ AS at,
+                product_price_at(regular_price, sale_price, sale_starts_at, sale_ends_at, $2) AS price,
+                product_price_at(regular_price, sale_price, sale_starts_at, sale_ends_at, $2) IS DISTINCT FROM regular_price AS on_sale,
+                regular_price, sale_price, sale_starts_at, sale_ends_at, changed_by, changed_at
+        FROM product_price_history
+        WHERE product_id = $1 AND changed_at <= $2
+        ORDER BY changed_at DESC, price_history_id DESC
+        LIMIT 1"
+    )
+        .bind(product_id)
+        .bind(at)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Price at this time"))?;
+
+    Ok(HttpResponse::Ok().json(price))
+}
+
+// Every price change of a product (staff only)
+// curl "http://localhost:8080/api/products/1/price-history?sort=-changed_at" -H "Authorization: Bearer $TOKEN"
+pub async fn get_price_history(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    page: web::Query<PageParams>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+
+    pagination::list::<PriceHistory>(&data.db, &req, &PRICE_HISTORY_LIST, &page, |q| {
+        q.push(" AND product_id = ").push_bind(product_id);
+    })
+        .await
+}
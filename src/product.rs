@@ -1,41 +1,50 @@
-use std::str::FromStr;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Deserializer, Serialize};
-use uuid::Uuid;
+use sqlx::{Postgres, QueryBuilder};
 
-use rust_decimal::Decimal;
-use rust_decimal::prelude::ToPrimitive;
 use crate::AppState;
-use crate::user::User;
-// use sqlx::types::Decimal;
+use crate::auth::AuthenticatedUser;
+use crate::category;
+use crate::error::ApiError;
+use crate::inventory::{self, MovementType, NewMovement};
+use crate::money;
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::pricing;
+use crate::tax;
 
 
 // Data models
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Product {
     // id: Uuid, // TODO
-    // product_id: i64, // TODO
-    // product_id: i64, // TODO
+    pub product_id: i32,
     pub name: String,
-    pub description: String,
+    // nullable columns
+    pub description: Option<String>,
     pub sku: String,
 
+    // NUMERIC(10, 2) <-> Decimal, no float casts
+    // price charged now: the sale price during a sale, the regular price otherwise.
+    // Maintained by the database, see pricing.rs
+    #[serde(with = "money")]
+    pub price: Decimal,
+    #[serde(with = "money")]
+    pub regular_price: Decimal,
+    #[serde(with = "money::option")]
+    pub sale_price: Option<Decimal>,
+    pub sale_starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub sale_ends_at: Option<chrono::DateTime<chrono::Utc>>,
 
-     // #[serde(deserialize_with = "deserialize_number_to_f64")]
+    // quantity on hand, changed through inventory.rs
+    pub stock_quantity: i32,
 
-    /*
-     #[sqlx(try_from = "i32")]
-     pub price: f64, // use sqlx::types::Decimal?
-     */
 
-
-    #[sqlx(try_from = "i32")]
-    pub stock_quantity: i64,
-
-
-    #[sqlx(try_from = "i32")]
-    pub category_id: i16,
-    pub image_url: String,
+    // see category.rs
+    pub category_id: Option<i32>,
+    pub image_url: Option<String>,
+    // picks the tax rate, see tax.rs
+    pub tax_class: String,
 
     // #[sqlx(try_from = "NaiveDateTime")]
     created_at: Option<chrono::DateTime<chrono::Utc>>,
@@ -43,106 +52,370 @@ pub struct Product {
     // #[sqlx(try_from = "NaiveDateTime")]
     updated_at: Option<chrono::DateTime<chrono::Utc>>,
     is_available: bool,
-    // deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Soft delete: set by DELETE, cleared by restore
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 // TODO Requests ...
 
+// Body of POST and PUT (full replacement)
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateProductRequest {
     pub name: String,
     pub description: String,
     pub sku: String,
 
-    // #[serde(deserialize_with = "deserialize_number_to_f64")]
-    // pub current_price: f64,
-    //
-    // #[serde(deserialize_with = "deserialize_number_to_f64")]
-    // pub regular_price: f64,
-    #[serde(deserialize_with = "deserialize_number_to_f64")]
-    pub price: f64, // use sqlx::types::Decimal?
-    pub category_id: i16,
+    // `price` is still accepted for older clients
+    #[serde(alias = "price", with = "money")]
+    pub regular_price: Decimal,
+    // optional sale, see pricing.rs; PUT without them ends the current sale
+    #[serde(default, with = "money::option")]
+    pub sale_price: Option<Decimal>,
+    pub sale_starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub sale_ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub stock_quantity: i32,
+    pub category_id: Option<i32>,
     pub image_url: String,
     is_available: bool,
+    #[serde(default = "default_tax_class")]
+    pub tax_class: String,
+}
+
+fn default_tax_class() -> String {
+    tax::DEFAULT_TAX_CLASS.to_string()
+}
+
+// Body of PATCH: only the fields that are present are changed. The nullable
+// columns are Option<Option<_>>: absent is None, null is Some(None) and clears them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatchProductRequest {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub description: Option<Option<String>>,
+    pub sku: Option<String>,
+    // the sale is changed through PUT/DELETE /products/{id}/sale
+    #[serde(default, alias = "price", with = "money::option")]
+    pub regular_price: Option<Decimal>,
+    pub stock_quantity: Option<i32>,
+    #[serde(default, deserialize_with = "present")]
+    pub category_id: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub image_url: Option<Option<String>>,
+    pub is_available: Option<bool>,
+    pub tax_class: Option<String>,
+}
+
+// Wraps a present field, null included, in Some; with `default` a missing one stays None
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// GET /api/products filters
+#[derive(Debug, Deserialize)]
+pub struct ProductFilter {
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+    // includes subcategories
+    pub category_id: Option<i32>,
+    pub is_available: Option<bool>,
+    pub in_stock: Option<bool>,
+}
+
+pub(crate) const PRODUCT_LIST: ListSpec = ListSpec {
+    table: "products",
+    key: "product_id",
+    sort_fields: &[
+        SortField { name: "created_at", expr: "COALESCE(created_at, 'epoch')", sql_type: "TIMESTAMPTZ" },
+        SortField { name: "name", expr: "name", sql_type: "TEXT" },
+        SortField { name: "price", expr: "price", sql_type: "NUMERIC" },
+        SortField { name: "stock_quantity", expr: "stock_quantity", sql_type: "INTEGER" },
+    ],
+    default_sort: "-created_at",
+};
+
+fn validate_stock(stock_quantity: i32) -> Result<i32, ApiError> {
+    if stock_quantity < 0 {
+        return Err(ApiError::validation("stock_quantity must not be negative"));
+    }
+    Ok(stock_quantity)
 }
 
 
 // Endpoint Callbacks
 // Create Product
 // curl -X POST http://localhost:8080/api/products \
-//   -H "Content-Type: application/json" \
-//   -d '{"name": "Iphone", "sku": "iphone1234567tt", "description": "best phone(", "price": 1000, "category_id": 1, "image_url": "https://images/1.webp", "is_available": true}'
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"name": "Iphone", "sku": "iphone1234567tt", "description": "best phone(", "regular_price": "1000.00", "stock_quantity": 10, "category_id": 1, "image_url": "https://images/1.webp", "is_available": true}'
 
 pub(crate) async fn create_product(
     data: web::Data<AppState>,
     product_req: web::Json<CreateProductRequest>,
-) -> actix_web::Result<HttpResponse> {
-    match sqlx::query_as::<_, Product>(
-        "INSERT INTO products (name, sku, description, price, category_id, image_url, is_available) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *"
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let regular_price = money::validate(product_req.regular_price, "regular_price")?;
+    let sale_price = pricing::validate_sale(regular_price, product_req.sale_price, product_req.sale_starts_at, product_req.sale_ends_at)?;
+    let stock_quantity = validate_stock(product_req.stock_quantity)?;
+    let tax_class = tax::validate_tax_class(&product_req.tax_class)?;
+    let created_by = auth.map(|a| a.user_id);
+    let mut tx = data.db.begin().await?;
+
+    pricing::set_changed_by(&mut tx, created_by).await?;
+
+    // Duplicate sku is mapped to 409 by ApiError; price is set by the database
+    let product = sqlx::query_as::<_, Product>(
+        "INSERT INTO products (name, sku, description, price, regular_price, sale_price, sale_starts_at, sale_ends_at, stock_quantity, category_id, image_url, is_available, tax_class) VALUES ($1, $2, $3, $4, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING *"
     )
         .bind(&product_req.name)
         .bind(&product_req.sku)
         .bind(&product_req.description)
-        .bind(&product_req.price)
-        // .bind(&product_req.regular_price)
-        .bind(&product_req.category_id)
+        .bind(regular_price)
+        .bind(sale_price)
+        .bind(product_req.sale_starts_at)
+        .bind(product_req.sale_ends_at)
+        .bind(stock_quantity)
+        .bind(product_req.category_id)
         .bind(&product_req.image_url)
-        .bind(&product_req.is_available)
-        .fetch_one(&data.db)
-        .await
-    {
-        Ok(product) => Ok(HttpResponse::Created().json(product)),
-        Err(e) => {
-            // Handle unique constraint violation
-            if e.to_string().contains("unique constraint") {
-                Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "Sku already exists"
-                })))
-            } else {
-                Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": e.to_string()
-                })))
-            }
-        }
+        .bind(product_req.is_available)
+        .bind(tax_class)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    // Initial stock is the first receipt in the ledger
+    if stock_quantity > 0 {
+        inventory::log_movement(&mut tx, &NewMovement {
+            product_id: product.product_id,
+            movement_type: MovementType::Receipt,
+            quantity: stock_quantity,
+            order_id: None,
+            created_by,
+            comment: Some("Initial stock"),
+        }, stock_quantity)
+            .await?;
     }
-}
 
+    tx.commit().await?;
 
-// curl http://localhost:8080/api/products
-pub async fn get_products(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Created().json(product))
+}
 
-    // SELECT amount::FLOAT8
 
-    // match sqlx::query_as::<_, Product>("SELECT * FROM products ORDER BY created_at DESC")
-    match sqlx::query_as::<_, Product>("SELECT product_id, name, sku, description, price::FLOAT8, category_id::INT2, image_url, created_at, updated_at, is_available FROM products ORDER BY created_at DESC")
-        .fetch_all(&data.db)
+// Deleted products are not listed
+// curl "http://localhost:8080/api/products?min_price=100&max_price=2000&category_id=1&is_available=true&sort=-price&page=1&limit=20"
+pub async fn get_products(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    filter: web::Query<ProductFilter>,
+) -> Result<HttpResponse, ApiError> {
+    let category_ids = match filter.category_id {
+        Some(category_id) => Some(category::descendant_ids(&data.db, category_id).await?),
+        None => None,
+    };
+
+    pagination::list::<Product>(&data.db, &req, &PRODUCT_LIST, &page, |q| {
+        q.push(" AND deleted_at IS NULL");
+        if let Some(min_price) = filter.min_price {
+            q.push(" AND price >= ").push_bind(min_price);
+        }
+        if let Some(max_price) = filter.max_price {
+            q.push(" AND price <= ").push_bind(max_price);
+        }
+        if let Some(ids) = &category_ids {
+            q.push(" AND category_id = ANY(").push_bind(ids.clone()).push(")");
+        }
+        if let Some(is_available) = filter.is_available {
+            q.push(" AND is_available = ").push_bind(is_available);
+        }
+        match filter.in_stock {
+            Some(true) => q.push(" AND stock_quantity > 0"),
+            Some(false) => q.push(" AND stock_quantity = 0"),
+            None => q,
+        };
+    })
         .await
-    {
-        Ok(products) => Ok(HttpResponse::Ok().json(products)),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e.to_string()
-        }))),
+}
+
+// Get product by ID
+// curl http://localhost:8080/api/products/1
+pub async fn get_product(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+
+    let product = sqlx::query_as::<_, Product>("SELECT * FROM products WHERE product_id = $1 AND deleted_at IS NULL")
+        .bind(product_id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    Ok(HttpResponse::Ok().json(product))
+}
+
+// Replace product (staff only)
+// curl -X PUT http://localhost:8080/api/products/1 \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"name": "Iphone", "sku": "iphone1234567tt", "description": "best phone", "regular_price": "1000.00", "sale_price": "900.00", "sale_ends_at": "2026-12-01T00:00:00Z", "stock_quantity": 5, "category_id": 1, "image_url": "https://images/1.webp", "is_available": true}'
+pub async fn update_product(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    product_req: web::Json<CreateProductRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let regular_price = money::validate(product_req.regular_price, "regular_price")?;
+    let sale_price = pricing::validate_sale(regular_price, product_req.sale_price, product_req.sale_starts_at, product_req.sale_ends_at)?;
+    let stock_quantity = validate_stock(product_req.stock_quantity)?;
+    let tax_class = tax::validate_tax_class(&product_req.tax_class)?;
+    let updated_by = auth.map(|a| a.user_id);
+    let mut tx = data.db.begin().await?;
+
+    // A different stock_quantity is recorded as an adjustment
+    inventory::set_on_hand(&mut tx, product_id, stock_quantity, updated_by).await?;
+    pricing::set_changed_by(&mut tx, updated_by).await?;
+
+    let product = sqlx::query_as::<_, Product>(
+        "UPDATE products SET name = $1, sku = $2, description = $3, regular_price = $4, sale_price = $5, sale_starts_at = $6, sale_ends_at = $7, category_id = $8, image_url = $9, is_available = $10, tax_class = $11 WHERE product_id = $12 AND deleted_at IS NULL RETURNING *"
+    )
+        .bind(&product_req.name)
+        .bind(&product_req.sku)
+        .bind(&product_req.description)
+        .bind(regular_price)
+        .bind(sale_price)
+        .bind(product_req.sale_starts_at)
+        .bind(product_req.sale_ends_at)
+        .bind(product_req.category_id)
+        .bind(&product_req.image_url)
+        .bind(product_req.is_available)
+        .bind(tax_class)
+        .bind(product_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(product))
+}
+
+// Partial update (staff only)
+// curl -X PATCH http://localhost:8080/api/products/1 \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"regular_price": "899.99", "stock_quantity": 3, "image_url": null}'
+pub async fn patch_product(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    patch_req: web::Json<PatchProductRequest>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let regular_price = patch_req.regular_price.map(|p| money::validate(p, "regular_price")).transpose()?;
+    let stock_quantity = patch_req.stock_quantity.map(validate_stock).transpose()?;
+    let tax_class = patch_req.tax_class.as_deref().map(tax::validate_tax_class).transpose()?;
+    let updated_by = auth.map(|a| a.user_id);
+    let mut tx = data.db.begin().await?;
+
+    if let Some(stock_quantity) = stock_quantity {
+        inventory::set_on_hand(&mut tx, product_id, stock_quantity, updated_by).await?;
+    }
+    pricing::set_changed_by(&mut tx, updated_by).await?;
+
+    // SET lists only the fields that are present; with none it is a no-op update.
+    // A regular_price at or below the current sale_price is a 422 from ApiError
+    let mut q = QueryBuilder::<Postgres>::new("UPDATE products SET product_id = product_id");
+    if let Some(name) = &patch_req.name {
+        q.push(", name = ").push_bind(name);
+    }
+    if let Some(sku) = &patch_req.sku {
+        q.push(", sku = ").push_bind(sku);
+    }
+    if let Some(description) = &patch_req.description {
+        q.push(", description = ").push_bind(description);
+    }
+    if let Some(regular_price) = regular_price {
+        q.push(", regular_price = ").push_bind(regular_price);
+    }
+    if let Some(category_id) = patch_req.category_id {
+        q.push(", category_id = ").push_bind(category_id);
+    }
+    if let Some(image_url) = &patch_req.image_url {
+        q.push(", image_url = ").push_bind(image_url);
+    }
+    if let Some(is_available) = patch_req.is_available {
+        q.push(", is_available = ").push_bind(is_available);
     }
+    if let Some(tax_class) = tax_class {
+        q.push(", tax_class = ").push_bind(tax_class);
+    }
+    q.push(" WHERE product_id = ").push_bind(product_id).push(" AND deleted_at IS NULL RETURNING *");
+
+    let product = q
+        .build_query_as::<Product>()
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(product))
 }
 
+// Soft delete (staff only): order_items keep referencing the row
+// curl -X DELETE http://localhost:8080/api/products/1 -H "Authorization: Bearer $TOKEN"
+pub async fn delete_product(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
 
-// Вариант 1: Если Decimal передается как строка
-// fn deserialize_decimal_to_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
-// where
-//     D: Deserializer<'de>,
-// {
-//     // Пробуем десериализовать как строку
-//     let s = String::deserialize(deserializer)?;
-//     Decimal::from_str(&s)
-//         .map(|d| d.to_f64().unwrap_or(0.0))
-//         .map_err(serde::de::Error::custom)
-// }
+    let result = sqlx::query("UPDATE products SET deleted_at = CURRENT_TIMESTAMP WHERE product_id = $1 AND deleted_at IS NULL")
+        .bind(product_id)
+        .execute(&data.db)
+        .await?;
 
-fn deserialize_number_to_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    // Пробуем как f64 напрямую
-    let num = f64::deserialize(deserializer)?;
-    Ok(num)
-}
\ No newline at end of file
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Product"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Restore a deleted product (admin only)
+// curl -X POST http://localhost:8080/api/products/1/restore -H "Authorization: Bearer $TOKEN"
+pub async fn restore_product(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+
+    // The sku may have been reused meanwhile, that is a 409 from ApiError
+    let product = sqlx::query_as::<_, Product>(
+        "UPDATE products SET deleted_at = NULL WHERE product_id = $1 AND deleted_at IS NOT NULL RETURNING *"
+    )
+        .bind(product_id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Deleted product"))?;
+
+    Ok(HttpResponse::Ok().json(product))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_tells_null_from_absent() {
+        let patch: PatchProductRequest =
+            serde_json::from_str(r#"{"description": null, "category_id": 3}"#).unwrap();
+        assert_eq!(patch.description, Some(None));
+        assert_eq!(patch.category_id, Some(Some(3)));
+        assert_eq!(patch.image_url, None);
+    }
+}
@@ -0,0 +1,533 @@
+// Product images.
+//
+// Uploaded as multipart/form-data, checked by content (not only by the declared
+// type), stored through `Storage` together with a few thumbnail sizes. A product
+// has any number of images ordered by `position`; the first one is mirrored into
+// products.image_url, which is what the frontend shows in lists.
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use actix_multipart::Multipart;
+use actix_web::{web, HttpResponse};
+use futures_util::TryStreamExt;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::PgConnection;
+
+use crate::AppState;
+use crate::error::ApiError;
+use crate::storage::Storage;
+
+// Longest side of each thumbnail; images are never upscaled
+const THUMBNAIL_SIZES: &[(&str, u32)] = &[("small", 150), ("medium", 400), ("large", 800)];
+const ALLOWED_FORMATS: &[ImageFormat] = &[ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP, ImageFormat::Gif];
+// Decompression bombs: a tiny file can declare a huge canvas
+const MAX_DIMENSION: u32 = 10_000;
+const MAX_ALT_TEXT: usize = 255;
+
+// UPLOAD_MAX_BYTES (per file, default 5 MiB) and UPLOAD_MAX_FILES (per request, default 10)
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    pub max_bytes: usize,
+    pub max_files: usize,
+}
+
+impl UploadConfig {
+    pub fn from_env() -> Self {
+        let number = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("{} must be a number", name)))
+                .unwrap_or(default)
+        };
+
+        UploadConfig {
+            max_bytes: number("UPLOAD_MAX_BYTES", 5 * 1024 * 1024),
+            max_files: number("UPLOAD_MAX_FILES", 10),
+        }
+    }
+}
+
+// Data models
+#[derive(Debug, sqlx::FromRow)]
+pub struct ProductImage {
+    pub image_id: i32,
+    pub product_id: i32,
+    pub storage_key: String,
+    pub thumbnails: Json<BTreeMap<String, String>>,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub size_bytes: i32,
+    pub position: i32,
+    pub alt_text: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Storage keys are internal, clients get URLs
+#[derive(Debug, Serialize)]
+pub struct ProductImageResponse {
+    pub image_id: i32,
+    pub product_id: i32,
+    pub url: String,
+    pub thumbnails: BTreeMap<String, String>,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub size_bytes: i32,
+    pub position: i32,
+    pub alt_text: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ProductImage {
+    fn keys(&self) -> Vec<String> {
+        std::iter::once(self.storage_key.clone())
+            .chain(self.thumbnails.values().cloned())
+            .collect()
+    }
+
+    fn into_response(self, storage: &dyn Storage) -> ProductImageResponse {
+        ProductImageResponse {
+            image_id: self.image_id,
+            product_id: self.product_id,
+            url: storage.url(&self.storage_key),
+            thumbnails: self.thumbnails.0.iter().map(|(size, key)| (size.clone(), storage.url(key))).collect(),
+            content_type: self.content_type,
+            width: self.width,
+            height: self.height,
+            size_bytes: self.size_bytes,
+            position: self.position,
+            alt_text: self.alt_text,
+            created_at: self.created_at,
+        }
+    }
+}
+
+// Body of PUT /api/products/{id}/images/order: every image of the product, new order
+#[derive(Debug, Deserialize)]
+pub struct ReorderImagesRequest {
+    pub image_ids: Vec<i32>,
+}
+
+// A validated upload with its thumbnails, ready to be stored
+struct ProcessedImage {
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    original: Vec<u8>,
+    // (size name, extension, encoded bytes)
+    thumbnails: Vec<(&'static str, &'static str, Vec<u8>)>,
+}
+
+// CPU bound, run in web::block
+fn process_image(bytes: Vec<u8>) -> Result<ProcessedImage, ApiError> {
+    let format = image::guess_format(&bytes)
+        .ok()
+        .filter(|f| ALLOWED_FORMATS.contains(f))
+        .ok_or_else(|| ApiError::UnsupportedMediaType("File is not a JPEG, PNG, WebP or GIF image".to_string()))?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(&bytes), format);
+    reader.limits(limits);
+    let img = reader
+        .decode()
+        .map_err(|e| ApiError::validation(format!("Image can't be decoded: {}", e)))?;
+
+    // Transparent images keep their alpha channel as PNG, the rest become JPEG
+    let (thumb_format, extension) = if img.color().has_alpha() {
+        (ImageFormat::Png, "png")
+    } else {
+        (ImageFormat::Jpeg, "jpg")
+    };
+
+    let mut thumbnails = Vec::new();
+    for &(size, max_side) in THUMBNAIL_SIZES {
+        let thumb = if img.width() > max_side || img.height() > max_side {
+            img.thumbnail(max_side, max_side)
+        } else {
+            img.clone()
+        };
+        let thumb = match thumb_format {
+            ImageFormat::Png => DynamicImage::ImageRgba8(thumb.to_rgba8()),
+            _ => DynamicImage::ImageRgb8(thumb.to_rgb8()),
+        };
+
+        let mut encoded = Cursor::new(Vec::new());
+        thumb
+            .write_to(&mut encoded, thumb_format)
+            .map_err(|e| ApiError::Internal(format!("thumbnail encoding failed: {}", e)))?;
+        thumbnails.push((size, extension, encoded.into_inner()));
+    }
+
+    Ok(ProcessedImage {
+        format,
+        width: img.width(),
+        height: img.height(),
+        original: bytes,
+        thumbnails,
+    })
+}
+
+// An image whose files are in storage, not yet in the database
+struct StoredImage {
+    key: String,
+    thumbnails: BTreeMap<String, String>,
+    content_type: &'static str,
+    width: u32,
+    height: u32,
+    size_bytes: i32,
+}
+
+fn extension(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "png",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Gif => "gif",
+        _ => "jpg",
+    }
+}
+
+// Uploaded files and the alt_text field of one request
+async fn read_upload(mut payload: Multipart, config: &UploadConfig) -> Result<(Vec<Vec<u8>>, Option<String>), ApiError> {
+    let bad_request = |e: actix_multipart::MultipartError| ApiError::BadRequest(e.to_string());
+    let mut files = Vec::new();
+    let mut alt_text = None;
+
+    while let Some(mut field) = payload.try_next().await.map_err(bad_request)? {
+        match field.name() {
+            Some("image") | Some("images") | Some("images[]") => {
+                if files.len() == config.max_files {
+                    return Err(ApiError::validation(format!("At most {} images per request", config.max_files)));
+                }
+                let declared = field.content_type().map(|m| m.essence_str().to_string()).unwrap_or_default();
+                if !ALLOWED_FORMATS.iter().any(|f| f.to_mime_type() == declared) {
+                    return Err(ApiError::UnsupportedMediaType(format!(
+                        "Unsupported content type '{}', expected image/jpeg, image/png, image/webp or image/gif",
+                        declared
+                    )));
+                }
+
+                // Stop reading as soon as the limit is crossed
+                let mut bytes = Vec::new();
+                while let Some(chunk) = field.try_next().await.map_err(bad_request)? {
+                    if bytes.len() + chunk.len() > config.max_bytes {
+                        return Err(ApiError::PayloadTooLarge(format!(
+                            "Image exceeds the limit of {} bytes",
+                            config.max_bytes
+                        )));
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                if bytes.is_empty() {
+                    return Err(ApiError::validation("Image file is empty"));
+                }
+                files.push(bytes);
+            }
+            Some("alt_text") => {
+                let mut bytes = Vec::new();
+                while let Some(chunk) = field.try_next().await.map_err(bad_request)? {
+                    bytes.extend_from_slice(&chunk);
+                    if bytes.len() > MAX_ALT_TEXT * 4 {
+                        return Err(ApiError::validation("alt_text is too long"));
+                    }
+                }
+                let text = String::from_utf8(bytes)
+                    .map_err(|_| ApiError::validation("alt_text must be UTF-8"))?;
+                if text.chars().count() > MAX_ALT_TEXT {
+                    return Err(ApiError::validation(format!("alt_text must be at most {} characters", MAX_ALT_TEXT)));
+                }
+                alt_text = Some(text).filter(|t| !t.trim().is_empty());
+            }
+            // Unknown fields are drained and ignored
+            _ => while field.try_next().await.map_err(bad_request)?.is_some() {},
+        }
+    }
+
+    if files.is_empty() {
+        return Err(ApiError::validation("No image uploaded, send the file in the 'image' field"));
+    }
+    Ok((files, alt_text))
+}
+
+async fn ensure_product(conn: &mut PgConnection, product_id: i32, lock: bool) -> Result<(), ApiError> {
+    let sql = if lock {
+        "SELECT product_id FROM products WHERE product_id = $1 AND deleted_at IS NULL FOR UPDATE"
+    } else {
+        "SELECT product_id FROM products WHERE product_id = $1 AND deleted_at IS NULL"
+    };
+    sqlx::query_scalar::<_, i32>(sql)
+        .bind(product_id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::NotFound("Product"))?;
+    Ok(())
+}
+
+// products.image_url follows the first image. When the last image is removed the
+// URL is cleared, unless it was set by hand to something else
+async fn sync_image_url(
+    conn: &mut PgConnection,
+    storage: &dyn Storage,
+    product_id: i32,
+    removed_key: Option<&str>,
+) -> Result<(), ApiError> {
+    let first_key = sqlx::query_scalar::<_, String>(
+        "SELECT storage_key FROM product_images WHERE product_id = $1 ORDER BY position LIMIT 1"
+    )
+        .bind(product_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    match (first_key, removed_key) {
+        (Some(key), _) => {
+            sqlx::query("UPDATE products SET image_url = $1 WHERE product_id = $2")
+                .bind(storage.url(&key))
+                .bind(product_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        (None, Some(removed)) => {
+            sqlx::query("UPDATE products SET image_url = NULL WHERE product_id = $1 AND image_url = $2")
+                .bind(product_id)
+                .bind(storage.url(removed))
+                .execute(&mut *conn)
+                .await?;
+        }
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+// Files are removed after the rows are gone; a failure only leaves an orphan file
+async fn delete_files(storage: &dyn Storage, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = storage.delete(key).await {
+            eprintln!("❌ Failed to delete {}: {}", key, e);
+        }
+    }
+}
+
+async fn list_images(conn: &mut PgConnection, product_id: i32) -> Result<Vec<ProductImage>, ApiError> {
+    let images = sqlx::query_as::<_, ProductImage>(
+        "SELECT * FROM product_images WHERE product_id = $1 ORDER BY position"
+    )
+        .bind(product_id)
+        .fetch_all(conn)
+        .await?;
+    Ok(images)
+}
+
+// Endpoint Callbacks
+// Images of a product, in display order
+// curl http://localhost:8080/api/products/1/images
+pub async fn get_product_images(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let mut conn = data.db.acquire().await?;
+
+    ensure_product(&mut conn, product_id, false).await?;
+    let images: Vec<_> = list_images(&mut conn, product_id)
+        .await?
+        .into_iter()
+        .map(|image| image.into_response(data.storage.as_ref()))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(images))
+}
+
+// Upload one or more images (staff only); they are appended after the existing ones
+// curl -X POST http://localhost:8080/api/products/1/images \
+//   -H "Authorization: Bearer $TOKEN" \
+//   -F "image=@photo.jpg;type=image/jpeg" -F "alt_text=Front view"
+pub async fn upload_product_images(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let storage = data.storage.as_ref();
+
+    // Fail before reading the body if the product doesn't exist
+    ensure_product(&mut *data.db.acquire().await?, product_id, false).await?;
+    let (files, alt_text) = read_upload(payload, &data.uploads).await?;
+
+    let mut processed = Vec::with_capacity(files.len());
+    for bytes in files {
+        let image = web::block(move || process_image(bytes))
+            .await
+            .map_err(|e| ApiError::Internal(e.to_string()))??;
+        processed.push(image);
+    }
+
+    // Store the files first, the rows only point at files that exist
+    let mut stored: Vec<String> = Vec::new();
+    let mut uploads = Vec::with_capacity(processed.len());
+    for image in processed {
+        let base = format!("images/products/{}/{}", product_id, uuid::Uuid::new_v4());
+        let key = format!("{}.{}", base, extension(image.format));
+        let size_bytes = image.original.len() as i32;
+
+        let mut thumbnails = BTreeMap::new();
+        let result = async {
+            storage.put(&key, image.original).await?;
+            stored.push(key.clone());
+            for (size, ext, bytes) in image.thumbnails {
+                let thumb_key = format!("{}_{}.{}", base, size, ext);
+                storage.put(&thumb_key, bytes).await?;
+                stored.push(thumb_key.clone());
+                thumbnails.insert(size.to_string(), thumb_key);
+            }
+            Ok::<_, crate::storage::StorageError>(())
+        }
+            .await;
+
+        if let Err(e) = result {
+            delete_files(storage, &stored).await;
+            return Err(ApiError::Internal(e.to_string()));
+        }
+        uploads.push(StoredImage {
+            key,
+            thumbnails,
+            content_type: image.format.to_mime_type(),
+            width: image.width,
+            height: image.height,
+            size_bytes,
+        });
+    }
+
+    let saved = async {
+        let mut tx = data.db.begin().await?;
+        // Serializes concurrent uploads to the same product
+        ensure_product(&mut tx, product_id, true).await?;
+        let mut position = sqlx::query_scalar::<_, i32>(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM product_images WHERE product_id = $1"
+        )
+            .bind(product_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let mut images = Vec::with_capacity(uploads.len());
+        for upload in &uploads {
+            let image = sqlx::query_as::<_, ProductImage>(
+                "INSERT INTO product_images (product_id, storage_key, thumbnails, content_type, width, height, size_bytes, position, alt_text) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *"
+            )
+                .bind(product_id)
+                .bind(&upload.key)
+                .bind(Json(&upload.thumbnails))
+                .bind(upload.content_type)
+                .bind(upload.width as i32)
+                .bind(upload.height as i32)
+                .bind(upload.size_bytes)
+                .bind(position)
+                .bind(&alt_text)
+                .fetch_one(&mut *tx)
+                .await?;
+            images.push(image);
+            position += 1;
+        }
+
+        sync_image_url(&mut tx, storage, product_id, None).await?;
+        tx.commit().await?;
+        Ok::<_, ApiError>(images)
+    }
+        .await;
+
+    match saved {
+        Ok(images) => {
+            let images: Vec<_> = images.into_iter().map(|image| image.into_response(storage)).collect();
+            Ok(HttpResponse::Created().json(images))
+        }
+        Err(e) => {
+            delete_files(storage, &stored).await;
+            Err(e)
+        }
+    }
+}
+
+// Change the display order (staff only); the first image becomes products.image_url
+// curl -X PUT http://localhost:8080/api/products/1/images/order \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"image_ids": [3, 1, 2]}'
+pub async fn reorder_product_images(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    reorder_req: web::Json<ReorderImagesRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let product_id = path.into_inner();
+    let mut tx = data.db.begin().await?;
+    ensure_product(&mut tx, product_id, true).await?;
+
+    let mut current: Vec<i32> = list_images(&mut tx, product_id)
+        .await?
+        .iter()
+        .map(|image| image.image_id)
+        .collect();
+    let mut requested = reorder_req.image_ids.clone();
+    current.sort_unstable();
+    requested.sort_unstable();
+    if current != requested {
+        return Err(ApiError::validation(
+            "image_ids must list every image of the product exactly once",
+        ));
+    }
+
+    // product_images_position_key is deferred, positions may collide until commit
+    sqlx::query(
+        "UPDATE product_images SET position = array_position($1, image_id) - 1 WHERE product_id = $2"
+    )
+        .bind(&reorder_req.image_ids)
+        .bind(product_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sync_image_url(&mut tx, data.storage.as_ref(), product_id, None).await?;
+    let images: Vec<_> = list_images(&mut tx, product_id)
+        .await?
+        .into_iter()
+        .map(|image| image.into_response(data.storage.as_ref()))
+        .collect();
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(images))
+}
+
+// Delete an image and its thumbnails (staff only)
+// curl -X DELETE http://localhost:8080/api/products/1/images/3 -H "Authorization: Bearer $TOKEN"
+pub async fn delete_product_image(
+    data: web::Data<AppState>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (product_id, image_id) = path.into_inner();
+    let mut tx = data.db.begin().await?;
+    ensure_product(&mut tx, product_id, true).await?;
+
+    let image = sqlx::query_as::<_, ProductImage>(
+        "DELETE FROM product_images WHERE product_id = $1 AND image_id = $2 RETURNING *"
+    )
+        .bind(product_id)
+        .bind(image_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Image"))?;
+
+    // Close the gap
+    sqlx::query("UPDATE product_images SET position = position - 1 WHERE product_id = $1 AND position > $2")
+        .bind(product_id)
+        .bind(image.position)
+        .execute(&mut *tx)
+        .await?;
+
+    sync_image_url(&mut tx, data.storage.as_ref(), product_id, Some(&image.storage_key)).await?;
+    tx.commit().await?;
+
+    delete_files(data.storage.as_ref(), &image.keys()).await;
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,986 @@
+// Promotions and coupons.
+//
+// A promotion takes a percentage or a fixed amount off the whole order, one
+// product or one category (including its subcategories). Promotions with a
+// `code` are coupons the customer enters at checkout, the others apply
+// automatically. Both can require a minimum order value, be limited to a number
+// of orders overall and per user (cancelled orders don't count) and to a
+// validity window.
+//
+// Stacking:
+//   - coupons entered together must all be stackable
+//   - a stackable coupon combines with the stackable automatic promotions,
+//     a non-stackable one applies alone
+//   - without coupons the customer gets whichever is more: all stackable
+//     automatic promotions together, or the best non-stackable one
+//
+// Promotions apply by descending priority, each to what the previous ones left
+// of its lines (spread over them proportionally), so the discount never exceeds
+// the order. Every discount is stored
+// as a line in `order_discounts`; orders.total_amount = items - discount_amount.
+// The line keeps the terms of its promotion, an order that changes later is
+// discounted on the terms it was placed with.
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::checkout::{self, CheckoutLine};
+use crate::error::ApiError;
+use crate::money;
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::pg_enum::impl_pg_string_enum;
+use crate::tax::{self, TaxLocation};
+
+const MAX_COUPONS: usize = 5;
+const MAX_CODE_LENGTH: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscountType {
+    // `value` percent of the covered lines
+    Percentage,
+    // `value` off the covered lines
+    Fixed,
+}
+
+impl DiscountType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiscountType::Percentage => "percentage",
+            DiscountType::Fixed => "fixed",
+        }
+    }
+}
+
+impl FromStr for DiscountType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "percentage" => Ok(DiscountType::Percentage),
+            "fixed" => Ok(DiscountType::Fixed),
+            other => Err(format!("unknown discount type: {}", other)),
+        }
+    }
+}
+
+// Stored as VARCHAR(20)
+impl_pg_string_enum!(DiscountType);
+
+// Data models
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Promotion {
+    pub promotion_id: i32,
+    pub name: String,
+    pub code: Option<String>,
+    pub discount_type: DiscountType,
+    #[serde(with = "money")]
+    pub value: Decimal,
+    pub product_id: Option<i32>,
+    pub category_id: Option<i32>,
+    #[serde(with = "money::option")]
+    pub min_order_amount: Option<Decimal>,
+    pub max_uses: Option<i32>,
+    pub max_uses_per_user: Option<i32>,
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub stackable: bool,
+    pub priority: i32,
+    pub is_active: bool,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+// GET /api/promotions: with the number of orders that used it
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PromotionUsage {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub promotion: Promotion,
+    pub times_used: i32,
+}
+
+// Row of order_discounts
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct OrderDiscount {
+    pub order_discount_id: i32,
+    pub order_id: i32,
+    pub promotion_id: Option<i32>,
+    pub code: Option<String>,
+    pub description: String,
+    #[serde(with = "money")]
+    pub amount: Decimal,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// What a discount is worked out from: the promotion at checkout, then the copy
+// stored with the discount line
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct DiscountTerms {
+    // None once the promotion is deleted
+    pub promotion_id: Option<i32>,
+    pub code: Option<String>,
+    pub description: String,
+    pub discount_type: DiscountType,
+    #[serde(with = "money")]
+    pub value: Decimal,
+    pub product_id: Option<i32>,
+    pub category_id: Option<i32>,
+    #[serde(with = "money::option")]
+    pub min_order_amount: Option<Decimal>,
+}
+
+// A discount worked out for a set of lines, before it is stored
+#[derive(Debug, Serialize)]
+pub struct Discount {
+    #[serde(flatten)]
+    pub terms: DiscountTerms,
+    #[serde(with = "money")]
+    pub amount: Decimal,
+    // (product_id, amount) taken off each line, used to tax the lines net of discounts
+    #[serde(skip)]
+    pub allocation: Vec<(i32, Decimal)>,
+}
+
+// Line as the engine sees it: `category_path` is the product's category and all
+// of its ancestors, `amount` the line subtotal
+#[derive(Debug)]
+pub struct DiscountLine {
+    pub product_id: i32,
+    pub category_path: Vec<i32>,
+    pub amount: Decimal,
+}
+
+// A promotion that may apply; `entered` for coupons the customer typed in
+#[derive(Debug)]
+pub struct Candidate {
+    pub promotion: Promotion,
+    pub entered: bool,
+}
+
+// Body of POST and PUT (full replacement)
+#[derive(Debug, Deserialize)]
+pub struct PromotionRequest {
+    pub name: String,
+    pub code: Option<String>,
+    pub discount_type: DiscountType,
+    #[serde(with = "money")]
+    pub value: Decimal,
+    pub product_id: Option<i32>,
+    pub category_id: Option<i32>,
+    #[serde(default, with = "money::option")]
+    pub min_order_amount: Option<Decimal>,
+    pub max_uses: Option<i32>,
+    pub max_uses_per_user: Option<i32>,
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub stackable: bool,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default = "default_active")]
+    pub is_active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl PromotionRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.name.trim().is_empty() {
+            return Err(ApiError::validation("name must not be empty"));
+        }
+        if let Some(code) = &self.code
+            && (code.is_empty()
+                || code.len() > MAX_CODE_LENGTH
+                || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        {
+            return Err(ApiError::validation(format!(
+                "code must be 1 to {} letters, digits, dashes or underscores",
+                MAX_CODE_LENGTH
+            )));
+        }
+
+        let value = money::validate(self.value, "value")?;
+        if value.is_zero() {
+            return Err(ApiError::validation("value must be positive"));
+        }
+        if self.discount_type == DiscountType::Percentage && value > Decimal::ONE_HUNDRED {
+            return Err(ApiError::validation("a percentage must not exceed 100"));
+        }
+        if let Some(min_order_amount) = self.min_order_amount {
+            money::validate(min_order_amount, "min_order_amount")?;
+        }
+
+        if self.product_id.is_some() && self.category_id.is_some() {
+            return Err(ApiError::validation("set product_id or category_id, not both"));
+        }
+        if self.max_uses.is_some_and(|n| n <= 0) || self.max_uses_per_user.is_some_and(|n| n <= 0) {
+            return Err(ApiError::validation("usage limits must be positive"));
+        }
+        if let (Some(starts_at), Some(ends_at)) = (self.starts_at, self.ends_at)
+            && ends_at <= starts_at
+        {
+            return Err(ApiError::validation("ends_at must be after starts_at"));
+        }
+        Ok(())
+    }
+}
+
+// POST /api/promotions/preview
+#[derive(Debug, Deserialize)]
+pub struct PreviewRequest {
+    pub items: Vec<CheckoutLine>,
+    #[serde(default)]
+    pub coupon_codes: Vec<String>,
+    // where the order would be taxed, as in checkout
+    pub country: Option<String>,
+    pub region: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Preview {
+    #[serde(with = "money")]
+    pub subtotal: Decimal,
+    pub discounts: Vec<Discount>,
+    #[serde(with = "money")]
+    pub discount_amount: Decimal,
+    #[serde(with = "money")]
+    pub tax_amount: Decimal,
+    pub prices_include_tax: bool,
+    #[serde(with = "money")]
+    pub total_amount: Decimal,
+}
+
+// Promotions with the number of orders that used them (cancelled ones excluded)
+const PROMOTION_USAGE: &str = "(
+    SELECT p.*, (
+        SELECT COUNT(DISTINCT d.order_id)
+        FROM order_discounts d
+        JOIN orders o ON o.order_id = d.order_id
+        WHERE d.promotion_id = p.promotion_id AND o.status <> 'cancelled'
+    )::INTEGER AS times_used
+    FROM promotions p
+) AS promotion_usage";
+
+const PROMOTION_LIST: ListSpec = ListSpec {
+    table: PROMOTION_USAGE,
+    key: "promotion_id",
+    sort_fields: &[
+        SortField { name: "name", expr: "name", sql_type: "TEXT" },
+        SortField { name: "priority", expr: "priority", sql_type: "INTEGER" },
+        SortField { name: "created_at", expr: "created_at", sql_type: "TIMESTAMPTZ" },
+    ],
+    default_sort: "-created_at",
+};
+
+impl Promotion {
+    fn label(&self) -> &str {
+        self.code.as_deref().unwrap_or(&self.name)
+    }
+
+    fn terms(&self) -> DiscountTerms {
+        DiscountTerms {
+            promotion_id: Some(self.promotion_id),
+            code: self.code.clone(),
+            description: self.name.clone(),
+            discount_type: self.discount_type,
+            value: self.value,
+            product_id: self.product_id,
+            category_id: self.category_id,
+            min_order_amount: self.min_order_amount,
+        }
+    }
+
+    // Why the promotion can't be used right now
+    fn unavailable(&self, now: chrono::DateTime<chrono::Utc>) -> Option<&'static str> {
+        if !self.is_active {
+            Some("is no longer active")
+        } else if self.starts_at.is_some_and(|starts_at| starts_at > now) {
+            Some("is not active yet")
+        } else if self.ends_at.is_some_and(|ends_at| ends_at <= now) {
+            Some("has expired")
+        } else {
+            None
+        }
+    }
+}
+
+impl DiscountTerms {
+    fn covers(&self, line: &DiscountLine) -> bool {
+        match (self.product_id, self.category_id) {
+            (Some(product_id), _) => line.product_id == product_id,
+            (None, Some(category_id)) => line.category_path.contains(&category_id),
+            (None, None) => true,
+        }
+    }
+
+    // Why the discount doesn't apply to these lines
+    fn unmet(&self, lines: &[DiscountLine]) -> Option<String> {
+        let subtotal: Decimal = lines.iter().map(|line| line.amount).sum();
+        if let Some(min_order_amount) = self.min_order_amount
+            && subtotal < min_order_amount
+        {
+            return Some(format!("needs an order of at least {}", min_order_amount));
+        }
+        if !lines.iter().any(|line| self.covers(line)) {
+            return Some("doesn't apply to any item in the order".to_string());
+        }
+        None
+    }
+}
+
+pub fn total(discounts: &[Discount]) -> Decimal {
+    discounts.iter().map(|d| d.amount).sum()
+}
+
+// The terms of `promotions` in the order they apply
+fn by_priority(promotions: &[&Promotion]) -> Vec<DiscountTerms> {
+    let mut ordered = promotions.to_vec();
+    ordered.sort_by_key(|p| (Reverse(p.priority), p.promotion_id));
+    ordered.into_iter().map(Promotion::terms).collect()
+}
+
+// Applies `terms` in order, each to what is left of the lines it covers
+fn apply(terms: &[DiscountTerms], lines: &[DiscountLine]) -> Vec<Discount> {
+    let mut remaining: Vec<Decimal> = lines.iter().map(|line| line.amount).collect();
+    let mut discounts = Vec::new();
+
+    for terms in terms {
+        let covered: Vec<usize> = (0..lines.len()).filter(|&i| terms.covers(&lines[i])).collect();
+        let base: Decimal = covered.iter().map(|&i| remaining[i]).sum();
+
+        let amount = match terms.discount_type {
+            DiscountType::Percentage => money::round(base * terms.value / Decimal::ONE_HUNDRED),
+            DiscountType::Fixed => terms.value.min(base),
+        };
+        if amount <= Decimal::ZERO {
+            continue;
+        }
+
+        // Spread over the lines in proportion to what is left of them, the last
+        // line takes the rounding difference
+        let mut left = amount;
+        let mut taken = vec![Decimal::ZERO; lines.len()];
+        for (n, &i) in covered.iter().enumerate() {
+            let share = if n + 1 == covered.len() { left } else { money::round(amount * remaining[i] / base) };
+            let take = share.min(left).min(remaining[i]);
+            taken[i] += take;
+            remaining[i] -= take;
+            left -= take;
+        }
+        for &i in &covered {
+            let take = left.min(remaining[i]);
+            taken[i] += take;
+            remaining[i] -= take;
+            left -= take;
+        }
+        let allocation = covered
+            .iter()
+            .filter(|&&i| taken[i] > Decimal::ZERO)
+            .map(|&i| (lines[i].product_id, taken[i]))
+            .collect();
+
+        discounts.push(Discount {
+            terms: terms.clone(),
+            amount,
+            allocation,
+        });
+    }
+    discounts
+}
+
+// Picks the promotions to apply (see the stacking rules above) and works out the
+// discounts; 422 if an entered coupon can't be used with these lines
+pub fn choose(candidates: &[Candidate], lines: &[DiscountLine]) -> Result<Vec<Discount>, ApiError> {
+    let mut entered = Vec::new();
+    let mut automatic = Vec::new();
+
+    for candidate in candidates {
+        let promotion = &candidate.promotion;
+        match promotion.terms().unmet(lines) {
+            Some(reason) if candidate.entered => {
+                return Err(ApiError::Unprocessable {
+                    code: "coupon_not_applicable",
+                    detail: format!("Coupon {} {}", promotion.label(), reason),
+                });
+            }
+            Some(_) => {}
+            None if candidate.entered => entered.push(promotion),
+            None => automatic.push(promotion),
+        }
+    }
+    let stackable: Vec<&Promotion> = automatic.iter().filter(|p| p.stackable).copied().collect();
+
+    if !entered.is_empty() {
+        if entered.len() > 1
+            && let Some(alone) = entered.iter().find(|p| !p.stackable)
+        {
+            return Err(ApiError::Unprocessable {
+                code: "coupons_not_combinable",
+                detail: format!("Coupon {} can't be combined with other coupons", alone.label()),
+            });
+        }
+        if entered[0].stackable {
+            entered.extend(stackable);
+        }
+        return Ok(apply(&by_priority(&entered), lines));
+    }
+
+    let mut best = apply(&by_priority(&stackable), lines);
+    for promotion in automatic.iter().filter(|p| !p.stackable) {
+        let alone = apply(&[promotion.terms()], lines);
+        if total(&alone) > total(&best) {
+            best = alone;
+        }
+    }
+    Ok(best)
+}
+
+// Lines with the category path of their products; `amounts` is (product_id, subtotal)
+pub async fn discount_lines(
+    conn: &mut PgConnection,
+    amounts: &[(i32, Decimal)],
+) -> Result<Vec<DiscountLine>, ApiError> {
+    let product_ids: Vec<i32> = amounts.iter().map(|(product_id, _)| *product_id).collect();
+
+    let paths: HashMap<i32, Vec<i32>> = sqlx::query_as::<_, (i32, Vec<i32>)>(
+        "SELECT p.product_id, ARRAY(
+            WITH RECURSIVE up AS (
+                SELECT c.category_id, c.parent_id FROM categories c WHERE c.category_id = p.category_id
+                UNION
+                SELECT c.category_id, c.parent_id FROM categories c JOIN up ON c.category_id = up.parent_id
+            )
+            SELECT category_id FROM up
+        ) AS category_path
+        FROM products p
+        WHERE p.product_id = ANY($1)"
+    )
+        .bind(&product_ids)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .collect();
+
+    Ok(amounts
+        .iter()
+        .map(|(product_id, amount)| DiscountLine {
+            product_id: *product_id,
+            category_path: paths.get(product_id).cloned().unwrap_or_default(),
+            amount: *amount,
+        })
+        .collect())
+}
+
+// Automatic promotions running now plus the entered coupons, with the usage
+// limits checked. Limited promotions stay locked until the caller commits, so
+// two checkouts can't both take the last use
+pub async fn candidates(
+    conn: &mut PgConnection,
+    user_id: i32,
+    codes: &[String],
+) -> Result<Vec<Candidate>, ApiError> {
+    let mut wanted: Vec<String> = Vec::new();
+    for code in codes {
+        let code = code.trim().to_lowercase();
+        if !code.is_empty() && !wanted.contains(&code) {
+            wanted.push(code);
+        }
+    }
+    if wanted.len() > MAX_COUPONS {
+        return Err(ApiError::validation(format!("at most {} coupons per order", MAX_COUPONS)));
+    }
+
+    let promotions = sqlx::query_as::<_, Promotion>(
+        "SELECT * FROM promotions
+        WHERE (code IS NULL AND is_active AND (starts_at IS NULL OR starts_at <= CURRENT_TIMESTAMP) AND (ends_at IS NULL OR ends_at > CURRENT_TIMESTAMP))
+           OR LOWER(code) = ANY($1)
+        ORDER BY promotion_id"
+    )
+        .bind(&wanted)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let now = chrono::Utc::now();
+    for code in &wanted {
+        let promotion = promotions
+            .iter()
+            .find(|p| p.code.as_ref().is_some_and(|c| c.to_lowercase() == *code))
+            .ok_or_else(|| ApiError::Unprocessable {
+                code: "coupon_not_found",
+                detail: format!("Coupon {} does not exist", code.to_uppercase()),
+            })?;
+
+        if let Some(reason) = promotion.unavailable(now) {
+            return Err(ApiError::Unprocessable {
+                code: "coupon_not_valid",
+                detail: format!("Coupon {} {}", promotion.label(), reason),
+            });
+        }
+    }
+
+    let limited: Vec<i32> = promotions
+        .iter()
+        .filter(|p| p.max_uses.is_some() || p.max_uses_per_user.is_some())
+        .map(|p| p.promotion_id)
+        .collect();
+
+    let mut usage: HashMap<i32, (i32, i32)> = HashMap::new();
+    if !limited.is_empty() {
+        sqlx::query("SELECT promotion_id FROM promotions WHERE promotion_id = ANY($1) ORDER BY promotion_id FOR UPDATE")
+            .bind(&limited)
+            .execute(&mut *conn)
+            .await?;
+
+        usage = sqlx::query_as::<_, (i32, i32, i32)>(
+            "SELECT d.promotion_id, COUNT(DISTINCT d.order_id)::INTEGER, (COUNT(DISTINCT d.order_id) FILTER (WHERE o.user_id = $2))::INTEGER
+            FROM order_discounts d
+            JOIN orders o ON o.order_id = d.order_id
+            WHERE d.promotion_id = ANY($1) AND o.status <> 'cancelled'
+            GROUP BY d.promotion_id"
+        )
+            .bind(&limited)
+            .bind(user_id)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|(promotion_id, uses, user_uses)| (promotion_id, (uses, user_uses)))
+            .collect();
+    }
+
+    let mut candidates = Vec::with_capacity(promotions.len());
+    for promotion in promotions {
+        let entered = promotion.code.is_some();
+        let (uses, user_uses) = usage.get(&promotion.promotion_id).copied().unwrap_or_default();
+
+        let exhausted = if promotion.max_uses.is_some_and(|max| uses >= max) {
+            Some(format!("Coupon {} has been used up", promotion.label()))
+        } else if promotion.max_uses_per_user.is_some_and(|max| user_uses >= max) {
+            Some(format!("You have already used coupon {}", promotion.label()))
+        } else {
+            None
+        };
+
+        match exhausted {
+            Some(detail) if entered => {
+                return Err(ApiError::Unprocessable { code: "coupon_usage_limit", detail });
+            }
+            Some(_) => {}
+            None => candidates.push(Candidate { promotion, entered }),
+        }
+    }
+    Ok(candidates)
+}
+
+// Replaces the discount lines of the order
+pub async fn save(
+    conn: &mut PgConnection,
+    order_id: i32,
+    discounts: &[Discount],
+) -> Result<Vec<OrderDiscount>, ApiError> {
+    sqlx::query("DELETE FROM order_discounts WHERE order_id = $1")
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let promotion_ids: Vec<Option<i32>> = discounts.iter().map(|d| d.terms.promotion_id).collect();
+    let codes: Vec<Option<String>> = discounts.iter().map(|d| d.terms.code.clone()).collect();
+    let descriptions: Vec<String> = discounts.iter().map(|d| d.terms.description.clone()).collect();
+    let discount_types: Vec<&str> = discounts.iter().map(|d| d.terms.discount_type.as_str()).collect();
+    let values: Vec<Decimal> = discounts.iter().map(|d| d.terms.value).collect();
+    let product_ids: Vec<Option<i32>> = discounts.iter().map(|d| d.terms.product_id).collect();
+    let category_ids: Vec<Option<i32>> = discounts.iter().map(|d| d.terms.category_id).collect();
+    let min_order_amounts: Vec<Option<Decimal>> = discounts.iter().map(|d| d.terms.min_order_amount).collect();
+    let amounts: Vec<Decimal> = discounts.iter().map(|d| d.amount).collect();
+
+    let saved = sqlx::query_as::<_, OrderDiscount>(
+        "INSERT INTO order_discounts (order_id, promotion_id, code, description, discount_type, value, product_id, category_id, min_order_amount, amount)
+        SELECT $1, d.promotion_id, d.code, d.description, d.discount_type, d.value, d.product_id, d.category_id, d.min_order_amount, d.amount
+        FROM UNNEST($2::INT[], $3::VARCHAR[], $4::VARCHAR[], $5::VARCHAR[], $6::NUMERIC[], $7::INT[], $8::INT[], $9::NUMERIC[], $10::NUMERIC[])
+            WITH ORDINALITY AS d(promotion_id, code, description, discount_type, value, product_id, category_id, min_order_amount, amount, n)
+        ORDER BY d.n RETURNING *"
+    )
+        .bind(order_id)
+        .bind(&promotion_ids)
+        .bind(&codes)
+        .bind(&descriptions)
+        .bind(&discount_types)
+        .bind(&values)
+        .bind(&product_ids)
+        .bind(&category_ids)
+        .bind(&min_order_amounts)
+        .bind(&amounts)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    Ok(saved)
+}
+
+// Lines of a pending order changed: the discounts it already has are worked out
+// again on the new lines, from the terms stored when the order was placed, and
+// dropped if their conditions no longer hold. Changing or deleting a promotion
+// doesn't touch them; validity windows and usage limits were checked when the
+// order was placed. Returns the new discounts
+pub async fn reapply(conn: &mut PgConnection, order_id: i32) -> Result<Vec<Discount>, ApiError> {
+    // Stored in the order they applied
+    let terms = sqlx::query_as::<_, DiscountTerms>(
+        "SELECT promotion_id, code, description, discount_type, value, product_id, category_id, min_order_amount
+        FROM order_discounts WHERE order_id = $1 ORDER BY order_discount_id"
+    )
+        .bind(order_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let amounts = sqlx::query_as::<_, (i32, Decimal)>(
+        "SELECT product_id, subtotal FROM order_items WHERE order_id = $1 ORDER BY product_id"
+    )
+        .bind(order_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    let lines = discount_lines(conn, &amounts).await?;
+
+    let kept: Vec<DiscountTerms> = terms.into_iter().filter(|t| t.unmet(&lines).is_none()).collect();
+    let discounts = apply(&kept, &lines);
+    save(conn, order_id, &discounts).await?;
+
+    Ok(discounts)
+}
+
+// Endpoint Callbacks
+// List promotions (staff only)
+// curl "http://localhost:8080/api/promotions?sort=-priority" -H "Authorization: Bearer $TOKEN"
+pub async fn get_promotions(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+) -> Result<HttpResponse, ApiError> {
+    pagination::list::<PromotionUsage>(&data.db, &req, &PROMOTION_LIST, &page, |_| {}).await
+}
+
+// curl http://localhost:8080/api/promotions/1 -H "Authorization: Bearer $TOKEN"
+pub async fn get_promotion(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let promotion = sqlx::query_as::<_, PromotionUsage>(&format!(
+        "SELECT * FROM {} WHERE promotion_id = $1",
+        PROMOTION_USAGE
+    ))
+        .bind(path.into_inner())
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Promotion"))?;
+
+    Ok(HttpResponse::Ok().json(promotion))
+}
+
+// Create promotion (staff only)
+// curl -X POST http://localhost:8080/api/promotions \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"name": "Autumn sale", "code": "AUTUMN10", "discount_type": "percentage", "value": "10", "min_order_amount": "50.00", "max_uses_per_user": 1}'
+pub async fn create_promotion(
+    data: web::Data<AppState>,
+    promotion_req: web::Json<PromotionRequest>,
+) -> Result<HttpResponse, ApiError> {
+    promotion_req.validate()?;
+
+    // Duplicate code -> 409, unknown product/category -> 422 (ApiError)
+    let promotion = sqlx::query_as::<_, Promotion>(
+        "INSERT INTO promotions (name, code, discount_type, value, product_id, category_id, min_order_amount, max_uses, max_uses_per_user, starts_at, ends_at, stackable, priority, is_active)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) RETURNING *"
+    )
+        .bind(promotion_req.name.trim())
+        .bind(&promotion_req.code)
+        .bind(promotion_req.discount_type)
+        .bind(promotion_req.value)
+        .bind(promotion_req.product_id)
+        .bind(promotion_req.category_id)
+        .bind(promotion_req.min_order_amount)
+        .bind(promotion_req.max_uses)
+        .bind(promotion_req.max_uses_per_user)
+        .bind(promotion_req.starts_at)
+        .bind(promotion_req.ends_at)
+        .bind(promotion_req.stackable)
+        .bind(promotion_req.priority)
+        .bind(promotion_req.is_active)
+        .fetch_one(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Created().json(promotion))
+}
+
+// Replace promotion (staff only); orders placed already keep their discounts
+// curl -X PUT http://localhost:8080/api/promotions/1 \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"name": "Autumn sale", "code": "AUTUMN10", "discount_type": "percentage", "value": "15", "is_active": false}'
+pub async fn update_promotion(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    promotion_req: web::Json<PromotionRequest>,
+) -> Result<HttpResponse, ApiError> {
+    promotion_req.validate()?;
+
+    let promotion = sqlx::query_as::<_, Promotion>(
+        "UPDATE promotions SET name = $2, code = $3, discount_type = $4, value = $5, product_id = $6, category_id = $7, min_order_amount = $8,
+            max_uses = $9, max_uses_per_user = $10, starts_at = $11, ends_at = $12, stackable = $13, priority = $14, is_active = $15
+        WHERE promotion_id = $1 RETURNING *"
+    )
+        .bind(path.into_inner())
+        .bind(promotion_req.name.trim())
+        .bind(&promotion_req.code)
+        .bind(promotion_req.discount_type)
+        .bind(promotion_req.value)
+        .bind(promotion_req.product_id)
+        .bind(promotion_req.category_id)
+        .bind(promotion_req.min_order_amount)
+        .bind(promotion_req.max_uses)
+        .bind(promotion_req.max_uses_per_user)
+        .bind(promotion_req.starts_at)
+        .bind(promotion_req.ends_at)
+        .bind(promotion_req.stackable)
+        .bind(promotion_req.priority)
+        .bind(promotion_req.is_active)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Promotion"))?;
+
+    Ok(HttpResponse::Ok().json(promotion))
+}
+
+// Delete promotion (staff only); discount lines of orders stay with their terms, without the link
+// curl -X DELETE http://localhost:8080/api/promotions/1 -H "Authorization: Bearer $TOKEN"
+pub async fn delete_promotion(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let result = sqlx::query("DELETE FROM promotions WHERE promotion_id = $1")
+        .bind(path.into_inner())
+        .execute(&data.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Promotion"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// What checkout would charge for these items and coupons, tax included; nothing is stored
+// curl -X POST http://localhost:8080/api/promotions/preview \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"items": [{"product_id": 4, "quantity": 2}], "coupon_codes": ["AUTUMN10"]}'
+pub async fn preview_discounts(
+    data: web::Data<AppState>,
+    preview_req: web::Json<PreviewRequest>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let quantities = checkout::merge_lines(&preview_req.items)?;
+    let location = TaxLocation::resolve(&data.tax, preview_req.country.as_deref(), preview_req.region.as_deref())?;
+
+    // Rolled back when dropped, it only holds the locks while pricing
+    let mut tx = data.db.begin().await?;
+
+    let priced = checkout::price_lines(&mut tx, &quantities).await?;
+    let amounts: Vec<(i32, Decimal)> = priced.iter().map(|line| (line.product_id, line.subtotal)).collect();
+    let lines = discount_lines(&mut tx, &amounts).await?;
+
+    let candidates = candidates(&mut tx, auth.user_id, &preview_req.coupon_codes).await?;
+    let discounts = choose(&candidates, &lines)?;
+
+    let prices_include_tax = data.tax.prices_include_tax;
+    let taxes = tax::calculate(&mut tx, &location, prices_include_tax, &checkout::taxable_lines(&priced), &discounts).await?;
+
+    let subtotal: Decimal = amounts.iter().map(|(_, amount)| *amount).sum();
+    let discount_amount = total(&discounts);
+    let tax_amount = tax::total(&taxes);
+
+    Ok(HttpResponse::Ok().json(Preview {
+        subtotal,
+        discounts,
+        discount_amount,
+        tax_amount,
+        prices_include_tax,
+        total_amount: tax::order_total(subtotal, discount_amount, tax_amount, prices_include_tax),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn promotion(promotion_id: i32, discount_type: DiscountType, value: &str) -> Promotion {
+        Promotion {
+            promotion_id,
+            name: format!("Promotion {}", promotion_id),
+            code: None,
+            discount_type,
+            value: value.parse().unwrap(),
+            product_id: None,
+            category_id: None,
+            min_order_amount: None,
+            max_uses: None,
+            max_uses_per_user: None,
+            starts_at: None,
+            ends_at: None,
+            stackable: false,
+            priority: 0,
+            is_active: true,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+
+    fn coupon(promotion_id: i32, code: &str, discount_type: DiscountType, value: &str) -> Promotion {
+        Promotion { code: Some(code.to_string()), ..promotion(promotion_id, discount_type, value) }
+    }
+
+    fn line(product_id: i32, category_path: &[i32], amount: &str) -> DiscountLine {
+        DiscountLine { product_id, category_path: category_path.to_vec(), amount: amount.parse().unwrap() }
+    }
+
+    fn candidate(promotion: Promotion) -> Candidate {
+        Candidate { entered: promotion.code.is_some(), promotion }
+    }
+
+    fn amounts(discounts: &[Discount]) -> Vec<String> {
+        discounts.iter().map(|d| format!("{:.2}", d.amount)).collect()
+    }
+
+    fn allocated(discount: &Discount) -> Decimal {
+        discount.allocation.iter().map(|(_, amount)| *amount).sum()
+    }
+
+    #[test]
+    fn percentage_is_rounded_to_cents() {
+        let lines = [line(1, &[], "22.00"), line(2, &[], "10.15")];
+        let discounts = apply(&[promotion(1, DiscountType::Percentage, "10").terms()], &lines);
+
+        assert_eq!(amounts(&discounts), ["3.22"]);
+        assert_eq!(allocated(&discounts[0]), discounts[0].amount);
+    }
+
+    #[test]
+    fn fixed_amount_is_capped_at_the_covered_lines() {
+        let lines = [line(1, &[], "12.00"), line(2, &[], "8.00")];
+        let mut shoes = promotion(1, DiscountType::Fixed, "50");
+        shoes.product_id = Some(2);
+
+        let discounts = apply(&[shoes.terms()], &lines);
+
+        assert_eq!(amounts(&discounts), ["8.00"]);
+        assert_eq!(discounts[0].allocation, [(2, "8.00".parse().unwrap())]);
+    }
+
+    #[test]
+    fn allocation_adds_up_to_the_discount() {
+        let lines = [line(1, &[], "1.00"), line(2, &[], "1.00"), line(3, &[], "1.00")];
+        let discounts = apply(&[promotion(1, DiscountType::Fixed, "1.00").terms()], &lines);
+
+        let shares: Vec<String> = discounts[0].allocation.iter().map(|(_, amount)| amount.to_string()).collect();
+        assert_eq!(shares, ["0.33", "0.33", "0.34"]);
+        assert_eq!(allocated(&discounts[0]), discounts[0].amount);
+    }
+
+    #[test]
+    fn allocation_never_exceeds_a_line() {
+        // Nearly everything is taken, the rounded shares must still fit their lines
+        let lines = [line(1, &[], "0.01"), line(2, &[], "0.01"), line(3, &[], "9.98")];
+        let discounts = apply(&[promotion(1, DiscountType::Percentage, "99.9").terms()], &lines);
+
+        assert_eq!(allocated(&discounts[0]), discounts[0].amount);
+        for (product_id, amount) in &discounts[0].allocation {
+            let line = lines.iter().find(|l| l.product_id == *product_id).unwrap();
+            assert!(*amount <= line.amount);
+        }
+    }
+
+    #[test]
+    fn category_promotion_covers_subcategories() {
+        let lines = [line(1, &[7, 3], "10.00"), line(2, &[4], "10.00")];
+        let mut phones = promotion(1, DiscountType::Percentage, "50");
+        phones.category_id = Some(3);
+
+        let discounts = apply(&[phones.terms()], &lines);
+
+        assert_eq!(discounts[0].allocation, [(1, "5.00".parse().unwrap())]);
+    }
+
+    #[test]
+    fn stacked_promotions_apply_by_priority_to_what_is_left() {
+        let lines = [line(1, &[], "100.00")];
+        let mut half = promotion(1, DiscountType::Percentage, "50");
+        half.stackable = true;
+        let mut ten = promotion(2, DiscountType::Fixed, "10");
+        ten.stackable = true;
+        ten.priority = 1;
+
+        let discounts = choose(&[candidate(half.clone()), candidate(ten.clone())], &lines).unwrap();
+        assert_eq!(amounts(&discounts), ["10.00", "45.00"]);
+
+        ten.priority = -1;
+        let discounts = choose(&[candidate(half), candidate(ten)], &lines).unwrap();
+        assert_eq!(amounts(&discounts), ["50.00", "10.00"]);
+    }
+
+    #[test]
+    fn best_of_stacked_and_exclusive_automatic_promotions() {
+        let lines = [line(1, &[], "100.00")];
+        let mut five = promotion(1, DiscountType::Fixed, "5");
+        five.stackable = true;
+        let mut ten = promotion(2, DiscountType::Fixed, "10");
+        ten.stackable = true;
+        let exclusive = promotion(3, DiscountType::Percentage, "20");
+
+        let discounts = choose(&[candidate(five.clone()), candidate(ten.clone()), candidate(exclusive)], &lines).unwrap();
+        assert_eq!(discounts.iter().map(|d| d.terms.promotion_id).collect::<Vec<_>>(), [Some(3)]);
+
+        let exclusive = promotion(3, DiscountType::Percentage, "10");
+        let discounts = choose(&[candidate(five), candidate(ten), candidate(exclusive)], &lines).unwrap();
+        assert_eq!(total(&discounts), "15.00".parse().unwrap());
+    }
+
+    #[test]
+    fn stackable_coupon_joins_the_stackable_automatic_promotions() {
+        let lines = [line(1, &[], "100.00")];
+        let mut automatic = promotion(1, DiscountType::Fixed, "5");
+        automatic.stackable = true;
+        let mut code = coupon(2, "SAVE10", DiscountType::Fixed, "10");
+        code.stackable = true;
+
+        let discounts = choose(&[candidate(automatic.clone()), candidate(code)], &lines).unwrap();
+        assert_eq!(total(&discounts), "15.00".parse().unwrap());
+
+        let code = coupon(2, "SAVE10", DiscountType::Fixed, "10");
+        let discounts = choose(&[candidate(automatic), candidate(code)], &lines).unwrap();
+        assert_eq!(amounts(&discounts), ["10.00"]);
+    }
+
+    #[test]
+    fn exclusive_coupon_cannot_be_combined() {
+        let lines = [line(1, &[], "100.00")];
+        let mut first = coupon(1, "FIRST", DiscountType::Fixed, "5");
+        first.stackable = true;
+        let second = coupon(2, "SECOND", DiscountType::Fixed, "10");
+
+        let err = choose(&[candidate(first), candidate(second)], &lines).unwrap_err();
+        assert!(matches!(err, ApiError::Unprocessable { code: "coupons_not_combinable", .. }));
+    }
+
+    #[test]
+    fn entered_coupon_below_minimum_is_rejected() {
+        let lines = [line(1, &[], "40.00")];
+        let mut code = coupon(1, "BIG50", DiscountType::Fixed, "10");
+        code.min_order_amount = Some("50.00".parse().unwrap());
+
+        let err = choose(&[candidate(code)], &lines).unwrap_err();
+        assert!(matches!(err, ApiError::Unprocessable { code: "coupon_not_applicable", .. }));
+    }
+}
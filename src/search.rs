@@ -0,0 +1,161 @@
+// Full-text product search.
+//
+// products.search_vector is a generated TSVECTOR (name A, sku B, description C)
+// built in the russian, english and simple configurations, see migration 0009.
+// The query language defaults to SEARCH_LANGUAGE (russian) and can be switched
+// per request with `lang`. Every word is matched as a prefix ("айф" finds "айфон").
+use std::str::FromStr;
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::error::ApiError;
+use crate::pagination;
+use crate::product::Product;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+// <mark> is inserted around matches of the HTML-escaped text (see escape_html), so
+// highlights are safe to render as HTML. Names are returned whole, descriptions
+// as up to two fragments around the matches
+const NAME_HEADLINE: &str = "StartSel=<mark>, StopSel=</mark>, HighlightAll=true";
+const DESCRIPTION_HEADLINE: &str =
+    "StartSel=<mark>, StopSel=</mark>, MaxWords=35, MinWords=15, MaxFragments=2, FragmentDelimiter=\" … \"";
+
+// Text search configurations present in search_vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchLanguage {
+    Russian,
+    English,
+    Simple,
+}
+
+impl SearchLanguage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchLanguage::Russian => "russian",
+            SearchLanguage::English => "english",
+            SearchLanguage::Simple => "simple",
+        }
+    }
+
+    // SEARCH_LANGUAGE=russian|english|simple; russian also stems latin words
+    pub fn from_env() -> Self {
+        std::env::var("SEARCH_LANGUAGE")
+            .ok()
+            .map(|v| v.parse().unwrap_or_else(|e| panic!("{}", e)))
+            .unwrap_or(SearchLanguage::Russian)
+    }
+}
+
+impl FromStr for SearchLanguage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "russian" => Ok(SearchLanguage::Russian),
+            "english" => Ok(SearchLanguage::English),
+            "simple" => Ok(SearchLanguage::Simple),
+            other => Err(format!("unsupported search language: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    pub lang: Option<SearchLanguage>,
+    pub page: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub product: Product,
+    pub rank: f32,
+    // name / description with matches wrapped in <mark>
+    pub name_highlight: String,
+    pub description_highlight: Option<String>,
+}
+
+// "iPhone 15, чехол" -> "iPhone:* & 15:* & чехол:*"
+// Only letters and digits reach to_tsquery, so user input can't break its syntax
+fn prefix_query(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("{}:*", t))
+        .collect();
+
+    (!terms.is_empty()).then(|| terms.join(" & "))
+}
+
+// SQL expression that HTML-escapes `column`; the parser keeps entities such as
+// &amp; as single tokens, so the words still match
+fn escape_html(column: &str) -> String {
+    format!(
+        "replace(replace(replace(replace(replace({}, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;'), '''', '&#39;')",
+        column
+    )
+}
+
+// Endpoint Callbacks
+// Search products, best matches first
+// curl "http://localhost:8080/api/products/search?q=айфон&lang=russian&page=1&limit=20"
+pub async fn search_products(
+    data: web::Data<AppState>,
+    params: web::Query<SearchParams>,
+) -> Result<HttpResponse, ApiError> {
+    let query = prefix_query(&params.q)
+        .ok_or_else(|| ApiError::validation("q must contain at least one letter or digit"))?;
+    let language = params.lang.unwrap_or(data.search_language).as_str();
+
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!("limit must be between 1 and {}", MAX_LIMIT)));
+    }
+    let (_, offset) = pagination::page_offset(params.page, limit)?;
+
+    let total = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM products WHERE deleted_at IS NULL AND search_vector @@ to_tsquery($1::regconfig, $2)"
+    )
+        .bind(language)
+        .bind(&query)
+        .fetch_one(&data.db)
+        .await?;
+
+    // ts_headline is expensive: rank and cut the page first, highlight only that
+    let hits = sqlx::query_as::<_, SearchHit>(&format!(
+        "WITH matches AS (
+            SELECT p.*, ts_rank_cd(p.search_vector, q.query) AS rank, q.query
+            FROM products p, to_tsquery($1::regconfig, $2) AS q(query)
+            WHERE p.deleted_at IS NULL AND p.search_vector @@ q.query
+            ORDER BY rank DESC, p.product_id
+            LIMIT $3 OFFSET $4
+        )
+        SELECT m.*,
+               ts_headline($1::regconfig, {}, m.query, $5) AS name_highlight,
+               ts_headline($1::regconfig, {}, m.query, $6) AS description_highlight
+        FROM matches m
+        ORDER BY m.rank DESC, m.product_id",
+        escape_html("m.name"),
+        escape_html("m.description"),
+    ))
+        .bind(language)
+        .bind(&query)
+        .bind(limit)
+        .bind(offset)
+        .bind(NAME_HEADLINE)
+        .bind(DESCRIPTION_HEADLINE)
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Total-Count", total.to_string()))
+        .json(hits))
+}
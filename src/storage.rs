@@ -0,0 +1,103 @@
+// File storage abstraction.
+//
+// Uploaded files are addressed by a relative key ("images/products/12/<uuid>.jpg").
+// `LocalStorage` keeps them under a directory that main() serves statically;
+// S3-like backends only have to implement `Storage` and be added to `from_env`.
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError>;
+
+    // Deleting a missing file is not an error
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    // Public URL of a stored file
+    fn url(&self, key: &str) -> String;
+
+    // Directory to serve under the public URL, for backends that store locally
+    fn local_root(&self) -> Option<(&str, &Path)> {
+        None
+    }
+}
+
+// Files on the local disk: UPLOAD_DIR (default "uploads") served as UPLOAD_BASE_URL
+// (default "/uploads")
+pub struct LocalStorage {
+    root: PathBuf,
+    base_url: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, base_url: impl Into<String>) -> Self {
+        LocalStorage {
+            root: root.into(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    // Keys are generated by us, but never let one escape the root
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let relative = Path::new(key);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(StorageError(format!("invalid key: {}", key)));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| StorageError(e.to_string()))?;
+        }
+        tokio::fs::write(&path, bytes)
+            .await
+            .map_err(|e| StorageError(format!("{}: {}", path.display(), e)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(StorageError(e.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+
+    fn local_root(&self) -> Option<(&str, &Path)> {
+        Some((&self.base_url, &self.root))
+    }
+}
+
+// STORAGE_BACKEND selects the implementation (only "local" for now)
+pub fn from_env() -> Arc<dyn Storage> {
+    let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string());
+
+    match backend.as_str() {
+        "local" => Arc::new(LocalStorage::new(
+            std::env::var("UPLOAD_DIR").unwrap_or_else(|_| "uploads".to_string()),
+            std::env::var("UPLOAD_BASE_URL").unwrap_or_else(|_| "/uploads".to_string()),
+        )),
+        other => panic!("Unknown STORAGE_BACKEND: {}", other),
+    }
+}
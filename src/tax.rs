@@ -0,0 +1,700 @@
+// Taxes.
+//
+// Rates are set per country, optionally per region, and per product tax class
+// (products.tax_class, "standard" unless set). A regional rate replaces the
+// country rate of its class; a class without a rate is not taxed.
+//
+// An order is taxed where it is delivered: the country and region given at
+// checkout, TAX_DEFAULT_COUNTRY without a country. TAX_PRICES_INCLUDE_TAX picks
+// the pricing mode:
+//   exclusive (default)   tax is added on top, total = items - discounts + tax
+//   inclusive             prices contain the tax, total = items - discounts,
+//                         the tax is worked out of it
+// Every line is taxed net of its share of the discounts and rounded on its own.
+// Lines keep the class, rate, taxable amount and tax they were taxed with and
+// the order keeps its location and mode, so rate changes never touch placed
+// orders; editing a pending order taxes it again with the current rates.
+use std::collections::HashMap;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::AppState;
+use crate::error::ApiError;
+use crate::money;
+use crate::payment;
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::promotion::Discount;
+
+pub const DEFAULT_TAX_CLASS: &str = "standard";
+
+const MAX_TAX_CLASS_LENGTH: usize = 50;
+const MAX_REGION_LENGTH: usize = 100;
+// NUMERIC(6, 3)
+const RATE_SCALE: u32 = 3;
+
+// TAX_PRICES_INCLUDE_TAX (default false) and TAX_DEFAULT_COUNTRY (none by default)
+#[derive(Debug, Clone)]
+pub struct TaxConfig {
+    pub prices_include_tax: bool,
+    pub default_country: Option<String>,
+}
+
+impl TaxConfig {
+    pub fn from_env() -> Self {
+        let prices_include_tax = std::env::var("TAX_PRICES_INCLUDE_TAX")
+            .ok()
+            .map(|v| v.parse::<bool>().expect("TAX_PRICES_INCLUDE_TAX must be true or false"))
+            .unwrap_or(false);
+
+        let default_country = std::env::var("TAX_DEFAULT_COUNTRY")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| parse_country(&v).expect("TAX_DEFAULT_COUNTRY must be an ISO 3166-1 alpha-2 code"));
+
+        TaxConfig {
+            prices_include_tax,
+            default_country,
+        }
+    }
+}
+
+// Where an order is taxed; no country means no tax
+#[derive(Debug, Clone, Default)]
+pub struct TaxLocation {
+    pub country: Option<String>,
+    pub region: Option<String>,
+}
+
+impl TaxLocation {
+    // Country and region sent by the client, TAX_DEFAULT_COUNTRY without a country
+    pub fn resolve(config: &TaxConfig, country: Option<&str>, region: Option<&str>) -> Result<Self, ApiError> {
+        let country = match country.map(str::trim).filter(|c| !c.is_empty()) {
+            Some(country) => Some(
+                parse_country(country).ok_or_else(|| ApiError::validation("country must be an ISO 3166-1 alpha-2 code"))?,
+            ),
+            None => config.default_country.clone(),
+        };
+        let region = parse_region(region)?;
+        if region.is_some() && country.is_none() {
+            return Err(ApiError::validation("region requires a country"));
+        }
+        Ok(TaxLocation { country, region })
+    }
+}
+
+// Line to be taxed: `subtotal` before discounts
+#[derive(Debug)]
+pub struct TaxableLine {
+    pub product_id: i32,
+    pub tax_class: String,
+    pub subtotal: Decimal,
+}
+
+// Tax of one line
+#[derive(Debug, Clone)]
+pub struct LineTax {
+    pub product_id: i32,
+    pub tax_rate: Decimal,
+    // net of discounts and of tax
+    pub taxable_amount: Decimal,
+    pub tax_amount: Decimal,
+}
+
+// Data models
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TaxRate {
+    pub tax_rate_id: i32,
+    pub country: String,
+    pub region: Option<String>,
+    pub tax_class: String,
+    // percent
+    pub rate: Decimal,
+    pub name: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Body of POST and PUT /tax-rates
+#[derive(Debug, Deserialize)]
+pub struct TaxRateRequest {
+    pub country: String,
+    pub region: Option<String>,
+    pub tax_class: String,
+    pub rate: Decimal,
+    pub name: String,
+}
+
+impl TaxRateRequest {
+    // The request with country, region and class normalized
+    fn validate(&self) -> Result<(String, Option<String>, String), ApiError> {
+        let country = parse_country(&self.country)
+            .ok_or_else(|| ApiError::validation("country must be an ISO 3166-1 alpha-2 code"))?;
+        let region = parse_region(self.region.as_deref())?;
+        let tax_class = validate_tax_class(&self.tax_class)?;
+
+        if self.rate.is_sign_negative() || self.rate > Decimal::ONE_HUNDRED {
+            return Err(ApiError::validation("rate must be between 0 and 100"));
+        }
+        if self.rate.normalize().scale() > RATE_SCALE {
+            return Err(ApiError::validation(format!("rate must have at most {} decimal places", RATE_SCALE)));
+        }
+        if self.name.trim().is_empty() {
+            return Err(ApiError::validation("name must not be empty"));
+        }
+        Ok((country, region, tax_class))
+    }
+}
+
+// GET /tax-rates filters
+#[derive(Debug, Deserialize)]
+pub struct TaxRateFilter {
+    pub country: Option<String>,
+}
+
+const TAX_RATE_LIST: ListSpec = ListSpec {
+    table: "tax_rates",
+    key: "tax_rate_id",
+    sort_fields: &[
+        SortField { name: "country", expr: "country", sql_type: "TEXT" },
+        SortField { name: "rate", expr: "rate", sql_type: "NUMERIC" },
+        SortField { name: "created_at", expr: "created_at", sql_type: "TIMESTAMPTZ" },
+    ],
+    default_sort: "country",
+};
+
+// GET /reports/tax query; order_date range, RFC 3339
+#[derive(Debug, Deserialize)]
+pub struct TaxReportQuery {
+    pub from: chrono::DateTime<chrono::Utc>,
+    pub to: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub period: ReportPeriod,
+    pub country: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportPeriod {
+    Day,
+    Week,
+    #[default]
+    Month,
+    Quarter,
+    Year,
+}
+
+impl ReportPeriod {
+    // date_trunc field
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportPeriod::Day => "day",
+            ReportPeriod::Week => "week",
+            ReportPeriod::Month => "month",
+            ReportPeriod::Quarter => "quarter",
+            ReportPeriod::Year => "year",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TaxReportRow {
+    // start of the period, UTC
+    pub period_start: chrono::DateTime<chrono::Utc>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub tax_class: String,
+    pub tax_rate: Decimal,
+    pub order_count: i64,
+    #[serde(with = "money")]
+    pub taxable_amount: Decimal,
+    #[serde(with = "money")]
+    pub tax_amount: Decimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaxReport {
+    pub from: chrono::DateTime<chrono::Utc>,
+    pub to: chrono::DateTime<chrono::Utc>,
+    pub period: ReportPeriod,
+    pub rows: Vec<TaxReportRow>,
+    #[serde(with = "money")]
+    pub taxable_amount: Decimal,
+    #[serde(with = "money")]
+    pub tax_amount: Decimal,
+}
+
+fn parse_country(country: &str) -> Option<String> {
+    let country = country.trim().to_ascii_uppercase();
+    (country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase())).then_some(country)
+}
+
+fn parse_region(region: Option<&str>) -> Result<Option<String>, ApiError> {
+    let Some(region) = region.map(str::trim).filter(|r| !r.is_empty()) else {
+        return Ok(None);
+    };
+    if region.chars().count() > MAX_REGION_LENGTH {
+        return Err(ApiError::validation(format!("region must be at most {} characters", MAX_REGION_LENGTH)));
+    }
+    Ok(Some(region.to_string()))
+}
+
+pub fn validate_tax_class(tax_class: &str) -> Result<String, ApiError> {
+    let tax_class = tax_class.trim();
+    if tax_class.is_empty() || tax_class.len() > MAX_TAX_CLASS_LENGTH {
+        return Err(ApiError::validation(format!(
+            "tax_class must be 1 to {} characters",
+            MAX_TAX_CLASS_LENGTH
+        )));
+    }
+    Ok(tax_class.to_string())
+}
+
+// Rates of `classes` at `location`, the regional rate over the country one
+async fn rates(
+    conn: &mut PgConnection,
+    location: &TaxLocation,
+    classes: &[String],
+) -> Result<HashMap<String, Decimal>, ApiError> {
+    let Some(country) = &location.country else {
+        return Ok(HashMap::new());
+    };
+
+    let rates = sqlx::query_as::<_, (String, Decimal)>(
+        "SELECT DISTINCT ON (tax_class) tax_class, rate FROM tax_rates
+        WHERE country = $1 AND (region IS NULL OR LOWER(region) = LOWER($2)) AND tax_class = ANY($3)
+        ORDER BY tax_class, region NULLS LAST"
+    )
+        .bind(country)
+        .bind(&location.region)
+        .bind(classes)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    Ok(rates.into_iter().collect())
+}
+
+// Tax of a line of `amount` (after discounts): on top of it, or contained in it
+fn line_tax(amount: Decimal, rate: Decimal, prices_include_tax: bool) -> (Decimal, Decimal) {
+    if prices_include_tax {
+        let tax = money::round(amount * rate / (Decimal::ONE_HUNDRED + rate));
+        (amount - tax, tax)
+    } else {
+        (amount, money::round(amount * rate / Decimal::ONE_HUNDRED))
+    }
+}
+
+// Taxes the lines at `location`, each net of its share of `discounts`
+pub async fn calculate(
+    conn: &mut PgConnection,
+    location: &TaxLocation,
+    prices_include_tax: bool,
+    lines: &[TaxableLine],
+    discounts: &[Discount],
+) -> Result<Vec<LineTax>, ApiError> {
+    let mut classes: Vec<String> = lines.iter().map(|line| line.tax_class.clone()).collect();
+    classes.sort();
+    classes.dedup();
+    let rates = rates(conn, location, &classes).await?;
+
+    let mut discounted: HashMap<i32, Decimal> = HashMap::new();
+    for (product_id, amount) in discounts.iter().flat_map(|d| &d.allocation) {
+        *discounted.entry(*product_id).or_default() += *amount;
+    }
+
+    Ok(lines
+        .iter()
+        .map(|line| {
+            let rate = rates.get(&line.tax_class).copied().unwrap_or_default();
+            let amount = (line.subtotal - discounted.get(&line.product_id).copied().unwrap_or_default()).max(Decimal::ZERO);
+            let (taxable_amount, tax_amount) = line_tax(amount, rate, prices_include_tax);
+            LineTax {
+                product_id: line.product_id,
+                tax_rate: rate,
+                taxable_amount,
+                tax_amount,
+            }
+        })
+        .collect())
+}
+
+pub fn total(taxes: &[LineTax]) -> Decimal {
+    taxes.iter().map(|t| t.tax_amount).sum()
+}
+
+// What the customer pays
+pub fn order_total(subtotal: Decimal, discount_amount: Decimal, tax_amount: Decimal, prices_include_tax: bool) -> Decimal {
+    if prices_include_tax {
+        subtotal - discount_amount
+    } else {
+        subtotal - discount_amount + tax_amount
+    }
+}
+
+// Lines of a pending order changed: taxes them again where the order is taxed,
+// with its `discounts`. Returns the new tax total
+pub async fn retax(conn: &mut PgConnection, order_id: i32, discounts: &[Discount]) -> Result<Decimal, ApiError> {
+    let (country, region, prices_include_tax) = sqlx::query_as::<_, (Option<String>, Option<String>, bool)>(
+        "SELECT tax_country, tax_region, prices_include_tax FROM orders WHERE order_id = $1"
+    )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let lines = sqlx::query_as::<_, (i32, String, Decimal)>(
+        "SELECT product_id, tax_class, subtotal FROM order_items WHERE order_id = $1 ORDER BY product_id"
+    )
+        .bind(order_id)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(product_id, tax_class, subtotal)| TaxableLine { product_id, tax_class, subtotal })
+        .collect::<Vec<_>>();
+
+    let taxes = calculate(conn, &TaxLocation { country, region }, prices_include_tax, &lines, discounts).await?;
+
+    sqlx::query(
+        "UPDATE order_items oi SET tax_rate = t.tax_rate, taxable_amount = t.taxable_amount, tax_amount = t.tax_amount
+        FROM UNNEST($2::INT[], $3::NUMERIC[], $4::NUMERIC[], $5::NUMERIC[]) AS t(product_id, tax_rate, taxable_amount, tax_amount)
+        WHERE oi.order_id = $1 AND oi.product_id = t.product_id"
+    )
+        .bind(order_id)
+        .bind(taxes.iter().map(|t| t.product_id).collect::<Vec<_>>())
+        .bind(taxes.iter().map(|t| t.tax_rate).collect::<Vec<_>>())
+        .bind(taxes.iter().map(|t| t.taxable_amount).collect::<Vec<_>>())
+        .bind(taxes.iter().map(|t| t.tax_amount).collect::<Vec<_>>())
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(total(&taxes))
+}
+
+// Endpoint Callbacks
+// List tax rates (staff only)
+// curl "http://localhost:8080/api/tax-rates?country=DE&sort=-rate" -H "Authorization: Bearer $TOKEN"
+pub async fn get_tax_rates(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    filter: web::Query<TaxRateFilter>,
+) -> Result<HttpResponse, ApiError> {
+    let country = filter.country.as_deref().map(str::to_ascii_uppercase);
+
+    pagination::list::<TaxRate>(&data.db, &req, &TAX_RATE_LIST, &page, |q| {
+        if let Some(country) = &country {
+            q.push(" AND country = ").push_bind(country.clone());
+        }
+    })
+        .await
+}
+
+// Create tax rate (admin only)
+// curl -X POST http://localhost:8080/api/tax-rates \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"country": "DE", "tax_class": "standard", "rate": "19", "name": "MwSt 19%"}'
+pub async fn create_tax_rate(
+    data: web::Data<AppState>,
+    rate_req: web::Json<TaxRateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let (country, region, tax_class) = rate_req.validate()?;
+
+    // The same country, region and class twice -> 409 (ApiError)
+    let rate = sqlx::query_as::<_, TaxRate>(
+        "INSERT INTO tax_rates (country, region, tax_class, rate, name) VALUES ($1, $2, $3, $4, $5) RETURNING *"
+    )
+        .bind(country)
+        .bind(region)
+        .bind(tax_class)
+        .bind(rate_req.rate)
+        .bind(rate_req.name.trim())
+        .fetch_one(&data.db)
+        .await?;
+
+    Ok(HttpResponse::Created().json(rate))
+}
+
+// Replace tax rate (admin only); placed orders keep the rate they were taxed with
+// curl -X PUT http://localhost:8080/api/tax-rates/1 \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"country": "DE", "tax_class": "reduced", "rate": "7", "name": "MwSt 7%"}'
+pub async fn update_tax_rate(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    rate_req: web::Json<TaxRateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let (country, region, tax_class) = rate_req.validate()?;
+
+    let rate = sqlx::query_as::<_, TaxRate>(
+        "UPDATE tax_rates SET country = $2, region = $3, tax_class = $4, rate = $5, name = $6 WHERE tax_rate_id = $1 RETURNING *"
+    )
+        .bind(path.into_inner())
+        .bind(country)
+        .bind(region)
+        .bind(tax_class)
+        .bind(rate_req.rate)
+        .bind(rate_req.name.trim())
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("Tax rate"))?;
+
+    Ok(HttpResponse::Ok().json(rate))
+}
+
+// Delete tax rate (admin only)
+// curl -X DELETE http://localhost:8080/api/tax-rates/1 -H "Authorization: Bearer $TOKEN"
+pub async fn delete_tax_rate(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let result = sqlx::query("DELETE FROM tax_rates WHERE tax_rate_id = $1")
+        .bind(path.into_inner())
+        .execute(&data.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Tax rate"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// One order's lines for a single report row, before its refunds are taken off
+#[derive(Debug, sqlx::FromRow)]
+struct TaxReportLine {
+    period_start: chrono::DateTime<chrono::Utc>,
+    country: Option<String>,
+    region: Option<String>,
+    tax_class: String,
+    tax_rate: Decimal,
+    order_id: i32,
+    total_amount: Decimal,
+    taxable_amount: Decimal,
+    tax_amount: Decimal,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct TaxReportRefund {
+    order_id: i32,
+    payment_status: String,
+    status: String,
+    amount: Decimal,
+    reason: Option<String>,
+}
+
+// Share of an order's total the shop kept. Only succeeded refunds of the
+// succeeded charge that paid the order count; a refunded duplicate charge or
+// a payment that arrived after cancelling never paid for the order
+fn kept_share(total_amount: Decimal, refunds: &[&TaxReportRefund]) -> Decimal {
+    if total_amount <= Decimal::ZERO {
+        return Decimal::ONE;
+    }
+    let refunded: Decimal = refunds
+        .iter()
+        .filter(|r| r.payment_status == "succeeded" && r.status == "succeeded")
+        .filter(|r| !matches!(r.reason.as_deref(), Some(payment::DUPLICATE_PAYMENT | payment::CANCELLED_ORDER_PAYMENT)))
+        .map(|r| r.amount)
+        .sum();
+    (Decimal::ONE - refunded / total_amount).max(Decimal::ZERO).min(Decimal::ONE)
+}
+
+fn tax_report_rows(lines: Vec<TaxReportLine>, refunds: &[TaxReportRefund]) -> Vec<TaxReportRow> {
+    let mut by_order: HashMap<i32, Vec<&TaxReportRefund>> = HashMap::new();
+    for refund in refunds {
+        by_order.entry(refund.order_id).or_default().push(refund);
+    }
+
+    let mut rows: Vec<TaxReportRow> = Vec::new();
+    for line in lines {
+        let share = kept_share(line.total_amount, by_order.get(&line.order_id).map_or(&[][..], Vec::as_slice));
+        let same_row = rows.last().is_some_and(|row| {
+            row.period_start == line.period_start
+                && row.country == line.country
+                && row.region == line.region
+                && row.tax_class == line.tax_class
+                && row.tax_rate == line.tax_rate
+        });
+        if !same_row {
+            rows.push(TaxReportRow {
+                period_start: line.period_start,
+                country: line.country,
+                region: line.region,
+                tax_class: line.tax_class,
+                tax_rate: line.tax_rate,
+                order_count: 0,
+                taxable_amount: Decimal::ZERO,
+                tax_amount: Decimal::ZERO,
+            });
+        }
+        let row = rows.last_mut().expect("row pushed above");
+        row.order_count += 1;
+        row.taxable_amount += line.taxable_amount * share;
+        row.tax_amount += line.tax_amount * share;
+    }
+    for row in &mut rows {
+        row.taxable_amount = money::round(row.taxable_amount);
+        row.tax_amount = money::round(row.tax_amount);
+    }
+    rows
+}
+
+// Tax collected per period, location, class and rate (staff only). Counts paid
+// and partially refunded orders by order_date; cancelled and fully refunded
+// orders are left out. A partially refunded order counts for the share of its
+// total that was not refunded, whenever the refund was made
+// curl "http://localhost:8080/api/reports/tax?from=2026-01-01T00:00:00Z&to=2027-01-01T00:00:00Z&period=quarter" \
+//   -H "Authorization: Bearer $TOKEN"
+pub async fn get_tax_report(
+    data: web::Data<AppState>,
+    query: web::Query<TaxReportQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if query.to <= query.from {
+        return Err(ApiError::validation("to must be after from"));
+    }
+    let country = query.country.as_deref().map(str::to_ascii_uppercase);
+
+    // Ordered by the report row first so each row's orders come together
+    let lines = sqlx::query_as::<_, TaxReportLine>(
+        "SELECT date_trunc($3, o.order_date, 'UTC') AS period_start,
+                o.tax_country AS country, o.tax_region AS region, oi.tax_class, oi.tax_rate,
+                o.order_id, o.total_amount,
+                SUM(oi.taxable_amount) AS taxable_amount, SUM(oi.tax_amount) AS tax_amount
+        FROM orders o
+        JOIN order_items oi ON oi.order_id = o.order_id
+        WHERE o.order_date >= $1 AND o.order_date < $2
+          AND o.status <> 'cancelled' AND o.payment_status IN ('paid', 'partially_refunded')
+          AND ($4::TEXT IS NULL OR o.tax_country = $4)
+        GROUP BY 1, 2, 3, 4, 5, 6, 7
+        ORDER BY 1, 2 NULLS FIRST, 3 NULLS FIRST, 4, 5, 6"
+    )
+        .bind(query.from)
+        .bind(query.to)
+        .bind(query.period.as_str())
+        .bind(country)
+        .fetch_all(&data.db)
+        .await?;
+
+    let order_ids: Vec<i32> = lines.iter().map(|l| l.order_id).collect();
+    let refunds = sqlx::query_as::<_, TaxReportRefund>(
+        "SELECT p.order_id, p.status AS payment_status, r.status, r.amount, r.reason
+        FROM refunds r
+        JOIN payments p ON p.payment_id = r.payment_id
+        WHERE p.order_id = ANY($1)"
+    )
+        .bind(&order_ids)
+        .fetch_all(&data.db)
+        .await?;
+
+    let rows = tax_report_rows(lines, &refunds);
+    Ok(HttpResponse::Ok().json(TaxReport {
+        from: query.from,
+        to: query.to,
+        period: query.period,
+        taxable_amount: rows.iter().map(|r| r.taxable_amount).sum(),
+        tax_amount: rows.iter().map(|r| r.tax_amount).sum(),
+        rows,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn exclusive_tax_goes_on_top() {
+        assert_eq!(line_tax(dec("100.00"), dec("19"), false), (dec("100.00"), dec("19.00")));
+        // 10.10 * 7% = 0.707
+        assert_eq!(line_tax(dec("10.10"), dec("7"), false), (dec("10.10"), dec("0.71")));
+    }
+
+    #[test]
+    fn inclusive_tax_is_taken_out_of_the_amount() {
+        assert_eq!(line_tax(dec("119.00"), dec("19"), true), (dec("100.00"), dec("19.00")));
+        // 10.00 * 19 / 119 = 1.5966
+        let (taxable, tax) = line_tax(dec("10.00"), dec("19"), true);
+        assert_eq!((taxable, tax), (dec("8.40"), dec("1.60")));
+        assert_eq!(taxable + tax, dec("10.00"));
+    }
+
+    #[test]
+    fn zero_rate_has_no_tax() {
+        assert_eq!(line_tax(dec("12.34"), Decimal::ZERO, false), (dec("12.34"), Decimal::ZERO));
+        assert_eq!(line_tax(dec("12.34"), Decimal::ZERO, true), (dec("12.34"), Decimal::ZERO));
+    }
+
+    #[test]
+    fn order_total_adds_exclusive_tax_only() {
+        assert_eq!(order_total(dec("100.00"), dec("10.00"), dec("17.10"), false), dec("107.10"));
+        assert_eq!(order_total(dec("100.00"), dec("10.00"), dec("14.37"), true), dec("90.00"));
+    }
+
+    fn refund(payment_status: &str, status: &str, amount: &str, reason: Option<&str>) -> TaxReportRefund {
+        TaxReportRefund {
+            order_id: 1,
+            payment_status: payment_status.to_string(),
+            status: status.to_string(),
+            amount: dec(amount),
+            reason: reason.map(str::to_string),
+        }
+    }
+
+    fn line(order_id: i32, total_amount: &str, taxable_amount: &str, tax_amount: &str) -> TaxReportLine {
+        TaxReportLine {
+            period_start: chrono::DateTime::UNIX_EPOCH,
+            country: Some("DE".to_string()),
+            region: None,
+            tax_class: "standard".to_string(),
+            tax_rate: dec("19"),
+            order_id,
+            total_amount: dec(total_amount),
+            taxable_amount: dec(taxable_amount),
+            tax_amount: dec(tax_amount),
+        }
+    }
+
+    #[test]
+    fn partial_refund_reduces_the_kept_share() {
+        let partial = refund("succeeded", "succeeded", "29.75", Some("damaged"));
+        assert_eq!(kept_share(dec("119.00"), &[&partial]), dec("0.75"));
+    }
+
+    #[test]
+    fn refunded_duplicate_charge_is_not_taken_off() {
+        let duplicate = refund("succeeded", "succeeded", "119.00", Some(payment::DUPLICATE_PAYMENT));
+        let late = refund("succeeded", "succeeded", "119.00", Some(payment::CANCELLED_ORDER_PAYMENT));
+        assert_eq!(kept_share(dec("119.00"), &[&duplicate, &late]), Decimal::ONE);
+
+        let rows = tax_report_rows(vec![line(1, "119.00", "100.00", "19.00")], &[duplicate]);
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].taxable_amount, rows[0].tax_amount), (dec("100.00"), dec("19.00")));
+    }
+
+    #[test]
+    fn pending_and_failed_refunds_are_not_taken_off() {
+        let pending = refund("succeeded", "pending", "50.00", None);
+        let failed = refund("succeeded", "failed", "50.00", None);
+        let failed_charge = refund("failed", "succeeded", "50.00", None);
+        assert_eq!(kept_share(dec("100.00"), &[&pending, &failed, &failed_charge]), Decimal::ONE);
+    }
+
+    #[test]
+    fn kept_share_never_goes_negative() {
+        let refunds = [refund("succeeded", "succeeded", "80.00", None), refund("succeeded", "succeeded", "80.00", None)];
+        assert_eq!(kept_share(dec("100.00"), &[&refunds[0], &refunds[1]]), Decimal::ZERO);
+    }
+
+    #[test]
+    fn lines_of_the_same_row_are_summed_per_order() {
+        let lines = vec![line(1, "119.00", "100.00", "19.00"), line(2, "59.50", "50.00", "9.50")];
+        let partial = TaxReportRefund { order_id: 2, ..refund("succeeded", "succeeded", "29.75", None) };
+        let rows = tax_report_rows(lines, &[partial]);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].order_count, 2);
+        assert_eq!((rows[0].taxable_amount, rows[0].tax_amount), (dec("125.00"), dec("23.75")));
+    }
+}
@@ -1,193 +1,385 @@
-use actix_web::{web, HttpResponse};
+use std::str::FromStr;
+
+use actix_web::{web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use sqlx::{Pool, Postgres};
 use crate::AppState;
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::pagination::{self, ListSpec, PageParams, SortField};
+use crate::password;
+use crate::pg_enum::impl_pg_string_enum;
+
+// Roles are ordered: admin can do everything staff can, staff everything a customer can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Customer,
+    Staff,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Customer => "customer",
+            Role::Staff => "staff",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "customer" => Ok(Role::Customer),
+            "staff" => Ok(Role::Staff),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role: {}", other)),
+        }
+    }
+}
+
+// Stored as VARCHAR(20)
+impl_pg_string_enum!(Role);
 
 // Data models
+// password_hash is intentionally not a field: it can never end up in a response
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
-    // id: Uuid,
-    // product_id: i64, // TODO
+    pub user_id: i32,
     pub username: String,
     pub email: String,
-    pub first_name: String,
-    pub last_name: String,
-    pub phone: String,
-    pub address: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
     created_at: Option<chrono::DateTime<chrono::Utc>>,
     updated_at: Option<chrono::DateTime<chrono::Utc>>,
-    is_active: bool,
+    is_active: Option<bool>,
+    pub role: Role,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateUserRequest {
-    // product_id: i64, // TODO
     pub username: String,
     pub email: String,
-    pub first_name: String,
-    pub last_name: String,
-    pub phone: String,
-    pub address: String,
-    created_at: Option<chrono::DateTime<chrono::Utc>>,
-    updated_at: Option<chrono::DateTime<chrono::Utc>>, // TIMESTAMPTZ
+    pub password: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
+    #[serde(default = "default_is_active")]
     is_active: bool,
 }
 
+// Self-registration; accounts are always active
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterUserRequest {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateUserRequest {
-    pub name: Option<String>,
     pub email: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateRoleRequest {
+    pub role: Role,
+}
+
+// GET /api/users filters
+#[derive(Debug, Deserialize)]
+pub struct UserFilter {
+    pub role: Option<Role>,
+    pub is_active: Option<bool>,
+    // substring match on email / username
+    pub q: Option<String>,
+}
+
+const USER_LIST: ListSpec = ListSpec {
+    table: "users",
+    key: "user_id",
+    sort_fields: &[
+        SortField { name: "created_at", expr: "COALESCE(created_at, 'epoch')", sql_type: "TIMESTAMPTZ" },
+        SortField { name: "username", expr: "username", sql_type: "TEXT" },
+        SortField { name: "email", expr: "email", sql_type: "TEXT" },
+    ],
+    default_sort: "-created_at",
+};
+
+fn default_is_active() -> bool {
+    true
 }
 
 // Endpoint callbacks
-// Get all users
-// curl http://localhost:8080/api/users
-pub async fn get_users(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
-    match sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY created_at DESC")
-        .fetch_all(&data.db)
+// Get all users (staff only, see require_role! in main)
+// curl "http://localhost:8080/api/users?role=staff&q=example&sort=email" -H "Authorization: Bearer $TOKEN"
+pub async fn get_users(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    page: web::Query<PageParams>,
+    filter: web::Query<UserFilter>,
+) -> Result<HttpResponse, ApiError> {
+    pagination::list::<User>(&data.db, &req, &USER_LIST, &page, |q| {
+        if let Some(role) = filter.role {
+            q.push(" AND role = ").push_bind(role);
+        }
+        if let Some(is_active) = filter.is_active {
+            q.push(" AND is_active IS NOT DISTINCT FROM ").push_bind(is_active);
+        }
+        if let Some(text) = &filter.q {
+            let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+            q.push(" AND (email ILIKE ").push_bind(pattern.clone());
+            q.push(" OR username ILIKE ").push_bind(pattern).push(")");
+        }
+    })
         .await
-    {
-        Ok(users) => Ok(HttpResponse::Ok().json(users)),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e.to_string()
-        }))),
-    }
 }
 
-// Get user by ID
+// Get user by ID (own record, or any for staff)
+// curl http://localhost:8080/api/users/1 -H "Authorization: Bearer $TOKEN"
 pub async fn get_user(
     data: web::Data<AppState>,
-    path: web::Path<Uuid>,
-) -> actix_web::Result<HttpResponse> {
+    path: web::Path<i32>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
     let user_id = path.into_inner();
+    auth.ensure_owner_or_staff(user_id.into())?;
 
-    match sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE user_id = $1")
         .bind(user_id)
         .fetch_optional(&data.db)
-        .await
-    {
-        Ok(Some(user)) => Ok(HttpResponse::Ok().json(user)),
-        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
-            "error": "User not found"
-        }))),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e.to_string()
-        }))),
-    }
+        .await?
+        .ok_or(ApiError::NotFound("User"))?;
+
+    Ok(HttpResponse::Ok().json(user))
 }
 
-// Create new user
+// Create new user (admin only)
 // curl -X POST http://localhost:8080/api/users \
-//   -H "Content-Type: application/json" \
-//   -d '{"name": "John Doe", "email": "john@example.com"}'
-
-// curl -X POST http://localhost:8080/api/users -H "Content-Type: application/json" -d '{"name": "Jim Beam", "email": "beam@example.com"}'
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"username": "john", "email": "john@example.com", "password": "secret123", "is_active": true}'
 pub async fn create_user(
     data: web::Data<AppState>,
     user_req: web::Json<CreateUserRequest>,
-) -> actix_web::Result<HttpResponse> {
-    match sqlx::query_as::<_, User>(
-        "INSERT INTO users (username, email, first_name, last_name, phone, address, is_active) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *"
+) -> Result<HttpResponse, ApiError> {
+    let user_req = user_req.into_inner();
+    let user = insert_user(
+        &data,
+        NewUser {
+            username: user_req.username,
+            email: user_req.email,
+            password: user_req.password,
+            first_name: user_req.first_name,
+            last_name: user_req.last_name,
+            phone: user_req.phone,
+            address: user_req.address,
+            is_active: user_req.is_active,
+        },
     )
-        .bind(&user_req.username)
-        .bind(&user_req.email)
-        .bind(&user_req.first_name)
-        .bind(&user_req.last_name)
-        .bind(&user_req.phone)
-        .bind(&user_req.address)
-        .bind(&user_req.is_active)
-        .fetch_one(&data.db)
-        .await
-    {
-        Ok(user) => Ok(HttpResponse::Created().json(user)),
-        Err(e) => {
-            // Handle unique constraint violation
-            if e.to_string().contains("unique constraint") {
-                Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "Email already exists"
-                })))
-            } else {
-                Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": e.to_string()
-                })))
-            }
-        }
-    }
+        .await?;
+
+    Ok(HttpResponse::Created().json(user))
 }
 
-// Update user
-pub async fn update_user(
+// Register (React frontend: REACT_APP_SHOP_API_USER_REGISTER)
+// curl -X POST http://localhost:8080/api/users/register \
+//   -H "Content-Type: application/json" \
+//   -d '{"username": "jim", "email": "beam@example.com", "password": "secret123"}'
+pub async fn register_user(
     data: web::Data<AppState>,
-    path: web::Path<Uuid>,
-    update_req: web::Json<UpdateUserRequest>,
-) -> actix_web::Result<HttpResponse> {
-    let user_id = path.into_inner();
+    register_req: web::Json<RegisterUserRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let register_req = register_req.into_inner();
+    let user = insert_user(
+        &data,
+        NewUser {
+            username: register_req.username,
+            email: register_req.email,
+            password: register_req.password,
+            first_name: register_req.first_name,
+            last_name: register_req.last_name,
+            phone: register_req.phone,
+            address: register_req.address,
+            is_active: true,
+        },
+    )
+        .await?;
 
-    let mut query = "UPDATE users SET ".to_string();
-    let mut params = Vec::new();
-    let mut param_count = 1;
+    Ok(HttpResponse::Created().json(user))
+}
 
-    if let Some(name) = &update_req.name {
-        query.push_str(&format!("name = ${} ", param_count));
-        params.push(name);
-        param_count += 1;
+struct NewUser {
+    username: String,
+    email: String,
+    password: String,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    phone: Option<String>,
+    address: Option<String>,
+    is_active: bool,
+}
+
+// Emails are stored trimmed and lowercase, login looks them up the same way
+fn normalize_email(email: &str) -> Result<String, ApiError> {
+    let email = email.trim().to_lowercase();
+    if !email.contains('@') {
+        return Err(ApiError::validation("email is not valid"));
     }
+    Ok(email)
+}
 
-    if let Some(email) = &update_req.email {
-        if param_count > 1 {
-            query.push_str(", ");
-        }
-        query.push_str(&format!("email = ${} ", param_count));
-        params.push(email);
-        param_count += 1;
+// Validates the password against the policy, hashes it and stores the user
+async fn insert_user(data: &AppState, new_user: NewUser) -> Result<User, ApiError> {
+    if new_user.username.trim().is_empty() {
+        return Err(ApiError::validation("username must not be empty"));
     }
+    let email = normalize_email(&new_user.email)?;
+    data.password_policy.validate(&new_user.password, &email)?;
+
+    // Argon2 is deliberately slow, keep it off the async workers
+    let password = new_user.password;
+    let password_hash = web::block(move || password::hash_password(&password))
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))??;
+
+    // Unique violations (email / username) are mapped to 409 by ApiError
+    let user = sqlx::query_as::<_, User>(
+        "INSERT INTO users (username, email, password_hash, first_name, last_name, phone, address, is_active) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *"
+    )
+        .bind(new_user.username.trim())
+        .bind(&email)
+        .bind(&password_hash)
+        .bind(&new_user.first_name)
+        .bind(&new_user.last_name)
+        .bind(&new_user.phone)
+        .bind(&new_user.address)
+        .bind(new_user.is_active)
+        .fetch_one(&data.db)
+        .await?;
 
-    query.push_str(&format!("WHERE id = ${} RETURNING *", param_count));
-    params.push(&user_id.to_string());
+    Ok(user)
+}
 
-    // For simplicity, using a prepared approach
-    match sqlx::query_as::<_, User>(
-        "UPDATE users SET name = COALESCE($1, name), email = COALESCE($2, email) WHERE id = $3 RETURNING *"
+// Update user (own record, or any for staff)
+// curl -X PUT http://localhost:8080/api/users/1 \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"first_name": "Jim", "phone": "+7 900 000-00-00"}'
+pub async fn update_user(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    update_req: web::Json<UpdateUserRequest>,
+    auth: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    auth.ensure_owner_or_staff(user_id.into())?;
+    let email = update_req.email.as_deref().map(normalize_email).transpose()?;
+
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET email = COALESCE($1, email), first_name = COALESCE($2, first_name), last_name = COALESCE($3, last_name), phone = COALESCE($4, phone), address = COALESCE($5, address) WHERE user_id = $6 RETURNING *"
     )
-        .bind(&update_req.name)
-        .bind(&update_req.email)
+        .bind(email)
+        .bind(&update_req.first_name)
+        .bind(&update_req.last_name)
+        .bind(&update_req.phone)
+        .bind(&update_req.address)
         .bind(user_id)
         .fetch_optional(&data.db)
-        .await
-    {
-        Ok(Some(user)) => Ok(HttpResponse::Ok().json(user)),
-        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
-            "error": "User not found"
-        }))),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e.to_string()
-        }))),
-    }
+        .await?
+        .ok_or(ApiError::NotFound("User"))?;
+
+    Ok(HttpResponse::Ok().json(user))
 }
 
-// Delete user
+// Change role (admin only)
+// curl -X PUT http://localhost:8080/api/users/1/role \
+//   -H "Authorization: Bearer $TOKEN" -H "Content-Type: application/json" \
+//   -d '{"role": "staff"}'
+pub async fn update_user_role(
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+    role_req: web::Json<UpdateRoleRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+
+    // Takes effect with the user's next token
+    let user = sqlx::query_as::<_, User>("UPDATE users SET role = $1 WHERE user_id = $2 RETURNING *")
+        .bind(role_req.role)
+        .bind(user_id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or(ApiError::NotFound("User"))?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+// Delete user (staff only). Nobody may delete a user with a higher role than
+// their own; client tokens count as staff
+// curl -X DELETE http://localhost:8080/api/users/1 -H "Authorization: Bearer $TOKEN"
 pub async fn delete_user(
     data: web::Data<AppState>,
-    path: web::Path<Uuid>,
-) -> actix_web::Result<HttpResponse> {
+    path: web::Path<i32>,
+    auth: Option<AuthenticatedUser>,
+) -> Result<HttpResponse, ApiError> {
     let user_id = path.into_inner();
+    let caller_role = auth.map_or(Role::Staff, |user| user.role);
+    let mut tx = data.db.begin().await?;
 
-    match sqlx::query("DELETE FROM users WHERE id = $1")
+    let role = sqlx::query_scalar::<_, Role>("SELECT role FROM users WHERE user_id = $1 FOR UPDATE")
         .bind(user_id)
-        .execute(&data.db)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("User"))?;
+    if role > caller_role {
+        return Err(ApiError::Forbidden(format!("Cannot delete a user with the {} role", role)));
+    }
+
+    sqlx::query("DELETE FROM users WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// CLI: grant a role, e.g. to bootstrap the first admin
+// cargo run -- user set-role admin@example.com admin
+pub async fn run_cli(pool: &Pool<Postgres>, args: &[String]) -> std::io::Result<()> {
+    let usage = || std::io::Error::other("usage: user set-role <email> <customer|staff|admin>");
+
+    let (Some("set-role"), Some(email), Some(role)) =
+        (args.first().map(String::as_str), args.get(1), args.get(2))
+    else {
+        return Err(usage());
+    };
+    let role = role.parse::<Role>().map_err(std::io::Error::other)?;
+
+    let result = sqlx::query("UPDATE users SET role = $1 WHERE email = $2")
+        .bind(role)
+        .bind(email.trim().to_lowercase())
+        .execute(pool)
         .await
-    {
-        Ok(result) => {
-            if result.rows_affected() > 0 {
-                Ok(HttpResponse::NoContent().finish())
-            } else {
-                Ok(HttpResponse::NotFound().json(serde_json::json!({
-                    "error": "User not found"
-                })))
-            }
-        }
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e.to_string()
-        }))),
+        .map_err(std::io::Error::other)?;
+
+    if result.rows_affected() == 0 {
+        return Err(std::io::Error::other(format!("user {} not found", email)));
     }
+
+    println!("✅ {} is now {}", email, role);
+    Ok(())
 }